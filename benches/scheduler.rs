@@ -0,0 +1,51 @@
+//! Emulation throughput with the event scheduler versus catching the peripherals up on
+//! every M-cycle. Run with `cargo bench`, optionally passing ROMs to measure instead of
+//! the default ones.
+
+use ramiel::cpu::{CPU, CYCLES_PER_FRAME};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const FRAMES: u32 = 600;
+
+fn run(rom: &Path, eager_sync: bool) -> Duration {
+    let mut cpu = CPU::new_with_rom(rom).unwrap();
+    cpu.bus.eager_sync = eager_sync;
+    let start = Instant::now();
+    let mut cycles: u64 = 0;
+    while cycles < FRAMES as u64 * CYCLES_PER_FRAME as u64 {
+        cpu.step().unwrap();
+        cycles += cpu.cycle_count as u64;
+    }
+    start.elapsed()
+}
+
+fn main() {
+    // cargo passes --bench to harness-less benches
+    let mut roms: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    if roms.is_empty() {
+        roms = vec!["roms/Tetris.gb".into(), "roms/cpu_instrs.gb".into()];
+    }
+
+    println!("{} frames per run", FRAMES);
+    for rom in &roms {
+        let rom = Path::new(rom);
+        if !rom.exists() {
+            println!("{} not found, skipping", rom.display());
+            continue;
+        }
+        let eager = run(rom, true);
+        let scheduled = run(rom, false);
+        let fps = |time: Duration| FRAMES as f64 / time.as_secs_f64();
+        println!(
+            "{}: every cycle {:.0} fps, scheduled {:.0} fps ({:.2}x)",
+            rom.display(),
+            fps(eager),
+            fps(scheduled),
+            eager.as_secs_f64() / scheduled.as_secs_f64()
+        );
+    }
+}
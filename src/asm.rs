@@ -0,0 +1,311 @@
+use crate::instructions::Instruction;
+use crate::registers::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    Register(ArithmeticTarget),
+    Register16(DoubleTarget),
+    AF,
+    Condition(JumpCondition), // C is parsed as a register and converted where needed
+    Memory16(DoubleTarget),
+    HLIncrement,
+    HLDecrement,
+    HighC,
+    High(u8),
+    Memory(u16),
+    SPOffset(i8),
+    Number(i32),
+}
+
+// RGBDS style numbers: $ or 0x for hexadecimal, % for binary, decimal otherwise
+fn parse_number(text: &str) -> Result<i32, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let value = if let Some(hex) = digits.strip_prefix('$').or(digits.strip_prefix("0X")) {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix('%') {
+        i32::from_str_radix(binary, 2)
+    } else {
+        digits.parse()
+    };
+    match value {
+        Ok(value) if value <= 0xFFFF => Ok(if negative { -value } else { value }),
+        _ => Err(format!("Invalid number: {}", text)),
+    }
+}
+
+fn parse_register(text: &str) -> Option<Operand> {
+    Some(match text {
+        "A" => Operand::Register(ArithmeticTarget::A),
+        "B" => Operand::Register(ArithmeticTarget::B),
+        "C" => Operand::Register(ArithmeticTarget::C),
+        "D" => Operand::Register(ArithmeticTarget::D),
+        "E" => Operand::Register(ArithmeticTarget::E),
+        "H" => Operand::Register(ArithmeticTarget::H),
+        "L" => Operand::Register(ArithmeticTarget::L),
+        "BC" => Operand::Register16(DoubleTarget::BC),
+        "DE" => Operand::Register16(DoubleTarget::DE),
+        "HL" => Operand::Register16(DoubleTarget::HL),
+        "SP" => Operand::Register16(DoubleTarget::SP),
+        "AF" => Operand::AF,
+        "NZ" => Operand::Condition(JumpCondition::NotZero),
+        "Z" => Operand::Condition(JumpCondition::Zero),
+        "NC" => Operand::Condition(JumpCondition::NotCarry),
+        _ => return None,
+    })
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if let Some(operand) = parse_register(text) {
+        return Ok(operand);
+    }
+    if let Some(offset) = text.strip_prefix("SP") {
+        let offset = parse_number(offset)?;
+        return i8::try_from(offset)
+            .map(Operand::SPOffset)
+            .map_err(|_| format!("Offset out of range: {}", offset));
+    }
+    let inner = text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+        .or_else(|| {
+            text.strip_prefix('[')
+                .and_then(|text| text.strip_suffix(']'))
+        });
+    let Some(inner) = inner else {
+        return parse_number(text).map(Operand::Number);
+    };
+    Ok(match inner {
+        "BC" => Operand::Memory16(DoubleTarget::BC),
+        "DE" => Operand::Memory16(DoubleTarget::DE),
+        "HL" => Operand::Memory16(DoubleTarget::HL),
+        "HL+" | "HLI" => Operand::HLIncrement,
+        "HL-" | "HLD" => Operand::HLDecrement,
+        "C" | "$FF00+C" | "0XFF00+C" => Operand::HighC,
+        _ => match inner
+            .strip_prefix("$FF00+")
+            .or(inner.strip_prefix("0XFF00+"))
+        {
+            Some(offset) => Operand::High(immediate8(parse_number(offset)?)?),
+            None => Operand::Memory(immediate16(parse_number(inner)?)?),
+        },
+    })
+}
+
+fn immediate8(value: i32) -> Result<u8, String> {
+    if (-0x80..=0xFF).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("Not a byte: {}", value))
+    }
+}
+
+fn immediate16(value: i32) -> Result<u16, String> {
+    if (-0x8000..=0xFFFF).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("Not a 16-bit value: {}", value))
+    }
+}
+
+fn target(operand: Operand) -> Result<Target, String> {
+    Ok(match operand {
+        Operand::Register(register) => Target::Register(register),
+        Operand::Register16(register) => Target::Register16(register),
+        Operand::Memory16(register) => Target::MemoryR16(register),
+        Operand::Memory(address) => Target::MemoryConst16(address),
+        Operand::Number(value) => Target::Const8(immediate8(value)?),
+        _ => return Err(format!("Invalid operand: {:?}", operand)),
+    })
+}
+
+fn condition(operand: Operand) -> Result<JumpCondition, String> {
+    match operand {
+        Operand::Condition(condition) => Ok(condition),
+        Operand::Register(ArithmeticTarget::C) => Ok(JumpCondition::Carry),
+        _ => Err(format!("Invalid condition: {:?}", operand)),
+    }
+}
+
+fn load(destination: Operand, source: Operand) -> Result<Instruction, String> {
+    let hl = Target::MemoryR16(DoubleTarget::HL);
+    let a = Operand::Register(ArithmeticTarget::A);
+    Ok(match (destination, source) {
+        (Operand::HLIncrement, source) => Instruction::LDI(hl, target(source)?),
+        (destination, Operand::HLIncrement) => Instruction::LDI(target(destination)?, hl),
+        (Operand::HLDecrement, source) => Instruction::LDD(hl, target(source)?),
+        (destination, Operand::HLDecrement) => Instruction::LDD(target(destination)?, hl),
+        (Operand::HighC, source) if source == a => {
+            Instruction::LDH(LDHRegister::C, LDHRegister::ArithmeticTarget)
+        }
+        (destination, Operand::HighC) if destination == a => {
+            Instruction::LDH(LDHRegister::ArithmeticTarget, LDHRegister::C)
+        }
+        (Operand::High(offset), source) if source == a => {
+            Instruction::LDH(LDHRegister::MemA8(offset), LDHRegister::ArithmeticTarget)
+        }
+        (destination, Operand::High(offset)) if destination == a => {
+            Instruction::LDH(LDHRegister::ArithmeticTarget, LDHRegister::MemA8(offset))
+        }
+        (Operand::Register16(DoubleTarget::HL), Operand::SPOffset(offset)) => {
+            Instruction::LDHLSP(offset)
+        }
+        (Operand::Register16(register), Operand::Number(value)) => Instruction::LD(
+            Target::Register16(register),
+            Target::Const16(immediate16(value)?),
+        ),
+        (destination, source) => Instruction::LD(target(destination)?, target(source)?),
+    })
+}
+
+// Accumulator operations can be written with or without the A
+fn accumulator(operands: &[Operand]) -> Result<Target, String> {
+    match operands {
+        [Operand::Register(ArithmeticTarget::A), source] | [source] => target(*source),
+        _ => Err("Expected A,SOURCE or SOURCE".to_string()),
+    }
+}
+
+fn jump_target(operands: &[Operand]) -> Result<(JumpCondition, i32), String> {
+    match operands {
+        [Operand::Number(address)] => Ok((JumpCondition::Always, *address)),
+        [jump, Operand::Number(address)] => Ok((condition(*jump)?, *address)),
+        _ => Err("Expected [CONDITION,]ADDRESS".to_string()),
+    }
+}
+
+/// Parses one instruction written the way the disassembler prints it, e.g. `LD A,($FF00+$44)`
+/// or `JR NZ,$0150`. `address` is where the instruction goes, relative jumps are computed
+/// from it.
+pub fn parse(text: &str, address: u16) -> Result<Instruction, String> {
+    let text = text.trim().to_ascii_uppercase();
+    let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((&text, ""));
+    let operands = if operands.trim().is_empty() {
+        Vec::new()
+    } else {
+        operands
+            .split(',')
+            .map(|operand| parse_operand(&operand.replace(char::is_whitespace, "")))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let single = || match operands[..] {
+        [operand] => target(operand),
+        _ => Err(format!("{} takes one operand", mnemonic)),
+    };
+    let bit = || match operands[..] {
+        [Operand::Number(bit @ 0..=7), operand] => Ok((bit as u8, target(operand)?)),
+        _ => Err(format!("Expected {} BIT,OPERAND", mnemonic)),
+    };
+    let a = Target::Register(ArithmeticTarget::A);
+
+    Ok(match (mnemonic, &operands[..]) {
+        ("NOP", []) => Instruction::NOP(),
+        ("STOP", []) => Instruction::STOP(),
+        ("HALT", []) => Instruction::HALT(),
+        ("DI", []) => Instruction::DI(),
+        ("EI", []) => Instruction::EI(),
+        ("CCF", []) => Instruction::CCF(),
+        ("SCF", []) => Instruction::SCF(),
+        ("CPL", []) => Instruction::CPL(),
+        ("DAA", []) => Instruction::DAA(),
+        ("RLCA", []) => Instruction::RLCA(),
+        ("RRCA", []) => Instruction::RRCA(),
+        ("RLA", []) => Instruction::RLA(),
+        ("RRA", []) => Instruction::RRA(),
+        ("RETI", []) => Instruction::RETI(JumpCondition::Always),
+        ("LD", &[destination, source]) => load(destination, source)?,
+        ("LDH", &[destination, source]) => {
+            // LDH takes the full address or just the low byte
+            let high = |operand| match operand {
+                Operand::Memory(address) if address >= 0xFF00 => Operand::High(address as u8),
+                Operand::Memory(address) if address <= 0xFF => Operand::High(address as u8),
+                operand => operand,
+            };
+            match load(high(destination), high(source))? {
+                instruction @ Instruction::LDH(..) => instruction,
+                _ => return Err("LDH only loads A from or into $FF00-$FFFF".to_string()),
+            }
+        }
+        ("ADD", &[Operand::Register16(DoubleTarget::HL), Operand::Register16(source)]) => {
+            Instruction::ADD(
+                Target::Register16(DoubleTarget::HL),
+                Target::Register16(source),
+            )
+        }
+        ("ADD", &[Operand::Register16(DoubleTarget::SP), Operand::Number(offset)]) => {
+            let offset =
+                i8::try_from(offset).map_err(|_| format!("Offset out of range: {}", offset))?;
+            Instruction::ADD(
+                Target::Register16(DoubleTarget::SP),
+                Target::Const8(offset as u8),
+            )
+        }
+        ("ADD", operands) => Instruction::ADD(a, accumulator(operands)?),
+        ("ADC", operands) => Instruction::ADC(accumulator(operands)?),
+        ("SUB", operands) => Instruction::SUB(accumulator(operands)?),
+        ("SBC", operands) => Instruction::SBC(a, accumulator(operands)?),
+        ("AND", operands) => Instruction::AND(a, accumulator(operands)?),
+        ("OR", operands) => Instruction::OR(a, accumulator(operands)?),
+        ("XOR", operands) => Instruction::XOR(a, accumulator(operands)?),
+        ("CP", operands) => Instruction::CP(accumulator(operands)?),
+        ("INC", _) => Instruction::INC(single()?),
+        ("DEC", _) => Instruction::DEC(single()?),
+        ("RLC", _) => Instruction::RLC(single()?),
+        ("RRC", _) => Instruction::RRC(single()?),
+        ("RL", _) => Instruction::RL(single()?),
+        ("RR", _) => Instruction::RR(single()?),
+        ("SLA", _) => Instruction::SLA(single()?),
+        ("SRA", _) => Instruction::SRA(single()?),
+        ("SWAP", _) => Instruction::SWAP(single()?),
+        ("SRL", _) => Instruction::SRL(single()?),
+        ("BIT", _) => {
+            let (bit, operand) = bit()?;
+            Instruction::BIT(bit, operand)
+        }
+        ("SET", _) => {
+            let (bit, operand) = bit()?;
+            Instruction::SET(bit, operand)
+        }
+        ("RES", _) => {
+            let (bit, operand) = bit()?;
+            Instruction::RES(bit, operand)
+        }
+        ("JP", [Operand::Register16(DoubleTarget::HL) | Operand::Memory16(DoubleTarget::HL)]) => {
+            Instruction::JPHL(Target::Register16(DoubleTarget::HL))
+        }
+        ("JP", operands) => {
+            let (condition, target) = jump_target(operands)?;
+            Instruction::JP(condition, immediate16(target)?)
+        }
+        ("CALL", operands) => {
+            let (condition, target) = jump_target(operands)?;
+            Instruction::CALL(condition, immediate16(target)?)
+        }
+        ("JR", operands) => {
+            let (condition, target) = jump_target(operands)?;
+            let offset = immediate16(target)?.wrapping_sub(address.wrapping_add(2)) as i16;
+            let offset =
+                i8::try_from(offset).map_err(|_| format!("JR target is {} bytes away", offset))?;
+            Instruction::JR(condition, offset)
+        }
+        ("RET", []) => Instruction::RET(JumpCondition::Always),
+        ("RET", &[jump]) => Instruction::RET(condition(jump)?),
+        ("RST", &[Operand::Number(vector)]) => Instruction::RST(immediate8(vector)?),
+        ("PUSH", [Operand::AF]) => Instruction::PUSHAF(),
+        ("POP", [Operand::AF]) => Instruction::POPAF(),
+        ("PUSH", _) => Instruction::PUSH(single()?),
+        ("POP", _) => Instruction::POP(single()?),
+        _ => return Err(format!("Unknown instruction: {}", text)),
+    })
+}
+
+/// Assembles one instruction at `address` into its bytes.
+pub fn assemble(text: &str, address: u16) -> Result<Vec<u8>, String> {
+    let instruction = parse(text, address)?;
+    instruction
+        .encode()
+        .ok_or_else(|| format!("No such instruction: {}", text.trim()))
+}
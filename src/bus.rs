@@ -0,0 +1,589 @@
+use crate::cartridge::Cartridge;
+use crate::gpu::*;
+use crate::joypad::*;
+use crate::scheduler::{Event, Scheduler};
+use crate::serial::Serial;
+use crate::state::{StateReader, StateWriter};
+use crate::timer::Timer;
+use crate::watchpoint::*;
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
+pub const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
+
+pub const VBLANK_INTERRUPT: u8 = 1 << 0;
+pub const TIMER_INTERRUPT: u8 = 1 << 2;
+pub const SERIAL_INTERRUPT: u8 = 1 << 3;
+pub const JOYPAD_INTERRUPT: u8 = 1 << 4;
+
+const ROM_SIZE: usize = 0x8000;
+// Work RAM, 0xE000-0xFDFF mirrors 0xC000-0xDDFF so masking the address covers both
+const WRAM_SIZE: usize = 0x2000;
+const WRAM_MASK: u16 = 0x1FFF;
+const OAM_BEGIN: u16 = 0xFE00;
+const OAM_END: u16 = 0xFE9F;
+const OAM_SIZE: u8 = 160;
+const UNUSABLE_BEGIN: u16 = 0xFEA0;
+const UNUSABLE_END: u16 = 0xFEFF;
+const IO_BEGIN: u16 = 0xFF00;
+const IO_END: u16 = 0xFF7F;
+const SOUND_BEGIN: u16 = 0xFF10;
+const SOUND_END: u16 = 0xFF3F;
+const HRAM_BEGIN: u16 = 0xFF80;
+const HRAM_END: u16 = 0xFFFE;
+
+// Bits of every IO register that always read as 1, unmapped registers read as 0xFF
+#[rustfmt::skip]
+const IO_READ_MASKS: [u8; (IO_END - IO_BEGIN + 1) as usize] = [
+    // P1    SB    SC          DIV   TIMA  TMA   TAC
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8,
+    //                                         IF
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+    // NR10  NR11  NR12  NR13  NR14        NR21  NR22
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00,
+    // NR23  NR24  NR30  NR31  NR32  NR33  NR34
+    0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    // NR41  NR42  NR43  NR44  NR50  NR51  NR52
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // Wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // LCDC  STAT  SCY   SCX   LY    LYC   DMA   BGP
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // OBP0  OBP1  WY    WX
+    0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// Who answers for a 256 byte page of the address space.
+#[derive(Clone, Copy)]
+enum Region {
+    Rom,
+    Vram,
+    ExternalRam,
+    Wram,
+    // OAM, IO registers, HRAM and IE share the last two pages and are told apart by address
+    High,
+}
+
+// Looked up on every access, so ROM and RAM reads don't have to go through a range match
+const PAGES: [Region; 0x100] = {
+    let mut pages = [Region::High; 0x100];
+    let mut page = 0;
+    while page < 0x100 {
+        pages[page] = match page {
+            0x00..=0x7F => Region::Rom,
+            0x80..=0x9F => Region::Vram,
+            0xA0..=0xBF => Region::ExternalRam,
+            0xC0..=0xFD => Region::Wram,
+            _ => Region::High,
+        };
+        page += 1;
+    }
+    pages
+};
+
+/// A read or write that went over the bus, with the M-cycle it happened in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusAccess {
+    pub cycle: u64,
+    pub address: u16,
+    pub value: u8,
+    pub access: Access,
+}
+
+/// The hardware being emulated, for the few places where revisions behave differently.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    #[default]
+    Dmg,
+}
+
+// OAM and the IO registers, the peripherals have to catch up before these are accessed
+#[inline(always)]
+pub(crate) fn is_peripheral(address: u16) -> bool {
+    (OAM_BEGIN..HRAM_BEGIN).contains(&address)
+}
+
+/// The address space as the CPU sees it. Every region has a single owner: the cartridge,
+/// the GPU for VRAM and its registers, the bus itself for work RAM, OAM, HRAM and the
+/// interrupt registers, and each peripheral for its own IO registers.
+pub struct MemoryBus {
+    pub gpu: GPU,
+    pub serial: Serial,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub cartridge: Option<Cartridge>,
+    pub model: Model,
+    // Plain RAM at 0x0000-0x7FFF while no cartridge is inserted, for the boot ROM and test
+    // programs
+    rom: Box<[u8; ROM_SIZE]>,
+    wram: [u8; WRAM_SIZE],
+    oam: [u8; OAM_SIZE as usize],
+    hram: [u8; (HRAM_END - HRAM_BEGIN + 1) as usize],
+    pub interrupt_flag: u8,
+    pub interrupt_enable: u8,
+    // There is no APU yet, its registers and wave RAM just hold whatever is written
+    sound: [u8; (SOUND_END - SOUND_BEGIN + 1) as usize],
+    // Every address is plain RAM with no side effects, used by the instruction tests
+    flat_ram: Option<Box<[u8; 0x10000]>>,
+    watchpoints: Vec<Watchpoint>,
+    // First access that matched a watchpoint, reads only borrow the bus so this is a Cell
+    watch_hit: Cell<Option<WatchHit>>,
+    // Every read and write while logging is on, to check instructions cycle by cycle
+    access_log: Option<RefCell<Vec<BusAccess>>>,
+    // OAM DMA copies one byte per M-cycle, `dma_index` is OAM_SIZE when no transfer runs
+    dma_source: u16,
+    dma_index: u8,
+    // The LCD, timer, serial port and DMA only catch up when their registers are accessed
+    // or one of their events is due. `synced_at` is when that last happened.
+    scheduler: Scheduler,
+    synced_at: u64,
+    /// Catch the peripherals up on every M-cycle instead of waiting for their events, only
+    /// useful to check and benchmark the scheduler against
+    pub eager_sync: bool,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        MemoryBus::new()
+    }
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        let mut bus = MemoryBus {
+            gpu: GPU::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            cartridge: None,
+            model: Model::Dmg,
+            rom: Box::new([0; ROM_SIZE]),
+            wram: [0; WRAM_SIZE],
+            oam: [0; OAM_SIZE as usize],
+            hram: [0; (HRAM_END - HRAM_BEGIN + 1) as usize],
+            interrupt_flag: 0,
+            interrupt_enable: 0,
+            sound: [0; (SOUND_END - SOUND_BEGIN + 1) as usize],
+            flat_ram: None,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            access_log: None,
+            dma_source: 0,
+            dma_index: OAM_SIZE,
+            scheduler: Scheduler::new(),
+            synced_at: 0,
+            eager_sync: false,
+        };
+        bus.reschedule();
+        bus
+    }
+
+    /// Turns the whole address space into plain RAM, or back into the regular memory map.
+    pub fn set_flat(&mut self, flat: bool) {
+        self.flat_ram = flat.then(|| Box::new([0; 0x10000]));
+    }
+
+    pub fn is_flat(&self) -> bool {
+        self.flat_ram.is_some()
+    }
+
+    /// M-cycles since power on.
+    pub fn now(&self) -> u64 {
+        self.scheduler.now()
+    }
+
+    /// Starts or stops recording every `read_byte` and `write_byte`, dropping anything
+    /// recorded so far.
+    pub fn log_accesses(&mut self, enabled: bool) {
+        self.access_log = enabled.then(|| RefCell::new(Vec::new()));
+    }
+
+    /// Everything recorded since logging started or this was last called, oldest first.
+    pub fn take_accesses(&mut self) -> Vec<BusAccess> {
+        self.access_log
+            .as_mut()
+            .map_or_else(Vec::new, |log| std::mem::take(log.get_mut()))
+    }
+
+    #[inline(always)]
+    pub fn read_byte(&self, address: u16) -> u8 {
+        let value = self.peek_byte(address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, Access::Read, value);
+        }
+        if self.access_log.is_some() {
+            self.log_access(address, Access::Read, value);
+        }
+        value
+    }
+
+    /// Reads like the CPU would but without triggering watchpoints, for debuggers and tools.
+    #[inline(always)]
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        if let Some(ram) = &self.flat_ram {
+            return ram[address as usize];
+        }
+        match PAGES[(address >> 8) as usize] {
+            Region::Rom => match &self.cartridge {
+                Some(cartridge) => cartridge.read_rom(address),
+                None => self.rom[address as usize],
+            },
+            Region::Vram => self.gpu.read_vram(address as usize - VRAM_BEGIN),
+            Region::ExternalRam => match &self.cartridge {
+                Some(cartridge) => cartridge.read_ram(address),
+                None => 0xFF,
+            },
+            Region::Wram => self.wram[(address & WRAM_MASK) as usize],
+            Region::High => self.read_high(address),
+        }
+    }
+
+    /// The cartridge ROM bank mapped at `address`, 0 for anything that isn't banked.
+    pub fn rom_bank_at(&self, address: u16) -> usize {
+        match (&self.cartridge, PAGES[(address >> 8) as usize]) {
+            (Some(cartridge), Region::Rom) if !self.is_flat() => cartridge.bank_at(address),
+            _ => 0,
+        }
+    }
+
+    fn read_high(&self, address: u16) -> u8 {
+        match address {
+            OAM_BEGIN..=OAM_END => self.oam[(address - OAM_BEGIN) as usize],
+            UNUSABLE_BEGIN..=UNUSABLE_END => self.read_unusable(),
+            IO_BEGIN..=IO_END => {
+                self.read_io(address) | IO_READ_MASKS[(address - IO_BEGIN) as usize]
+            }
+            HRAM_BEGIN..=HRAM_END => self.hram[(address - HRAM_BEGIN) as usize],
+            _ => self.interrupt_enable,
+        }
+    }
+
+    // Nothing drives the bus here. While OAM is blocked every model reads 0xFF, otherwise it
+    // depends on the revision. The OAM corruption these reads trigger on a DMG isn't emulated.
+    fn read_unusable(&self) -> u8 {
+        if self.dma_index < OAM_SIZE || self.gpu.oam_blocked() {
+            return 0xFF;
+        }
+        match self.model {
+            Model::Dmg => 0x00,
+        }
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            0xFF00 => self.joypad.read(),
+            0xFF01..=0xFF02 => self.serial.read_register(address),
+            0xFF04..=0xFF07 => self.timer.read_register(address),
+            INTERRUPT_FLAG_ADDRESS => self.interrupt_flag,
+            SOUND_BEGIN..=SOUND_END => self.sound[(address - SOUND_BEGIN) as usize],
+            0xFF46 => (self.dma_source >> 8) as u8,
+            0xFF40..=0xFF4B => self.gpu.read_register(address),
+            _ => 0xFF,
+        }
+    }
+
+    #[inline(always)]
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, Access::Write, value);
+        }
+        if self.access_log.is_some() {
+            self.log_access(address, Access::Write, value);
+        }
+        self.store_byte(address, value);
+    }
+
+    /// Writes without triggering watchpoints, for debuggers and tools. ROM is patched
+    /// instead of programming the bank controller.
+    pub fn poke_byte(&mut self, address: u16, value: u8) {
+        let flat = self.is_flat();
+        match &mut self.cartridge {
+            Some(cartridge) if address <= 0x7FFF && !flat => cartridge.patch_rom(address, value),
+            _ => self.store_byte(address, value),
+        }
+    }
+
+    #[inline(always)]
+    fn store_byte(&mut self, address: u16, value: u8) {
+        if let Some(ram) = &mut self.flat_ram {
+            ram[address as usize] = value;
+            return;
+        }
+        match PAGES[(address >> 8) as usize] {
+            Region::Rom => match &mut self.cartridge {
+                Some(cartridge) => cartridge.write_rom(address, value),
+                None => self.rom[address as usize] = value,
+            },
+            Region::Vram => self.gpu.write_vram(address as usize - VRAM_BEGIN, value),
+            Region::ExternalRam => {
+                if let Some(cartridge) = &mut self.cartridge {
+                    cartridge.write_ram(address, value);
+                }
+            }
+            Region::Wram => self.wram[(address & WRAM_MASK) as usize] = value,
+            Region::High => self.write_high(address, value),
+        }
+    }
+
+    fn write_high(&mut self, address: u16, value: u8) {
+        let peripheral = is_peripheral(address);
+        if peripheral {
+            self.sync();
+        }
+        match address {
+            OAM_BEGIN..=OAM_END => self.oam[(address - OAM_BEGIN) as usize] = value,
+            UNUSABLE_BEGIN..=UNUSABLE_END => {}
+            IO_BEGIN..=IO_END => self.write_io(address, value),
+            HRAM_BEGIN..=HRAM_END => self.hram[(address - HRAM_BEGIN) as usize] = value,
+            _ => self.interrupt_enable = value,
+        }
+        if peripheral {
+            // The write may have moved or cancelled the next event
+            self.reschedule();
+        }
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF00 => self.joypad.write(value),
+            0xFF01..=0xFF02 => self.serial.write_register(address, value),
+            0xFF04..=0xFF07 => {
+                let overflow = self.timer.write_register(address, value);
+                if overflow {
+                    self.request_interrupt(TIMER_INTERRUPT);
+                }
+            }
+            INTERRUPT_FLAG_ADDRESS => self.interrupt_flag = value,
+            SOUND_BEGIN..=SOUND_END => self.sound[(address - SOUND_BEGIN) as usize] = value,
+            0xFF46 => {
+                // OAM DMA from value * 0x100
+                self.dma_source = (value as u16) << 8;
+                self.dma_index = 0;
+            }
+            0xFF40..=0xFF4B => self.gpu.write_register(address, value),
+            _ => {}
+        }
+    }
+
+    #[cold]
+    fn log_access(&self, address: u16, access: Access, value: u8) {
+        if let Some(log) = &self.access_log {
+            log.borrow_mut().push(BusAccess {
+                cycle: self.scheduler.now(),
+                address,
+                value,
+                access,
+            });
+        }
+    }
+
+    fn check_watchpoints(&self, address: u16, access: Access, value: u8) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        if let Some(index) = self
+            .watchpoints
+            .iter()
+            .position(|watchpoint| watchpoint.matches(address, access, value))
+        {
+            self.watch_hit.set(Some(WatchHit {
+                watchpoint: index,
+                address,
+                access,
+                value,
+            }));
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns the first watchpoint hit since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// Brings the peripherals up to the current cycle. Reads through `peek_byte` and
+    /// `read_byte` can see stale timer, LCD and serial registers until this is called.
+    pub fn sync(&mut self) {
+        if self.is_flat() {
+            return;
+        }
+        // A scanline always ends within LINE_CYCLES, so this never spans more than one
+        let cycles = (self.scheduler.now() - self.synced_at) as u16;
+        self.synced_at = self.scheduler.now();
+
+        self.step_dma(cycles);
+        let ly = self.gpu.ly;
+        if self.gpu.step(cycles) == 144 && ly != 144 {
+            self.request_interrupt(VBLANK_INTERRUPT);
+        }
+        if self.timer.step(cycles) {
+            self.request_interrupt(TIMER_INTERRUPT);
+        }
+        if self.serial.step(cycles) {
+            self.request_interrupt(SERIAL_INTERRUPT);
+        }
+        self.reschedule();
+    }
+
+    // Deadlines are derived from the peripherals' state, they have to be synced first
+    fn reschedule(&mut self) {
+        self.scheduler
+            .schedule(Event::LineEnd, self.gpu.cycles_until_line_end() as u64);
+        match self.timer.cycles_until_overflow() {
+            Some(cycles) => self.scheduler.schedule(Event::TimerOverflow, cycles),
+            None => self.scheduler.cancel(Event::TimerOverflow),
+        }
+        match self.serial.cycles_until_event() {
+            Some(cycles) => self.scheduler.schedule(Event::Serial, cycles),
+            None => self.scheduler.cancel(Event::Serial),
+        }
+        if self.dma_index < OAM_SIZE {
+            self.scheduler
+                .schedule(Event::DmaEnd, (OAM_SIZE - self.dma_index) as u64);
+        } else {
+            self.scheduler.cancel(Event::DmaEnd);
+        }
+    }
+
+    /// Moves time forward by `cycles` M-cycles, catching up on whatever became due.
+    #[inline(always)]
+    pub(crate) fn advance(&mut self, cycles: u16) {
+        if self.scheduler.advance(cycles) {
+            while self.scheduler.pop_due().is_some() {
+                self.sync();
+            }
+        } else if self.eager_sync {
+            self.sync();
+        }
+    }
+
+    // At least one, a scanline at most
+    pub(crate) fn cycles_until_event(&self) -> u16 {
+        if self.is_flat() {
+            return 1;
+        }
+        self.scheduler
+            .cycles_until_next()
+            .map_or(1, |cycles| cycles.clamp(1, LINE_CYCLES as u64) as u16)
+    }
+
+    fn step_dma(&mut self, cycles: u16) {
+        for _ in 0..cycles {
+            if self.dma_index >= OAM_SIZE {
+                return;
+            }
+            let value = self.peek_byte(self.dma_source + self.dma_index as u16);
+            self.oam[self.dma_index as usize] = value;
+            self.dma_index += 1;
+        }
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: u8) {
+        self.interrupt_flag |= interrupt;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.request_interrupt(JOYPAD_INTERRUPT);
+        }
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        // With a cartridge inserted the ROM area is the cartridge's, and it saves its own state
+        writer.bool(self.cartridge.is_some());
+        if self.cartridge.is_none() {
+            writer.bytes(&self.rom[..]);
+        }
+        writer.bytes(&self.wram);
+        writer.bytes(&self.oam);
+        writer.bytes(&self.hram);
+        writer.u8(self.interrupt_flag);
+        writer.u8(self.interrupt_enable);
+        writer.bytes(&self.sound);
+        self.gpu.save_state(writer);
+        self.serial.save_state(writer);
+        self.timer.save_state(writer);
+        self.joypad.save_state(writer);
+        writer.u16(self.dma_source);
+        writer.u8(self.dma_index);
+        self.scheduler.save_state(writer);
+        writer.u64(self.synced_at);
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save_state(writer);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        if reader.bool()? != self.cartridge.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Save state doesn't match the loaded cartridge",
+            ));
+        }
+        if self.cartridge.is_none() {
+            reader.bytes_into(&mut self.rom[..])?;
+        }
+        reader.bytes_into(&mut self.wram)?;
+        reader.bytes_into(&mut self.oam)?;
+        reader.bytes_into(&mut self.hram)?;
+        self.interrupt_flag = reader.u8()?;
+        self.interrupt_enable = reader.u8()?;
+        reader.bytes_into(&mut self.sound)?;
+        self.gpu.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.dma_source = reader.u16()?;
+        self.dma_index = reader.u8()?;
+        self.scheduler.load_state(reader)?;
+        self.synced_at = reader.u64()?;
+        match &mut self.cartridge {
+            Some(cartridge) => cartridge.load_state(reader),
+            None => Ok(()),
+        }
+    }
+
+    /// Loads the boot ROM, or any other program, into 0x0000-0x7FFF while no cartridge is
+    /// inserted.
+    pub fn load_bootrom(&mut self, path: &Path) -> std::io::Result<()> {
+        let mut file = File::open(path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        let length = buffer.len().min(ROM_SIZE);
+        self.rom[..length].copy_from_slice(&buffer[..length]);
+
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, path: &Path) -> std::io::Result<()> {
+        self.cartridge = Some(Cartridge::load(path)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::state::{StateReader, StateWriter};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+
+#[derive(Debug, PartialEq)]
+enum MemoryBankController {
+    None,
+    MBC1,
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: MemoryBankController,
+    ram_enabled: bool,
+    rom_bank: u8,     // Lower 5 bits of the ROM bank number
+    bank_high: u8,    // RAM bank or upper 2 bits of the ROM bank number
+    banking_mode: u8, // 0 - simple, 1 - advanced
+    checksum: u32,
+}
+
+impl Cartridge {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        Ok(Cartridge::from_bytes(buffer))
+    }
+
+    pub fn from_bytes(mut rom: Vec<u8>) -> Self {
+        // Pad to at least two banks so the fixed and switchable areas are always backed
+        if rom.len() < 2 * ROM_BANK_SIZE {
+            rom.resize(2 * ROM_BANK_SIZE, 0xFF);
+        }
+
+        let mbc = match rom[CARTRIDGE_TYPE_ADDRESS] {
+            0x00 | 0x08 | 0x09 => MemoryBankController::None,
+            0x01..=0x03 => MemoryBankController::MBC1,
+            other => {
+                log::warn!(
+                    "Unsupported cartridge type {:#04X}, treating it as MBC1",
+                    other
+                );
+                MemoryBankController::MBC1
+            }
+        };
+
+        // Test ROMs report results through cartridge RAM even when the header declares none
+        let ram_size = match rom[RAM_SIZE_ADDRESS] {
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            _ => RAM_BANK_SIZE,
+        };
+
+        Cartridge {
+            checksum: crc32(&rom),
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            ram_enabled: false,
+            rom_bank: 1,
+            bank_high: 0,
+            banking_mode: 0,
+        }
+    }
+
+    /// CRC-32 of the whole ROM image, used to tell games apart.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len() / ROM_BANK_SIZE
+    }
+
+    /// Bank currently mapped at 0x4000-0x7FFF.
+    pub fn rom_bank(&self) -> usize {
+        match self.mbc {
+            MemoryBankController::None => 1,
+            MemoryBankController::MBC1 => {
+                ((self.bank_high as usize) << 5 | self.rom_bank as usize) % self.rom_bank_count()
+            }
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = if self.banking_mode == 1 {
+            self.bank_high as usize
+        } else {
+            0
+        };
+        (bank * RAM_BANK_SIZE + (address as usize - 0xA000)) % self.ram.len()
+    }
+
+    // Offset into the ROM image of the byte mapped at `address`
+    fn rom_offset(&self, address: u16) -> usize {
+        let address = address as usize;
+        let bank = match address {
+            0x0000..=0x3FFF => {
+                if self.mbc == MemoryBankController::MBC1 && self.banking_mode == 1 {
+                    ((self.bank_high as usize) << 5) % self.rom_bank_count()
+                } else {
+                    0
+                }
+            }
+            _ => self.rom_bank(),
+        };
+        bank * ROM_BANK_SIZE + (address % ROM_BANK_SIZE)
+    }
+
+    /// The ROM bank mapped at `address`, which must be in 0x0000-0x7FFF.
+    pub fn bank_at(&self, address: u16) -> usize {
+        self.rom_offset(address) / ROM_BANK_SIZE
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.rom[self.rom_offset(address)]
+    }
+
+    /// Changes the ROM byte currently mapped at `address`, for patching code in a debugger.
+    pub fn patch_rom(&mut self, address: u16, value: u8) {
+        let offset = self.rom_offset(address);
+        if self.rom[offset] != value {
+            self.rom[offset] = value;
+            self.checksum = crc32(&self.rom);
+        }
+    }
+
+    /// Writes to the ROM area never change the ROM, they program the bank controller.
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        if self.mbc == MemoryBankController::None {
+            return;
+        }
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Bank 0 can't be selected here, it maps to bank 1 instead
+                self.rom_bank = (value & 0x1F).max(1);
+            }
+            0x4000..=0x5FFF => self.bank_high = value & 0x03,
+            _ => self.banking_mode = value & 0x01,
+        }
+    }
+
+    // The ROM itself isn't saved, a state can only be loaded into the game it came from
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.ram);
+        writer.bool(self.ram_enabled);
+        writer.u8(self.rom_bank);
+        writer.u8(self.bank_high);
+        writer.u8(self.banking_mode);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        reader.bytes_into(&mut self.ram)?;
+        self.ram_enabled = reader.bool()?;
+        self.rom_bank = reader.u8()?;
+        self.bank_high = reader.u8()?;
+        self.banking_mode = reader.u8()?;
+        Ok(())
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if self.mbc == MemoryBankController::MBC1 && !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram[self.ram_offset(address)]
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if self.mbc == MemoryBankController::MBC1 && !self.ram_enabled {
+            return;
+        }
+        let offset = self.ram_offset(address);
+        self.ram[offset] = value;
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
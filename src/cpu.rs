@@ -1,98 +1,84 @@
+use crate::bus::is_peripheral;
+pub use crate::bus::{
+    MemoryBus, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS, JOYPAD_INTERRUPT,
+    SERIAL_INTERRUPT, TIMER_INTERRUPT, VBLANK_INTERRUPT,
+};
+use crate::cartridge::*;
+use crate::disasm::Decoded;
 use crate::gpu::*;
+use crate::history::{Executed, History};
 use crate::instructions::*;
 use crate::registers::*;
-use std::fs::File;
-use std::io::Read;
+use crate::state::{StateReader, StateWriter};
+use crate::symbols::{Location, Symbols};
+use std::io::Write;
 use std::path::Path;
 
-pub struct CPU {
-    pub registers: Registers,
-    pub pc: u16,
-    pub sp: u16,
-    pub bus: MemoryBus,
-    is_halted: bool,
-    pub cycle_count: u16,
-    pub debug_mode: bool,
-}
+/// All cycle counts are M-cycles, four clock ticks (dots) each. The LCD draws a frame in
+/// 154 lines of 114 M-cycles.
+pub const CYCLES_PER_FRAME: u32 = 154 * LINE_CYCLES as u32;
 
-#[allow(dead_code)]
-pub struct MemoryBus {
-    pub memory: [u8; 0xFFFF + 1],
-    pub gpu: GPU,
+#[derive(Clone, Copy, Debug)]
+pub enum CpuError {
+    /// The byte at `address` isn't an instruction
+    IllegalOpcode { opcode: u8, address: u16 },
+    /// The instruction at `address` has operands the CPU can't execute
+    InvalidOperand {
+        instruction: Instruction,
+        address: u16,
+    },
+    /// A push or pop at `address` would move SP past either end of the address space
+    StackFault { sp: u16, address: u16 },
 }
 
-impl MemoryBus {
-    #[inline(always)]
-    pub fn read_byte(&self, address: u16) -> u8 {
-        let address = address as usize;
-        match address {
-            VRAM_BEGIN..=VRAM_END => self.gpu.read_vram(address - VRAM_BEGIN),
-            0xFF44 => self.gpu.ly,
-            _ => self.memory[address as usize],
-        }
-    }
-
-    #[inline(always)]
-    pub fn write_byte(&mut self, address: u16, value: u8) {
-        match address as usize {
-            // VRAM
-            VRAM_BEGIN..=VRAM_END => {
-                self.gpu.write_vram(address as usize - VRAM_BEGIN, value);
-                self.memory[address as usize] = value;
-            }
-
-            // GPU Registers
-            0xFF40 => {
-                // LCDC
-                self.memory[address as usize] = value;
-                self.gpu.lcdc = value;
-            }
-            0xFF41 => {
-                // STAT
-                self.memory[address as usize] = value;
-                self.gpu.stat = value;
-            }
-            0xFF42 => {
-                // SCY
-                self.memory[address as usize] = value;
-                self.gpu.scy = value;
-            }
-            0xFF43 => {
-                // SCX
-                self.memory[address as usize] = value;
-                self.gpu.scx = value;
-            }
-            0xFF44 => {
-                // LY
-                self.memory[address as usize] = value;
-                self.gpu.ly = value;
-            }
-            0xFF45 => {
-                // LYC
-                self.memory[address as usize] = value;
-                self.gpu.lyc = value;
-            }
-            0xFF47 => {
-                // BGP
-                self.memory[address as usize] = value;
-                self.gpu.bgp = value;
-            }
-            _ => {
-                self.memory[address as usize] = value;
+impl std::fmt::Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CpuError::IllegalOpcode { opcode, address } => {
+                write!(f, "Illegal opcode {:#04x} at {:#06x}", opcode, address)
+            }
+            CpuError::InvalidOperand {
+                instruction,
+                address,
+            } => write!(
+                f,
+                "Invalid operands for {:?} at {:#06x}",
+                instruction, address
+            ),
+            CpuError::StackFault { sp, address } => {
+                write!(f, "Stack fault with SP {:#06x} at {:#06x}", sp, address)
             }
         }
     }
+}
 
-    pub fn load_rom(&mut self, path: &Path) -> std::io::Result<()> {
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-
-        let length = buffer.len().min(self.memory.len());
-        self.memory[..length].copy_from_slice(&buffer[..length]);
+impl std::error::Error for CpuError {}
 
-        Ok(())
-    }
+pub struct CPU {
+    pub registers: Registers,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: MemoryBus,
+    is_halted: bool,
+    // EI only enables interrupts once the instruction after it has started
+    ime_pending: bool,
+    // Set after an illegal opcode when lock_up_on_illegal_opcode is enabled
+    locked_up: bool,
+    /// Hang like a real DMG on illegal opcodes instead of returning an error
+    pub lock_up_on_illegal_opcode: bool,
+    /// Stop with a stack fault when an instruction would wrap SP instead of wrapping like
+    /// hardware does
+    pub fault_on_stack_wrap: bool,
+    // Set when LD B,B executes, test ROMs use it as a software breakpoint
+    breakpoint_hit: bool,
+    pub cycle_count: u16,
+    pub debug_mode: bool,
+    /// Gets a `trace_line` before every instruction is executed
+    pub trace: Option<Box<dyn Write>>,
+    /// When set, trace lines end with the closest symbol, e.g. ` ; Main.loop+$03`
+    pub trace_symbols: Option<Symbols>,
+    /// The last instructions executed, for finding out what led up to a fault
+    pub history: History,
 }
 
 #[allow(dead_code)]
@@ -117,13 +103,18 @@ impl Default for CPU {
             },
             pc: 0,
             sp: 0,
-            bus: MemoryBus {
-                memory: { [0u8; 0xFFFF + 1] },
-                gpu: GPU::new(),
-            },
+            bus: MemoryBus::new(),
             is_halted: false,
+            ime_pending: false,
+            locked_up: false,
+            lock_up_on_illegal_opcode: false,
+            fault_on_stack_wrap: false,
+            breakpoint_hit: false,
             cycle_count: 0,
             debug_mode: false,
+            trace: None,
+            trace_symbols: None,
+            history: History::default(),
         }
     }
 }
@@ -131,7 +122,7 @@ impl Default for CPU {
 impl CPU {
     pub fn new_bootrom(path: &Path) -> std::io::Result<Self> {
         let mut cpu = CPU::default();
-        cpu.bus.load_rom(path).unwrap();
+        cpu.bus.load_bootrom(path)?;
         let nintendo_logo = [
             0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C,
             0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6,
@@ -141,14 +132,19 @@ impl CPU {
 
         // Place Nintendo logo at 0x0104-0x0133
         for (i, &byte) in nintendo_logo.iter().enumerate() {
-            cpu.bus.memory[0x0104 + i] = byte;
+            cpu.bus.poke_byte(0x0104 + i as u16, byte);
         }
         Ok(cpu)
     }
 
-    pub fn _new_with_rom(path: &Path) -> std::io::Result<Self> {
+    pub fn new_with_rom(path: &Path) -> std::io::Result<Self> {
+        Ok(CPU::with_cartridge(Cartridge::load(path)?))
+    }
+
+    /// Starts `cartridge` at 0x100 with the registers the boot ROM leaves behind.
+    pub fn with_cartridge(cartridge: Cartridge) -> Self {
         let mut cpu = CPU::default();
-        cpu.bus.load_rom(path).unwrap();
+        cpu.bus.cartridge = Some(cartridge);
         cpu.pc = 0x100;
         cpu.sp = 0xFFFE;
         cpu.registers.a = 0x11;
@@ -164,39 +160,79 @@ impl CPU {
         cpu.registers.e = 0x56;
         cpu.registers.h = 0x00;
         cpu.registers.l = 0x0D;
-        Ok(cpu)
+        cpu
     }
 
-    fn get_instruction_cycles(&self, instruction: &Instruction) -> u16 {
-        match instruction {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.registers.a);
+        writer.u8(self.registers.b);
+        writer.u8(self.registers.c);
+        writer.u8(self.registers.d);
+        writer.u8(self.registers.e);
+        writer.u8(u8::from(self.registers.f));
+        writer.u8(self.registers.h);
+        writer.u8(self.registers.l);
+        writer.bool(self.registers.ime);
+        writer.bool(self.ime_pending);
+        writer.u16(self.pc);
+        writer.u16(self.sp);
+        writer.bool(self.is_halted);
+        writer.bool(self.locked_up);
+        self.bus.save_state(writer);
+    }
+
+    /// Restores a state written by `save_state`. If this fails part of the state may already
+    /// have been overwritten.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        self.registers.a = reader.u8()?;
+        self.registers.b = reader.u8()?;
+        self.registers.c = reader.u8()?;
+        self.registers.d = reader.u8()?;
+        self.registers.e = reader.u8()?;
+        self.registers.f = FlagsRegister::from(reader.u8()?);
+        self.registers.h = reader.u8()?;
+        self.registers.l = reader.u8()?;
+        self.registers.ime = reader.bool()?;
+        self.ime_pending = reader.bool()?;
+        self.pc = reader.u16()?;
+        self.sp = reader.u16()?;
+        self.is_halted = reader.bool()?;
+        self.locked_up = reader.bool()?;
+        // What ran before belongs to another timeline
+        self.history.clear();
+        self.bus.load_state(reader)
+    }
+
+    // None when the operands don't form a valid instruction
+    fn get_instruction_cycles(&self, instruction: &Instruction) -> Option<u16> {
+        let cycles = match instruction {
             Instruction::ADC(target) => match target {
-                Target::Const8() => 2,
+                Target::Const8(_) => 2,
                 Target::Register(_) => 1,
                 Target::MemoryR16(_) => 2,
-                _ => 1,
+                _ => return None,
             },
             Instruction::ADD(target, source) => {
                 match (target, source) {
                     (Target::Register(_), Target::Register(_)) => 1,
                     (Target::Register(_), Target::MemoryR16(_)) => 2,
-                    (Target::Register(_), Target::Const16()) => 2,
-                    (Target::MemoryR16(_), Target::Register16(_)) => 2,
+                    (Target::Register(_), Target::Const8(_)) => 2,
                     (Target::Register16(_), Target::Register16(_)) => 2,
-                    (Target::Register16(_), Target::Const8()) => 3,
+                    (Target::Register16(_), Target::Const8(_)) => 4,
                     // (Target::Register16(_), Target::Offset8()) => 4,
-                    _ => panic!("Invalid ADD instruction instruction cycle count target: {:?}, source: {:?}", target, source),
+                    _ => return None,
                 }
             }
             Instruction::AND(target, source) => match (target, source) {
                 (Target::Register(_), Target::Register(_)) => 1,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
-                (Target::Register(_), Target::Const8()) => 2,
-                _ => 1,
+                (Target::Register(_), Target::Const8(_)) => 2,
+                _ => return None,
             },
             Instruction::BIT(_, target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 3,
-                _ => 1,
+                _ => return None,
             },
             Instruction::CALL(condition, _) => match condition {
                 JumpCondition::Always => 6,
@@ -212,15 +248,15 @@ impl CPU {
             Instruction::CP(target) => match target {
                 Target::Register(_) => 1,
                 Target::MemoryR16(_) => 2,
-                Target::Const8() => 2,
-                _ => 1,
+                Target::Const8(_) => 2,
+                _ => return None,
             },
             Instruction::CPL() => 1,
             Instruction::DEC(target) => match target {
                 Target::Register(_) => 1,
                 Target::MemoryR16(_) => 3,
                 Target::Register16(_) => 2,
-                _ => 1,
+                _ => return None,
             },
             Instruction::DI() => 1,
             Instruction::EI() => 1,
@@ -228,7 +264,7 @@ impl CPU {
                 Target::Register(_) => 1,
                 Target::MemoryR16(_) => 3,
                 Target::Register16(_) => 2,
-                _ => 1,
+                _ => return None,
             },
             Instruction::JP(condition, _) => match condition {
                 JumpCondition::Always => 4,
@@ -241,7 +277,7 @@ impl CPU {
                 }
             },
             Instruction::JPHL(_) => 1,
-            Instruction::JR(condition) => match condition {
+            Instruction::JR(condition, _) => match condition {
                 JumpCondition::Always => 3,
                 _ => {
                     if self.get_jcondition_value(*condition) {
@@ -253,72 +289,59 @@ impl CPU {
             },
             Instruction::LD(target, source) => match (target, source) {
                 (Target::Register(_), Target::Register(_)) => 1,
-                (Target::Register(_), Target::Const8()) => 2,
-                (Target::Register16(_), Target::Const16()) => 3,
+                (Target::Register(_), Target::Const8(_)) => 2,
+                (Target::Register16(_), Target::Const16(_)) => 3,
                 (Target::MemoryR16(_), Target::Register(_)) => 2,
-                (Target::MemoryR16(_), Target::Const8()) => 3,
+                (Target::MemoryR16(_), Target::Const8(_)) => 3,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
                 (Target::Register16(_), Target::Register(_)) => 2,
-                (Target::MemoryConst16(), Target::Register(_)) => 4,
-                (Target::MemoryConst16(), Target::Register16(_)) => 5,
+                (Target::MemoryConst16(_), Target::Register(_)) => 4,
+                (Target::MemoryConst16(_), Target::Register16(_)) => 5,
                 (Target::Register16(_), Target::Register16(_)) => 2,
-                _ => panic!(
-                    "Invalid LD instruction cycle count target: {:?}, source: {:?}",
-                    target, source
-                ),
+                (Target::Register(_), Target::MemoryConst16(_)) => 4,
+                _ => return None,
             },
-            Instruction::LDHLSP() => 3,
+            Instruction::LDHLSP(_) => 3,
             Instruction::LDH(target, source) => match (target, source) {
-                (LDHRegister::MemoryConst16(_), LDHRegister::ArithmeticTarget) => 3,
-                (LDHRegister::ArithmeticTarget, LDHRegister::MemoryConst16(_)) => 3,
                 (LDHRegister::ArithmeticTarget, LDHRegister::C) => 2,
                 (LDHRegister::C, LDHRegister::ArithmeticTarget) => 2,
-                (LDHRegister::MemA8, LDHRegister::ArithmeticTarget) => 2,
-                (LDHRegister::ArithmeticTarget, LDHRegister::MemA8) => 2,
-                _ => panic!(
-                    "Invalid LDH instruction cycle count target: {:?}, source: {:?}",
-                    target, source
-                ),
+                (LDHRegister::MemA8(_), LDHRegister::ArithmeticTarget) => 3,
+                (LDHRegister::ArithmeticTarget, LDHRegister::MemA8(_)) => 3,
+                _ => return None,
             },
             Instruction::LDI(target, source) => match (target, source) {
                 (Target::MemoryR16(_), Target::Register(_)) => 2,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
                 (Target::Register(_), Target::Register16(_)) => 2,
 
-                _ => panic!(
-                    "Invalid LDI instruction cycle count target: {:?}, source: {:?}",
-                    target, source
-                ),
+                _ => return None,
             },
             Instruction::LDD(target, source) => match (target, source) {
                 (Target::MemoryR16(_), Target::Register(_)) => 2,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
-                _ => panic!(
-                    "Invalid LDD instruction cycle count target: {:?}, source: {:?}",
-                    target, source
-                ),
+                _ => return None,
             },
             Instruction::NOP() => 1,
             Instruction::OR(target, source) => match (target, source) {
                 (Target::Register(_), Target::Register(_)) => 1,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
-                (Target::Register(_), Target::Const8()) => 2,
-                _ => 1,
+                (Target::Register(_), Target::Const8(_)) => 2,
+                _ => return None,
             },
             Instruction::POPAF() => 3,
             Instruction::POP(target) => match target {
                 Target::Register16(_) => 3,
-                _ => panic!("Invalid POP instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::PUSHAF() => 4,
             Instruction::PUSH(target) => match target {
                 Target::Register16(_) => 4,
-                _ => panic!("Invalid PUSH instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::RES(_, target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid RES instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::RET(condition) => match condition {
                 JumpCondition::Always => 4,
@@ -331,81 +354,80 @@ impl CPU {
                 }
             },
             Instruction::RETI(_) => 4,
+            Instruction::RST(_) => 4,
             Instruction::RL(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid RL instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::RLA() => 1,
             Instruction::RLC(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid RLC instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::RLCA() => 1,
             Instruction::RR(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid RR instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::RRA() => 1,
             Instruction::RRC(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid RRC instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::RRCA() => 1,
             Instruction::SBC(target, source) => match (target, source) {
                 (Target::Register(_), Target::Register(_)) => 1,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
-                (Target::Register(_), Target::Const8()) => 2,
-                _ => panic!(
-                    "Invalid SBC instruction cycle count target: {:?}, source: {:?}",
-                    target, source
-                ),
+                (Target::Register(_), Target::Const8(_)) => 2,
+                _ => return None,
             },
             Instruction::SCF() => 1,
             Instruction::SET(_, target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid SET instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::SLA(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid SLA instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::SRA(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid SRA instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::SRL(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid SRL instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::SUB(target) => match target {
                 Target::Register(_) => 1,
                 Target::MemoryR16(_) => 2,
-                Target::Const8() => 2,
-                _ => panic!("Invalid SUB instruction cycle count target: {:?}", target),
+                Target::Const8(_) => 2,
+                _ => return None,
             },
             Instruction::SWAP(target) => match target {
                 Target::Register(_) => 2,
                 Target::MemoryR16(_) => 4,
-                _ => panic!("Invalid SWAP instruction cycle count target: {:?}", target),
+                _ => return None,
             },
             Instruction::XOR(target, source) => match (target, source) {
                 (Target::Register(_), Target::Register(_)) => 1,
                 (Target::Register(_), Target::MemoryR16(_)) => 2,
-                (Target::Register(_), Target::Const8()) => 2,
-                _ => 1,
+                (Target::Register(_), Target::Const8(_)) => 2,
+                _ => return None,
             },
             Instruction::DAA() => 1,
-            Instruction::HALT() => 0,
-            Instruction::STOP() => 0,
-        }
+            Instruction::HALT() => 1,
+            Instruction::STOP() => 1,
+        };
+        Some(cycles)
     }
 
     #[inline(always)]
@@ -426,28 +448,18 @@ impl CPU {
                 DoubleTarget::HL => self.registers.set_hl(value),
                 DoubleTarget::SP => self.sp = value,
             },
-            Target::MemoryR8(arithmetic_target) => match arithmetic_target {
-                ArithmeticTarget::A => self.bus.write_byte(self.registers.a as u16, value as u8),
-                ArithmeticTarget::B => self.bus.write_byte(self.registers.b as u16, value as u8),
-                ArithmeticTarget::C => self.bus.write_byte(self.registers.c as u16, value as u8),
-                ArithmeticTarget::D => self.bus.write_byte(self.registers.d as u16, value as u8),
-                ArithmeticTarget::E => self.bus.write_byte(self.registers.e as u16, value as u8),
-                ArithmeticTarget::H => self.bus.write_byte(self.registers.h as u16, value as u8),
-                ArithmeticTarget::L => self.bus.write_byte(self.registers.l as u16, value as u8),
-            },
             Target::MemoryR16(double_target) => match double_target {
-                DoubleTarget::BC => self.bus.write_byte(self.registers.get_bc(), value as u8),
-                DoubleTarget::DE => self.bus.write_byte(self.registers.get_de(), value as u8),
-                DoubleTarget::HL => self.bus.write_byte(self.registers.get_hl(), value as u8),
-                DoubleTarget::SP => self.bus.write_byte(self.sp, value as u8),
+                DoubleTarget::BC => self.write_cycle(self.registers.get_bc(), value as u8),
+                DoubleTarget::DE => self.write_cycle(self.registers.get_de(), value as u8),
+                DoubleTarget::HL => self.write_cycle(self.registers.get_hl(), value as u8),
+                DoubleTarget::SP => self.write_cycle(self.sp, value as u8),
             },
-            Target::MemoryConst16() => {
-                let address = self.bus.read_byte(self.pc.wrapping_add(1));
-                self.bus.write_byte(address as u16, value as u8);
-                self.pc = self.pc.wrapping_add(1);
-            }
+            Target::MemoryConst16(address) => self.write_cycle(address, value as u8),
             _ => {
-                panic!("Invalid target for set_register_value: {:?}", target)
+                unreachable!(
+                    "Operands are validated by get_instruction_cycles: {:?}",
+                    target
+                )
             }
         }
     }
@@ -470,36 +482,15 @@ impl CPU {
                 DoubleTarget::HL => self.registers.get_hl(),
                 DoubleTarget::SP => self.sp,
             },
-            Target::MemoryR8(arithmetic_target) => match arithmetic_target {
-                ArithmeticTarget::A => self.bus.memory[self.registers.a as usize] as u16,
-                ArithmeticTarget::B => self.bus.memory[self.registers.b as usize] as u16,
-                ArithmeticTarget::C => self.bus.memory[self.registers.c as usize] as u16,
-                ArithmeticTarget::D => self.bus.memory[self.registers.d as usize] as u16,
-                ArithmeticTarget::E => self.bus.memory[self.registers.e as usize] as u16,
-                ArithmeticTarget::H => self.bus.memory[self.registers.h as usize] as u16,
-                ArithmeticTarget::L => self.bus.memory[self.registers.l as usize] as u16,
-            },
             Target::MemoryR16(double_target) => match double_target {
-                DoubleTarget::BC => self.bus.memory[self.registers.get_bc() as usize] as u16,
-                DoubleTarget::DE => self.bus.memory[self.registers.get_de() as usize] as u16,
-                DoubleTarget::HL => self.bus.memory[self.registers.get_hl() as usize] as u16,
-                DoubleTarget::SP => self.bus.memory[self.sp as usize] as u16,
+                DoubleTarget::BC => self.read_cycle(self.registers.get_bc()) as u16,
+                DoubleTarget::DE => self.read_cycle(self.registers.get_de()) as u16,
+                DoubleTarget::HL => self.read_cycle(self.registers.get_hl()) as u16,
+                DoubleTarget::SP => self.read_cycle(self.sp) as u16,
             },
-            Target::Const8() => {
-                let result = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
-                self.pc = self.pc.wrapping_add(1);
-                result
-            }
-            Target::Const16() => {
-                let low_byte = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
-                let high_byte = self.bus.read_byte(self.pc.wrapping_add(2)) as u16;
-                self.pc = self.pc.wrapping_add(2);
-                (high_byte << 8) | low_byte
-            }
-            Target::MemoryConst16() => {
-                let address = self.bus.read_byte(self.pc.wrapping_add(1));
-                self.bus.read_byte(address as u16) as u16
-            }
+            Target::Const8(value) => value as u16,
+            Target::Const16(value) => value,
+            Target::MemoryConst16(address) => self.read_cycle(address) as u16,
         };
         value
     }
@@ -514,70 +505,216 @@ impl CPU {
         }
     }
 
-    pub fn push(&mut self, value: u16) {
-        if self.sp < 2 {
-            panic!("Stack overflow");
+    /// Like on hardware SP wraps around at either end of the address space, unless
+    /// `fault_on_stack_wrap` is set.
+    pub fn push(&mut self, value: u16) -> Result<(), CpuError> {
+        self.check_stack(self.sp.checked_sub(2))?;
+        // SP is decremented in an internal cycle before the first write
+        self.tick(1);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, value as u8);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<u16, CpuError> {
+        self.check_stack(self.sp.checked_add(2))?;
+        let low_byte = self.read_cycle(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let high_byte = self.read_cycle(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        Ok((high_byte << 8) | low_byte)
+    }
+
+    // `new_sp` is None when the push or pop would wrap SP
+    fn check_stack(&self, new_sp: Option<u16>) -> Result<(), CpuError> {
+        if self.fault_on_stack_wrap && new_sp.is_none() {
+            return Err(CpuError::StackFault {
+                sp: self.sp,
+                address: self.pc,
+            });
         }
-        self.sp = self.sp.wrapping_sub(2);
-        self.bus.write_byte(self.sp, (value >> 8) as u8);
-        self.bus.write_byte(self.sp.wrapping_add(1), value as u8);
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    /// Returns whether LD B,B was executed since the last call.
+    pub fn take_breakpoint(&mut self) -> bool {
+        std::mem::take(&mut self.breakpoint_hit)
     }
 
-    pub fn pop(&mut self) -> u16 {
-        if self.sp > 0xFFFD {
-            panic!("Stack underflow");
+    /// The registers and the next four bytes at PC in the format Gameboy Doctor and most
+    /// emulators' trace logs use, so traces can be diffed line by line.
+    pub fn trace_line(&self) -> String {
+        let pc = self.pc;
+        let byte = |offset: u16| self.bus.peek_byte(pc.wrapping_add(offset));
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.registers.a,
+            u8::from(self.registers.f),
+            self.registers.b,
+            self.registers.c,
+            self.registers.d,
+            self.registers.e,
+            self.registers.h,
+            self.registers.l,
+            self.sp,
+            pc,
+            byte(0),
+            byte(1),
+            byte(2),
+            byte(3)
+        )
+    }
+
+    pub fn flush_trace(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.flush() {
+                log::error!("Failed to write the trace: {}", e);
+            }
         }
-        let value = (self.bus.read_byte(self.sp) as u16) << 8
-            | self.bus.read_byte(self.sp.wrapping_add(1)) as u16;
-        value
     }
 
     fn _jump(&mut self, address: u16) {
         self.pc = address;
     }
 
-    pub fn step(&mut self) {
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        self.cycle_count = 0;
+        if self.locked_up {
+            // Nothing but a reset gets the CPU out of here, not even interrupts
+            self.tick(1);
+            return Ok(());
+        }
+
+        let pending = self.pending_interrupts();
+        if pending != 0 {
+            // Any pending interrupt wakes the CPU up, even when it won't be serviced
+            self.is_halted = false;
+            if self.registers.ime {
+                self.service_interrupt();
+                return Ok(());
+            }
+        }
+
         if self.is_halted {
-            return;
+            // The clock keeps running while halted, but nothing can wake the CPU up before
+            // the next event
+            self.tick(self.bus.cycles_until_event());
+            return Ok(());
         }
-        let mut instruction_byte = self.bus.read_byte(self.pc);
-        let prefixed = instruction_byte == 0xCB;
-        if prefixed {
-            instruction_byte = self.bus.read_byte(self.pc.wrapping_add(1));
+        if self.trace.is_some() {
+            let mut line = self.trace_line();
+            if let Some(symbols) = &self.trace_symbols {
+                let location = Location::new(self.bus.rom_bank_at(self.pc), self.pc);
+                if let Some(name) = symbols.describe(location) {
+                    line.push_str(&format!(" ; {}", name));
+                }
+            }
+            if let Err(e) = writeln!(self.trace.as_mut().unwrap(), "{}", line) {
+                log::error!("Failed to write the trace, stopping it: {}", e);
+                self.trace = None;
+            }
+        }
+        // Too late for this instruction to be interrupted, so EI; DI never lets one through
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.registers.ime = true;
+        }
+        let pc = self.pc;
+        // Every CB-prefixed byte is defined, so only unprefixed opcodes can be illegal
+        let Some(instruction) =
+            Instruction::decode(|offset| self.read_cycle(pc.wrapping_add(offset)))
+        else {
+            let opcode = self.bus.peek_byte(pc);
+            if self.lock_up_on_illegal_opcode {
+                log::warn!("Illegal opcode {:#04x} at {:#06x}, locking up", opcode, pc);
+                self.locked_up = true;
+                return Ok(());
+            }
+            return Err(CpuError::IllegalOpcode {
+                opcode,
+                address: pc,
+            });
+        };
+        if let Instruction::LD(
+            Target::Register(ArithmeticTarget::B),
+            Target::Register(ArithmeticTarget::B),
+        ) = instruction
+        {
+            // LD B,B does nothing, which makes it a handy breakpoint for test ROMs
+            self.breakpoint_hit = true;
+        }
+        if self.history.is_enabled() {
+            self.history.record(Executed {
+                bank: self.bus.rom_bank_at(pc),
+                decoded: Decoded {
+                    address: pc,
+                    opcode: self.bus.peek_byte(pc),
+                    instruction: Some(instruction),
+                    length: instruction.length(),
+                },
+                registers: self.registers,
+                sp: self.sp,
+            });
         }
-        let next_pc: u16 =
-            if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
-                self.execute(instruction)
-            } else {
-                let description = format!(
-                    "0x{}{:x}",
-                    if prefixed { "cb" } else { "" },
-                    instruction_byte
-                );
-                panic!("Unkown instruction found for: {}", description);
-            };
 
-        self.pc = next_pc;
+        self.pc = self.execute_fetched(instruction)?;
+        Ok(())
     }
 
-    pub fn execute(&mut self, instruction: Instruction) -> u16 {
+    pub fn is_locked_up(&self) -> bool {
+        self.locked_up
+    }
+
+    /// Executes `instruction` as if it had just been fetched from PC, taking all of its
+    /// cycles. Returns the new PC.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<u16, CpuError> {
+        self.cycle_count = 0;
+        self.execute_fetched(instruction)
+    }
+
+    // The fetch cycles are already counted in `cycle_count`
+    fn execute_fetched(&mut self, instruction: Instruction) -> Result<u16, CpuError> {
         let pc = self.pc;
+        // Conditional instructions don't change the flags they depend on, so the cycle count
+        // can be looked up before executing
+        let cycles = self
+            .get_instruction_cycles(&instruction)
+            .ok_or(CpuError::InvalidOperand {
+                instruction,
+                address: pc,
+            })?;
+        // Jumps, calls and returns overwrite this, every other instruction falls through
+        let mut next_pc = pc.wrapping_add(instruction.length());
         match instruction {
             Instruction::ADD(target, source) => {
                 let value: u16 = self.get_register_value(source);
-                match target {
-                    Target::Register(_) => {
+                match (target, source) {
+                    (Target::Register(_), _) => {
                         let value = value as u8;
                         let temp = self.get_register_value(target) as u8;
                         let (new_value, did_overflow) = temp.overflowing_add(value);
                         self.registers.f.zero = new_value == 0;
                         self.registers.f.subtract = false;
                         self.registers.f.carry = did_overflow;
-                        self.registers.f.half_carry =
-                            ((self.registers.a & 0xF) + (value & 0xF)) & 0x10 == 0x10;
+                        self.registers.f.half_carry = (temp & 0xF) + (value & 0xF) > 0xF;
                         self.set_register_value(new_value as u16, target);
                     }
-                    Target::Register16(_) => {
+                    (Target::Register16(DoubleTarget::SP), Target::Const8(_)) => {
+                        // ADD SP,e8 - signed offset, flags come from the low byte
+                        let offset = value as u8 as i8 as u16;
+                        self.registers.f.zero = false;
+                        self.registers.f.subtract = false;
+                        self.registers.f.half_carry = (self.sp & 0xF) + (offset & 0xF) > 0xF;
+                        self.registers.f.carry = (self.sp & 0xFF) + (offset & 0xFF) > 0xFF;
+                        self.sp = self.sp.wrapping_add(offset);
+                    }
+                    (Target::Register16(_), _) => {
                         let val = self.get_register_value(target);
                         let (new_value, did_overflow) = val.overflowing_add(value);
                         self.registers.f.subtract = false;
@@ -587,56 +724,20 @@ impl CPU {
                         self.set_register_value(new_value, target);
                     }
                     _ => {
-                        panic!("Invalid source for ADD instruction: {:?}", source);
+                        unreachable!("Operands are validated by get_instruction_cycles");
                     }
                 }
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::ADC(target) => {
-                let value = self.get_register_value(target);
-                match target {
-                    Target::Const8() => {
-                        let value = self.bus.read_byte(self.pc.wrapping_add(1));
-                        let carry = if self.registers.f.carry { 1 } else { 0 };
-                        let (new_value, did_overflow) =
-                            self.registers.a.overflowing_add(value + carry);
-                        self.registers.f.zero = new_value == 0;
-                        self.registers.f.subtract = false;
-                        self.registers.f.carry = did_overflow;
-                        self.registers.f.half_carry =
-                            (self.registers.a & 0xF) + (value & 0xF) + carry > 0xF;
-                        self.registers.a = new_value.wrapping_add(if did_overflow { 1 } else { 0 });
-                        self.pc = self.pc.wrapping_add(1);
-                    }
-                    Target::Register(_) => {
-                        let carry = if self.registers.f.carry { 1 } else { 0 };
-                        let (new_value, did_overflow) =
-                            self.registers.a.overflowing_add(value as u8 + carry);
-                        self.registers.f.zero = new_value == 0;
-                        self.registers.f.subtract = false;
-                        self.registers.f.carry = did_overflow;
-                        self.registers.f.half_carry =
-                            (self.registers.a & 0xF) + (value as u8 & 0xF) + carry > 0xF;
-                        self.registers.a = new_value.wrapping_add(if did_overflow { 1 } else { 0 });
-                    }
-                    Target::MemoryR16(_) => {
-                        let hl_value = self.registers.get_hl();
-                        let value = self.bus.read_byte(hl_value);
-                        let carry = if self.registers.f.carry { 1 } else { 0 };
-                        let (result, carry1) = self.registers.a.overflowing_add(value);
-                        let (result, carry2) = result.overflowing_add(carry);
-                        self.registers.f.zero = result == 0;
-                        self.registers.f.subtract = false;
-                        self.registers.f.carry = carry1 || carry2;
-                        self.registers.f.half_carry =
-                            (self.registers.a & 0xF) + (value & 0xF) + carry > 0xF;
-                        self.registers.a = result;
-                    }
-                    _ => {
-                        panic!("Invalid target for ADC instruction: {:?}", target);
-                    }
-                }
-                self.pc = self.pc.wrapping_add(1);
+                let value = self.get_register_value(target) as u8;
+                let carry = if self.registers.f.carry { 1 } else { 0 };
+                let result = self.registers.a as u16 + value as u16 + carry as u16;
+                self.registers.f.zero = result as u8 == 0;
+                self.registers.f.subtract = false;
+                self.registers.f.carry = result > 0xFF;
+                self.registers.f.half_carry =
+                    (self.registers.a & 0xF) + (value & 0xF) + carry > 0xF;
+                self.registers.a = result as u8;
             }
             Instruction::SUB(target) => {
                 let value = self.get_register_value(target);
@@ -646,19 +747,17 @@ impl CPU {
                 self.registers.f.subtract = true;
                 self.registers.f.half_carry = (self.registers.a & 0xF) < (value as u8 & 0xF);
                 self.registers.a = new_value;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::SBC(target, source) => {
-                let val = self.get_register_value(source);
+                let val = self.get_register_value(source) as u8;
                 let temp = self.get_register_value(target) as u8;
                 let carry = if self.registers.f.carry { 1 } else { 0 };
-                let (new_value, did_overflow) = temp.overflowing_sub(val as u8 + carry);
+                let new_value = temp.wrapping_sub(val).wrapping_sub(carry);
                 self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = true;
-                self.registers.f.carry = did_overflow;
-                self.registers.f.half_carry = (self.registers.a & 0xF) < (val as u8 & 0xF) + carry;
+                self.registers.f.carry = (temp as u16) < val as u16 + carry as u16;
+                self.registers.f.half_carry = (temp & 0xF) < (val & 0xF) + carry;
                 self.registers.a = new_value;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::AND(target, source) => {
                 let value = self.get_register_value(source) as u8;
@@ -669,7 +768,6 @@ impl CPU {
                 self.registers.f.half_carry = true;
                 self.registers.f.carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::OR(target, source) => {
                 let value = self.get_register_value(source) as u8;
@@ -680,7 +778,6 @@ impl CPU {
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::XOR(target, source) => {
                 let value = self.get_register_value(source) as u8;
@@ -691,7 +788,6 @@ impl CPU {
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::CP(target) => {
                 let value = self.get_register_value(target) as u8;
@@ -700,37 +796,41 @@ impl CPU {
                 self.registers.f.subtract = true;
                 self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
                 self.registers.f.carry = did_overflow;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::INC(target) => {
                 let value = self.get_register_value(target);
-                let (new_value, _did_overflow) = value.overflowing_add(1);
-                self.registers.f.zero = new_value == 0;
-                self.registers.f.subtract = false;
-                self.registers.f.half_carry = (value & 0xF) == 0xF;
-                self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(1);
+                if let Target::Register16(_) = target {
+                    // 16-bit increments leave the flags alone
+                    self.set_register_value(value.wrapping_add(1), target);
+                } else {
+                    let new_value = (value as u8).wrapping_add(1);
+                    self.registers.f.zero = new_value == 0;
+                    self.registers.f.subtract = false;
+                    self.registers.f.half_carry = (value & 0xF) == 0xF;
+                    self.set_register_value(new_value as u16, target);
+                }
             }
             Instruction::DEC(target) => {
                 let value = self.get_register_value(target);
-                let (new_value, _did_overflow) = value.overflowing_sub(1);
-                self.registers.f.zero = new_value == 0;
-                self.registers.f.subtract = true;
-                self.registers.f.half_carry = (value & 0xF) == 0xF;
-                self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(1);
+                if let Target::Register16(_) = target {
+                    self.set_register_value(value.wrapping_sub(1), target);
+                } else {
+                    let new_value = (value as u8).wrapping_sub(1);
+                    self.registers.f.zero = new_value == 0;
+                    self.registers.f.subtract = true;
+                    self.registers.f.half_carry = (value & 0xF) == 0x0;
+                    self.set_register_value(new_value as u16, target);
+                }
             }
             Instruction::CCF() => {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = !self.registers.f.carry;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::SCF() => {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = true;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::RRA() => {
                 let carry_in = if self.registers.f.carry { 0x80 } else { 0x00 };
@@ -740,7 +840,6 @@ impl CPU {
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::RLA() => {
                 let carry_in = if self.registers.f.carry { 1 } else { 0 };
@@ -750,7 +849,6 @@ impl CPU {
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::RLCA() => {
                 let carry_out = self.registers.a & 0x80 == 0x80;
@@ -759,7 +857,6 @@ impl CPU {
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::RRCA() => {
                 self.registers.f.carry = self.registers.a & 0x1 == 0x1;
@@ -767,28 +864,21 @@ impl CPU {
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::CPL() => {
                 self.registers.a = !self.registers.a;
                 self.registers.f.subtract = true;
                 self.registers.f.half_carry = true;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::BIT(bit, target) => {
                 let value = self.get_register_value(target) as u8;
                 self.registers.f.zero = (value & (1 << bit)) == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = true;
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::SET(offset, target) => {
                 let value: u16 = self.get_register_value(target) | (1 << offset);
-                self.set_register_value(value as u16, target);
-                self.registers.f.zero = false;
-                self.registers.f.subtract = false;
-                self.registers.f.half_carry = false;
-                self.pc = self.pc.wrapping_add(2);
+                self.set_register_value(value, target);
             }
             Instruction::SRL(target) => {
                 let value = self.get_register_value(target);
@@ -798,29 +888,26 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.set_register_value(new_value, target);
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::RR(target) => {
                 let value = self.get_register_value(target);
                 let carry = self.registers.f.carry;
                 self.registers.f.carry = value & 0x1 == 0x1;
                 let new_value = (value >> 1) | (if carry { 0x80 } else { 0x00 });
-                self.registers.f.zero = new_value == 0;
+                self.registers.f.zero = new_value as u8 == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
-                self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
+                self.set_register_value(new_value, target);
             }
             Instruction::RL(target) => {
                 let value = self.get_register_value(target);
                 let carry = self.registers.f.carry;
                 self.registers.f.carry = value & 0x80 == 0x80;
                 let new_value = (value << 1) | (if carry { 0x1 } else { 0x0 });
-                self.registers.f.zero = new_value == 0;
+                self.registers.f.zero = new_value as u8 == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
-                self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
+                self.set_register_value(new_value, target);
             }
             Instruction::RRC(target) => {
                 let value = self.get_register_value(target) as u8;
@@ -830,7 +917,6 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::RLC(target) => {
                 let value = self.get_register_value(target) as u8;
@@ -840,7 +926,6 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::SRA(target) => {
                 let value = self.get_register_value(target) as u8;
@@ -850,7 +935,6 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::SLA(target) => {
                 let value = self.get_register_value(target) as u8;
@@ -860,7 +944,6 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::SWAP(target) => {
                 let value = self.get_register_value(target) as u8;
@@ -870,19 +953,20 @@ impl CPU {
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
                 self.set_register_value(new_value as u16, target);
-                self.pc = self.pc.wrapping_add(2);
+            }
+            Instruction::LD(Target::MemoryConst16(address), Target::Register16(double_target)) => {
+                // LD (a16),SP stores both bytes of the stack pointer
+                let value = self.get_register_value(Target::Register16(double_target));
+                self.write_cycle(address, value as u8);
+                self.write_cycle(address.wrapping_add(1), (value >> 8) as u8);
             }
             Instruction::LD(target, source) => {
                 let value: u16 = self.get_register_value(source);
                 self.set_register_value(value, target);
-                self.pc = self.pc.wrapping_add(1);
-            }
-            Instruction::NOP() => {
-                self.pc = self.pc.wrapping_add(1);
             }
+            Instruction::NOP() => {}
             Instruction::STOP() => {
-                self.pc = self.pc.wrapping_add(2);
-                unimplemented!("STOP instruction not implemented yet");
+                // There is no speed switch or low power mode on the DMG to emulate
             }
             Instruction::DAA() => {
                 let mut adjust = 0;
@@ -909,187 +993,128 @@ impl CPU {
                 self.registers.f.half_carry = false;
                 self.registers.f.zero = self.registers.a == 0;
                 self.registers.f.carry = carry;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::DI() => {
                 self.registers.ime = false;
-                self.pc = self.pc.wrapping_add(1);
+                self.ime_pending = false;
             }
             Instruction::EI() => {
-                self.registers.ime = true;
-                self.pc = self.pc.wrapping_add(1);
+                self.ime_pending = true;
             }
             Instruction::JP(condition, address) => {
-                //address = 0 outside tests
-                let target_address = self.bus.read_byte(self.pc.wrapping_add(1)) as u16
-                    | ((self.bus.read_byte(self.pc.wrapping_add(2)) as u16) << 8);
-
-                let jump = self.get_jcondition_value(condition);
-
-                if jump {
-                    if address != 0 {
-                        self.pc = address;
-                    } else {
-                        self.pc = target_address;
-                    }
-                } else {
-                    return self.pc.wrapping_add(3); // Skip over the instruction (1 byte) and operand (2 bytes)
+                if self.get_jcondition_value(condition) {
+                    next_pc = address;
                 }
             }
             Instruction::JPHL(target) => {
-                let target_address = self.get_register_value(target);
-                self.pc = target_address;
+                next_pc = self.get_register_value(target);
             }
-            Instruction::JR(condition) => {
-                let jump = self.get_jcondition_value(condition);
-                if jump {
-                    let offset = self.bus.read_byte(self.pc.wrapping_add(1)) as i8;
-                    self.pc = self.pc.wrapping_add(2).wrapping_add(offset as u16);
-                } else {
-                    self.pc = self.pc.wrapping_add(2);
+            Instruction::JR(condition, offset) => {
+                if self.get_jcondition_value(condition) {
+                    next_pc = next_pc.wrapping_add(offset as u16);
                 }
             }
             Instruction::PUSH(target) => {
                 let value = self.get_register_value(target);
-                self.sp = self.sp.wrapping_sub(2);
-                self.bus.write_byte(self.sp, (value >> 8) as u8);
-                self.bus.write_byte(self.sp.wrapping_add(1), value as u8);
-                self.pc = self.pc.wrapping_add(1);
+                self.push(value)?;
             }
             Instruction::POP(target) => {
-                let value = (self.bus.read_byte(self.sp) as u16) << 8
-                    | self.bus.read_byte(self.sp.wrapping_add(1)) as u16;
+                let value = self.pop()?;
                 self.set_register_value(value, target);
-                self.sp = self.sp.wrapping_add(2);
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::CALL(condition, address) => {
-                let jump = self.get_jcondition_value(condition);
-                let target_address = self.bus.read_byte(self.pc.wrapping_add(1)) as u16
-                    | ((self.bus.read_byte(self.pc.wrapping_add(2)) as u16) << 8);
-                if jump {
-                    self.push(self.pc.wrapping_add(3));
-                    self.pc = if address != 0 {
-                        address
-                    } else {
-                        target_address
-                    };
-                } else {
-                    return self.pc.wrapping_add(3);
+                if self.get_jcondition_value(condition) {
+                    self.push(next_pc)?;
+                    next_pc = address;
                 }
             }
             Instruction::RET(condition) => {
-                let jump = self.get_jcondition_value(condition);
-                if jump {
-                    self.pc = self.pop();
-                } else {
-                    return self.pc.wrapping_add(1);
+                if !matches!(condition, JumpCondition::Always) {
+                    // The condition is checked in a cycle of its own
+                    self.tick(1);
+                }
+                if self.get_jcondition_value(condition) {
+                    next_pc = self.pop()?;
                 }
             }
             Instruction::RETI(condition) => {
-                let jump = self.get_jcondition_value(condition);
-                if jump {
-                    self.pc = self.pop();
-                } else {
-                    return self.pc.wrapping_add(1);
+                if self.get_jcondition_value(condition) {
+                    next_pc = self.pop()?;
+                    self.registers.ime = true;
                 }
-                self.registers.ime = true;
+            }
+            Instruction::RST(vector) => {
+                self.push(next_pc)?;
+                next_pc = vector as u16;
             }
             Instruction::HALT() => {
                 self.is_halted = true;
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::LDI(target, source) => {
                 let value = self.get_register_value(source);
                 self.set_register_value(value, target);
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_add(1));
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::LDD(target, source) => {
                 let value = self.get_register_value(source);
                 self.set_register_value(value, target);
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_sub(1));
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::LDH(target, source) => {
                 let value: u8 = match source {
-                    LDHRegister::C => self.bus.read_byte(0xFF00 + self.registers.c as u16),
-                    LDHRegister::MemoryConst16(_) => {
-                        self.pc = self.pc.wrapping_add(1);
-                        self.get_register_value(Target::MemoryConst16()) as u8
-                    }
+                    LDHRegister::C => self.read_cycle(0xFF00 + self.registers.c as u16),
                     LDHRegister::ArithmeticTarget => {
                         self.get_register_value(Target::Register(ArithmeticTarget::A)) as u8
                     }
-                    LDHRegister::MemA8 => {
-                        let address = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
-                        self.pc = self.pc.wrapping_add(1);
-                        self.bus.read_byte(0xFF00 + address)
-                    }
+                    LDHRegister::MemA8(offset) => self.read_cycle(0xFF00 + offset as u16),
                 };
                 match target {
                     LDHRegister::ArithmeticTarget => {
                         self.registers.a = value;
                     }
                     LDHRegister::C => {
-                        self.bus.write_byte(0xFF00 + self.registers.c as u16, value);
+                        self.write_cycle(0xFF00 + self.registers.c as u16, value);
                     }
-                    LDHRegister::MemoryConst16(addr) => {
-                        self.pc = self.pc.wrapping_add(1);
-                        self.bus.write_byte(addr as u16, value);
-                    }
-                    LDHRegister::MemA8 => {
-                        let address = self.bus.read_byte(self.pc.wrapping_add(1)) as u16;
-                        self.pc = self.pc.wrapping_add(1);
-                        self.bus.write_byte(0xFF00 + address, value);
+                    LDHRegister::MemA8(offset) => {
+                        self.write_cycle(0xFF00 + offset as u16, value);
                     }
                 }
-                self.pc = self.pc.wrapping_add(1);
             }
-            Instruction::LDHLSP() => {
+            Instruction::LDHLSP(offset) => {
                 // LD HL,SP+e8
                 let sp = self.sp;
-                let offset = self.bus.read_byte(self.pc.wrapping_add(1) as u16) as i8;
                 let result = self.sp.wrapping_add(offset as u16);
                 self.registers.set_hl(result);
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = (sp & 0xF) + ((offset as u16) & 0xF) > 0xF;
                 self.registers.f.carry = (sp & 0xFF) + ((offset as u16) & 0xFF) > 0xFF;
-                self.pc = self.pc.wrapping_add(2);
             }
             Instruction::PUSHAF() => {
+                self.check_stack(self.sp.checked_sub(2))?;
+                self.tick(1);
                 self.sp = self.sp.wrapping_sub(1);
-                self.bus.write_byte(self.sp, self.registers.a);
+                self.write_cycle(self.sp, self.registers.a);
 
                 self.sp = self.sp.wrapping_sub(1);
-                let f_value = (self.registers.f.zero as u8) << 7
-                    | (self.registers.f.subtract as u8) << 6
-                    | (self.registers.f.half_carry as u8) << 5
-                    | (self.registers.f.carry as u8) << 4;
-                self.bus.write_byte(self.sp, f_value);
-
-                self.pc = self.pc.wrapping_add(1);
+                self.write_cycle(self.sp, u8::from(self.registers.f));
             }
             Instruction::POPAF() => {
-                self.registers.f.carry = self.bus.read_byte(self.sp) & 0x10 == 0x10;
-                self.registers.f.half_carry = self.bus.read_byte(self.sp) & 0x20 == 0x20;
-                self.registers.f.subtract = self.bus.read_byte(self.sp) & 0x40 == 0x40;
-                self.registers.f.zero = self.bus.read_byte(self.sp) & 0x80 == 0x80;
+                self.check_stack(self.sp.checked_add(2))?;
+                // The low nibble of F always reads as zero
+                let flags = self.read_cycle(self.sp);
+                self.registers.f = FlagsRegister::from(flags);
                 self.sp = self.sp.wrapping_add(1);
 
-                self.registers.a = self.bus.read_byte(self.sp);
+                self.registers.a = self.read_cycle(self.sp);
                 self.sp = self.sp.wrapping_add(1);
-
-                self.pc = self.pc.wrapping_add(1);
             }
             Instruction::RES(bit, target) => {
                 let value = self.get_register_value(target);
                 let new_value = value & !(1 << bit);
                 self.set_register_value(new_value, target);
-                self.pc = self.pc.wrapping_add(2);
             }
         }
 
@@ -1105,13 +1130,69 @@ impl CPU {
                 self.registers.f.half_carry,
                 self.registers.f.carry,
                 &instruction,
-                self.bus.read_byte(0xFF44)
+                self.bus.peek_byte(0xFF44)
             );
         }
 
-        let cycles = self.get_instruction_cycles(&instruction);
-        self.cycle_count = cycles;
-        self.bus.gpu.step(self.cycle_count);
-        self.pc
+        // Internal cycles that weren't spent on memory accesses
+        if self.cycle_count < cycles {
+            self.tick(cycles - self.cycle_count);
+        }
+        self.pc = next_pc;
+        Ok(next_pc)
+    }
+
+    // The CPU polls IE and IF internally, which isn't a memory access a watchpoint should see
+    fn pending_interrupts(&self) -> u8 {
+        self.bus.peek_byte(INTERRUPT_ENABLE_ADDRESS)
+            & self.bus.peek_byte(INTERRUPT_FLAG_ADDRESS)
+            & 0x1F
+    }
+
+    // Dispatch takes 5 M-cycles: two wait cycles, pushing PC and setting it to the vector
+    fn service_interrupt(&mut self) {
+        self.registers.ime = false;
+        self.tick(2);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (self.pc >> 8) as u8);
+
+        // The interrupt is only picked after the high byte has been pushed. If that write
+        // went to IE and disabled it, dispatch is cancelled and jumps to 0x0000 instead.
+        let pending = self.pending_interrupts();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, self.pc as u8);
+        self.pc = if pending == 0 {
+            0x0000
+        } else {
+            // The lowest bit has the highest priority
+            let bit = pending.trailing_zeros() as u16;
+            let flags = self.bus.peek_byte(INTERRUPT_FLAG_ADDRESS);
+            self.bus
+                .poke_byte(INTERRUPT_FLAG_ADDRESS, flags & !(1 << bit));
+            0x40 + bit * 8
+        };
+        self.tick(1);
+    }
+
+    // Memory accesses take one M-cycle each. The rest of the system is advanced first, so
+    // it sees the access at the end of the cycle like on hardware.
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.tick(1);
+        if is_peripheral(address) {
+            self.bus.sync();
+        }
+        self.bus.read_byte(address)
+    }
+
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.tick(1);
+        self.bus.write_byte(address, value);
+    }
+
+    // Advances everything but the CPU by `cycles` M-cycles and counts them for this step
+    fn tick(&mut self, cycles: u16) {
+        self.cycle_count += cycles;
+        // There are no peripherals on a flat bus, but time still passes for the access log
+        self.bus.advance(cycles);
     }
 }
@@ -0,0 +1,685 @@
+use crate::asm;
+use crate::cpu::{CpuError, CPU, CYCLES_PER_FRAME};
+use crate::disasm;
+use crate::history::Executed;
+use crate::instructions::Instruction;
+use crate::registers::FlagsRegister;
+use crate::symbols::{Location, Symbols};
+use crate::watchpoint::Watchpoint;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+break [LOC]         b   Set a breakpoint, or list them without a location
+delete [LOC]            Remove a breakpoint, or all of them
+step [N]            s   Execute N instructions (1 by default)
+next                n   Step over a CALL or RST
+finish              f   Run until the current function returns
+continue            c   Run until a breakpoint
+watch [RANGE] [V]       Break on writes to RANGE (ADDR or ADDR-END), only of V if given,
+                        or list the watchpoints without a range
+rwatch RANGE [V]        Break on reads from RANGE
+awatch RANGE [V]        Break on reads from and writes to RANGE
+unwatch [N]             Remove watchpoint N, or all of them
+regs                r   Show the registers and flags
+mem ADDR [LEN]      x   Dump LEN bytes of memory (64 by default)
+write ADDR BYTE...  w   Write bytes to memory
+asm ADDR INSTR      a   Assemble INSTR (e.g. LD A,$05) at ADDR, patching ROM if needed
+set REG VALUE           Set a register (a-l, af-hl, sp, pc, ime)
+disasm [ADDR] [N]   l   Disassemble N instructions (around PC by default)
+history [N]             Show the last N executed instructions, oldest first
+backtrace           bt  Show the calls and interrupts that led to PC
+quit                q   Exit the emulator
+Numbers are hexadecimal, except that asm operands need a $ like in listings.
+A LOC is an ADDR, BANK:ADDR or a symbol from the ROM's .sym file, e.g. Main.loop.
+An empty line repeats the last command.
+";
+
+// Opcodes that can return from a function, used by `finish`
+const RETURN_OPCODES: [u8; 6] = [0xC0, 0xC8, 0xC9, 0xD0, 0xD8, 0xD9];
+const INTERRUPT_VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+
+const DEFAULT_DUMP_LENGTH: u16 = 64;
+const DEFAULT_LISTING_LENGTH: u16 = 8;
+// How many instructions before PC a listing tries to show
+const LISTING_CONTEXT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    Stepping(u32),
+    // Stepping over a call, it returns to `address` with the stack back at `sp`
+    Next { address: u16, sp: u16 },
+    // Running until a return pops the stack above `sp`
+    Finish { sp: u16 },
+}
+
+// Ordered by address first so the ones at an address can be found with a range
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Breakpoint {
+    address: u16,
+    // None stops whatever bank is mapped
+    bank: Option<usize>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{}", Location::new(bank, self.address)),
+            None => write!(f, "{:04X}", self.address),
+        }
+    }
+}
+
+// A CALL, RST or interrupt that hasn't returned yet
+struct Frame {
+    call_site: Location,
+    // SP right after the return address was pushed, the frame is gone once SP is above it
+    sp: u16,
+    interrupt: bool,
+}
+
+/// Interactive debugger driving the CPU one instruction at a time.
+pub struct Debugger {
+    breakpoints: BTreeSet<Breakpoint>,
+    mode: Mode,
+    quit: bool,
+    last_command: String,
+    frame_cycles: u32,
+    // Why execution paused, shown at the next prompt
+    report: Option<String>,
+    symbols: Symbols,
+    frames: Vec<Frame>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+fn parse_number(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid number: {}", value))
+}
+
+fn parse_byte(value: &str) -> Result<u8, String> {
+    u8::try_from(parse_number(value)?).map_err(|_| format!("Not a byte: {}", value))
+}
+
+fn flags_string(flags: FlagsRegister) -> String {
+    [
+        (flags.zero, 'Z'),
+        (flags.subtract, 'N'),
+        (flags.half_carry, 'H'),
+        (flags.carry, 'C'),
+    ]
+    .iter()
+    .map(|&(set, letter)| if set { letter } else { '-' })
+    .collect()
+}
+
+/// Decodes the instruction at `address`, returning its text and length.
+pub fn disassemble(cpu: &CPU, address: u16) -> (String, u16) {
+    disassemble_with_symbols(cpu, address, &Symbols::default())
+}
+
+/// Like `disassemble`, with jump targets named after `symbols` in the banks mapped now.
+pub fn disassemble_with_symbols(cpu: &CPU, address: u16, symbols: &Symbols) -> (String, u16) {
+    let bytes: Vec<u8> = (0..3)
+        .map(|offset| cpu.bus.peek_byte(address.wrapping_add(offset)))
+        .collect();
+    let decoded = disasm::decode(&bytes, address);
+    let hex: Vec<String> = bytes[..decoded.length as usize]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let text = decoded.text(|target| match symbols.name_at(location(cpu, target)) {
+        Some(name) => name.to_string(),
+        None => format!("${:04X}", target),
+    });
+    (
+        format!("{:04X}: {:<9} {}", address, hex.join(" "), text),
+        decoded.length,
+    )
+}
+
+// Where the CPU sees `address` right now
+fn location(cpu: &CPU, address: u16) -> Location {
+    Location::new(cpu.bus.rom_bank_at(address), address)
+}
+
+impl Debugger {
+    /// Creates a debugger that is paused, so the prompt comes up before anything runs.
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Paused,
+            quit: false,
+            last_command: String::new(),
+            frame_cycles: 0,
+            report: None,
+            symbols: Symbols::default(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(Breakpoint {
+            address,
+            bank: None,
+        });
+    }
+
+    /// Sets a breakpoint at a location written like in the `break` command.
+    pub fn add_breakpoint_at(&mut self, location: &str) -> Result<(), String> {
+        let breakpoint = self.parse_location(location)?;
+        self.breakpoints.insert(breakpoint);
+        Ok(())
+    }
+
+    /// Names used for locations, in listings and in backtraces.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    // Symbols win over plain numbers, a label can be spelled like one (e.g. Cafe), but
+    // prefixed numbers are always numbers
+    fn parse_location(&self, text: &str) -> Result<Breakpoint, String> {
+        if let Some(location) = self.symbols.get(text) {
+            let bank = (0x4000..0x8000)
+                .contains(&location.address())
+                .then_some(location.bank());
+            return Ok(Breakpoint {
+                address: location.address(),
+                bank,
+            });
+        }
+        if let Some((bank, address)) = text.split_once(':') {
+            let bank =
+                usize::from_str_radix(bank, 16).map_err(|_| format!("Invalid bank: {}", bank))?;
+            return Ok(Breakpoint {
+                address: parse_number(address)?,
+                bank: Some(bank),
+            });
+        }
+        parse_number(text)
+            .map(|address| Breakpoint {
+                address,
+                bank: None,
+            })
+            .map_err(|_| format!("Not an address or a known symbol: {}", text))
+    }
+
+    fn has_breakpoint(&self, cpu: &CPU, address: u16) -> bool {
+        let first = Breakpoint {
+            address,
+            bank: None,
+        };
+        let last = Breakpoint {
+            address,
+            bank: Some(usize::MAX),
+        };
+        self.breakpoints
+            .range(first..=last)
+            .any(|breakpoint| match breakpoint.bank {
+                Some(bank) => location(cpu, address) == Location::new(bank, address),
+                None => true,
+            })
+    }
+
+    fn disassemble(&self, cpu: &CPU, address: u16) -> (String, u16) {
+        disassemble_with_symbols(cpu, address, &self.symbols)
+    }
+
+    // Keeps a shadow call stack, so a backtrace doesn't have to guess from the stack contents
+    fn track_calls(&mut self, cpu: &CPU, pc: Location, sp: u16, instruction: Option<Instruction>) {
+        while self.frames.last().is_some_and(|frame| cpu.sp > frame.sp) {
+            self.frames.pop();
+        }
+        if cpu.sp != sp.wrapping_sub(2) {
+            return;
+        }
+        let call = match instruction {
+            Some(Instruction::CALL(_, target)) => cpu.pc == target,
+            Some(Instruction::RST(vector)) => cpu.pc == vector as u16,
+            _ => false,
+        };
+        // Dispatching an interrupt pushes PC before the instruction there gets to run
+        let interrupt = !call && INTERRUPT_VECTORS.contains(&cpu.pc);
+        if call || interrupt {
+            self.frames.push(Frame {
+                call_site: pc,
+                sp: cpu.sp,
+                interrupt,
+            });
+        }
+    }
+
+    fn backtrace(&self, cpu: &CPU) -> String {
+        let mut output = String::new();
+        let innermost = (location(cpu, cpu.pc), false);
+        let frames = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| (frame.call_site, frame.interrupt));
+        for (index, (location, interrupt)) in std::iter::once(innermost).chain(frames).enumerate() {
+            let _ = write!(output, "#{:<2} {}", index, location);
+            if let Some(name) = self.symbols.describe(location) {
+                let _ = write!(output, " {}", name);
+            }
+            if interrupt {
+                output.push_str(" (interrupted)");
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Executes one instruction, pausing when it lands on a breakpoint or completes the
+    /// current command. Errors pause too so the crash can be inspected.
+    pub fn step(&mut self, cpu: &mut CPU) -> Result<(), CpuError> {
+        let pc = cpu.pc;
+        let (sp, call_site) = (cpu.sp, location(cpu, pc));
+        let opcode = cpu.bus.peek_byte(pc);
+        let instruction = Instruction::decode(|offset| cpu.bus.peek_byte(pc.wrapping_add(offset)));
+        // Drop hits from accesses made while paused, like the write command
+        cpu.bus.take_watch_hit();
+        if let Err(e) = cpu.step() {
+            self.mode = Mode::Paused;
+            return Err(e);
+        }
+        self.track_calls(cpu, call_site, sp, instruction);
+
+        if let Some(hit) = cpu.bus.take_watch_hit() {
+            self.report = Some(format!("{} by {}", hit, self.disassemble(cpu, pc).0));
+            self.mode = Mode::Paused;
+            return Ok(());
+        }
+
+        let done = match self.mode {
+            Mode::Paused => true,
+            Mode::Running => false,
+            Mode::Stepping(count) => {
+                self.mode = Mode::Stepping(count - 1);
+                count <= 1
+            }
+            Mode::Next { address, sp } => cpu.pc == address && cpu.sp >= sp,
+            Mode::Finish { sp } => RETURN_OPCODES.contains(&opcode) && cpu.sp > sp,
+        };
+        if done || self.has_breakpoint(cpu, cpu.pc) {
+            self.mode = Mode::Paused;
+        }
+        Ok(())
+    }
+
+    /// Runs until a frame worth of cycles has passed or execution pauses. A frame cut short
+    /// by a pause is completed by the next call.
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> Result<(), CpuError> {
+        while !self.is_paused() {
+            let result = self.step(cpu);
+            self.frame_cycles += cpu.cycle_count as u32;
+            result?;
+            if self.frame_cycles >= CYCLES_PER_FRAME {
+                self.frame_cycles = 0;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and runs commands until one of them resumes execution or asks to quit.
+    pub fn prompt(
+        &mut self,
+        cpu: &mut CPU,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        if let Some(report) = self.report.take() {
+            writeln!(output, "{}", report)?;
+        }
+        writeln!(output, "{}", self.disassemble(cpu, cpu.pc).0)?;
+        while self.is_paused() && !self.quit {
+            write!(output, "(ramiel) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                self.quit = true;
+                break;
+            }
+            match self.execute(cpu, line.trim()) {
+                Ok(text) => write!(output, "{}", text)?,
+                Err(message) => writeln!(output, "{}", message)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a single command line and returns what it prints.
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let line = if line.is_empty() {
+            self.last_command.clone()
+        } else {
+            self.last_command = line.to_string();
+            line.to_string()
+        };
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+        let arguments: Vec<&str> = words.collect();
+        // Dumps peek at the IO registers, so they should be up to date
+        cpu.bus.sync();
+
+        match command {
+            "help" | "h" => Ok(HELP.to_string()),
+            "break" | "b" => match arguments.first() {
+                Some(location) => {
+                    let breakpoint = self.parse_location(location)?;
+                    self.breakpoints.insert(breakpoint);
+                    Ok(format!("Breakpoint at {}\n", breakpoint))
+                }
+                None => Ok(self
+                    .breakpoints
+                    .iter()
+                    .map(|breakpoint| {
+                        let text = self.disassemble(cpu, breakpoint.address).0;
+                        match breakpoint.bank {
+                            Some(bank) => format!("{:02X}:{}\n", bank, text),
+                            None => format!("{}\n", text),
+                        }
+                    })
+                    .collect()),
+            },
+            "delete" => match arguments.first() {
+                Some(location) => {
+                    let breakpoint = self.parse_location(location)?;
+                    if !self.breakpoints.remove(&breakpoint) {
+                        return Err(format!("No breakpoint at {}", breakpoint));
+                    }
+                    Ok(String::new())
+                }
+                None => {
+                    self.breakpoints.clear();
+                    Ok(String::new())
+                }
+            },
+            "watch" if arguments.is_empty() => Ok(cpu
+                .bus
+                .watchpoints()
+                .iter()
+                .enumerate()
+                .map(|(index, watchpoint)| format!("{}: {}\n", index, watchpoint))
+                .collect()),
+            "watch" | "rwatch" | "awatch" => {
+                let range = arguments.first().ok_or("Usage: watch ADDR[-END] [VALUE]")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+                    None => (parse_number(range)?, parse_number(range)?),
+                };
+                if end < start {
+                    return Err(format!("Empty range: {}", range));
+                }
+                let watchpoint = Watchpoint {
+                    start,
+                    end,
+                    on_read: command != "watch",
+                    on_write: command != "rwatch",
+                    value: arguments
+                        .get(1)
+                        .map(|value| parse_byte(value))
+                        .transpose()?,
+                };
+                cpu.bus.add_watchpoint(watchpoint);
+                Ok(format!(
+                    "Watchpoint {}: {}\n",
+                    cpu.bus.watchpoints().len() - 1,
+                    watchpoint
+                ))
+            }
+            "unwatch" => match arguments.first() {
+                Some(index) => {
+                    let index: usize = index
+                        .parse()
+                        .map_err(|_| format!("Invalid watchpoint: {}", index))?;
+                    cpu.bus
+                        .remove_watchpoint(index)
+                        .ok_or(format!("No watchpoint {}", index))?;
+                    Ok(String::new())
+                }
+                None => {
+                    cpu.bus.clear_watchpoints();
+                    Ok(String::new())
+                }
+            },
+            "step" | "s" => {
+                let count = match arguments.first() {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("Invalid count: {}", count))?,
+                    None => 1,
+                };
+                self.resume(cpu, Mode::Stepping(count.max(1)))
+            }
+            "next" | "n" => {
+                let pc = cpu.pc;
+                let instruction =
+                    Instruction::decode(|offset| cpu.bus.peek_byte(pc.wrapping_add(offset)));
+                let mode = match instruction {
+                    Some(instruction @ (Instruction::CALL(..) | Instruction::RST(_))) => {
+                        Mode::Next {
+                            address: pc.wrapping_add(instruction.length()),
+                            sp: cpu.sp,
+                        }
+                    }
+                    _ => Mode::Stepping(1),
+                };
+                self.resume(cpu, mode)
+            }
+            "finish" | "f" => self.resume(cpu, Mode::Finish { sp: cpu.sp }),
+            "continue" | "c" => self.resume(cpu, Mode::Running),
+            "regs" | "r" => Ok(self.registers(cpu)),
+            "mem" | "x" => {
+                let address = parse_number(arguments.first().ok_or("Usage: mem ADDR [LEN]")?)?;
+                let length = match arguments.get(1) {
+                    Some(length) => parse_number(length)?,
+                    None => DEFAULT_DUMP_LENGTH,
+                };
+                Ok(self.dump(cpu, address, length))
+            }
+            "write" | "w" => {
+                if arguments.len() < 2 {
+                    return Err("Usage: write ADDR BYTE...".to_string());
+                }
+                let address = parse_number(arguments[0])?;
+                let bytes = arguments[1..]
+                    .iter()
+                    .map(|byte| parse_byte(byte))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    cpu.bus
+                        .write_byte(address.wrapping_add(offset as u16), byte);
+                }
+                Ok(String::new())
+            }
+            "asm" | "a" => {
+                if arguments.len() < 2 {
+                    return Err("Usage: asm ADDR INSTR".to_string());
+                }
+                let address = parse_number(arguments[0])?;
+                let bytes = asm::assemble(&arguments[1..].join(" "), address)?;
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    cpu.bus.poke_byte(address.wrapping_add(offset as u16), byte);
+                }
+                Ok(format!("{}\n", self.disassemble(cpu, address).0))
+            }
+            "set" => {
+                let [register, value] = arguments[..] else {
+                    return Err("Usage: set REG VALUE".to_string());
+                };
+                self.set_register(cpu, register, parse_number(value)?)?;
+                Ok(self.registers(cpu))
+            }
+            "disasm" | "l" => {
+                let start = arguments.first().map(|address| parse_number(address));
+                let count = match arguments.get(1) {
+                    Some(count) => parse_number(count)?,
+                    None => DEFAULT_LISTING_LENGTH,
+                };
+                Ok(self.listing(cpu, start.transpose()?, count))
+            }
+            "backtrace" | "bt" => Ok(self.backtrace(cpu)),
+            "history" => {
+                let count = match arguments.first() {
+                    Some(count) => parse_number(count)? as usize,
+                    None => cpu.history.len(),
+                };
+                let show = |executed: &Executed| match self.symbols.describe(executed.location()) {
+                    Some(name) => format!("{}  {}\n", executed, name),
+                    None => format!("{}\n", executed),
+                };
+                Ok(cpu
+                    .history
+                    .iter()
+                    .skip(cpu.history.len().saturating_sub(count))
+                    .map(show)
+                    .collect())
+            }
+            "quit" | "q" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(format!("Unknown command: {} (try help)", command)),
+        }
+    }
+
+    fn resume(&mut self, cpu: &CPU, mode: Mode) -> Result<String, String> {
+        if cpu.is_locked_up() {
+            return Err("The CPU is locked up".to_string());
+        }
+        self.mode = mode;
+        Ok(String::new())
+    }
+
+    fn registers(&self, cpu: &CPU) -> String {
+        let registers = &cpu.registers;
+        format!(
+            "AF={:02X}{:02X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} [{}] IME={}{}\n",
+            registers.a,
+            u8::from(registers.f),
+            registers.get_bc(),
+            registers.get_de(),
+            registers.get_hl(),
+            cpu.sp,
+            cpu.pc,
+            flags_string(registers.f),
+            registers.ime as u8,
+            if cpu.is_halted() { " HALT" } else { "" }
+        )
+    }
+
+    fn set_register(&self, cpu: &mut CPU, register: &str, value: u16) -> Result<(), String> {
+        let registers = &mut cpu.registers;
+        let byte = || u8::try_from(value).map_err(|_| format!("Not a byte: {:X}", value));
+        match register.to_ascii_lowercase().as_str() {
+            "a" => registers.a = byte()?,
+            "b" => registers.b = byte()?,
+            "c" => registers.c = byte()?,
+            "d" => registers.d = byte()?,
+            "e" => registers.e = byte()?,
+            "f" => registers.f = FlagsRegister::from(byte()?),
+            "h" => registers.h = byte()?,
+            "l" => registers.l = byte()?,
+            "af" => {
+                registers.a = (value >> 8) as u8;
+                registers.f = FlagsRegister::from(value as u8);
+            }
+            "bc" => registers.set_bc(value),
+            "de" => registers.set_de(value),
+            "hl" => registers.set_hl(value),
+            "sp" => cpu.sp = value,
+            "pc" => cpu.pc = value,
+            "ime" => registers.ime = value != 0,
+            _ => return Err(format!("Unknown register: {}", register)),
+        }
+        Ok(())
+    }
+
+    fn dump(&self, cpu: &CPU, address: u16, length: u16) -> String {
+        let mut output = String::new();
+        let addresses: Vec<u16> = (0..length)
+            .map(|offset| address.wrapping_add(offset))
+            .collect();
+        for row in addresses.chunks(16) {
+            let bytes: Vec<u8> = row
+                .iter()
+                .map(|&address| cpu.bus.peek_byte(address))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            let _ = writeln!(output, "{:04X}: {:<47}  {}", row[0], hex.join(" "), text);
+        }
+        output
+    }
+
+    // Instructions have different lengths, so where the ones before PC start is a guess: the
+    // earliest nearby address that decodes into a sequence landing exactly on PC
+    fn listing_start(&self, cpu: &CPU) -> u16 {
+        for distance in (1..=LISTING_CONTEXT as u16 * 3).rev() {
+            let mut address = cpu.pc.wrapping_sub(distance);
+            let mut starts = Vec::new();
+            while address.wrapping_sub(cpu.pc.wrapping_sub(distance)) < distance {
+                starts.push(address);
+                address = address.wrapping_add(self.disassemble(cpu, address).1);
+            }
+            if address == cpu.pc {
+                return starts[starts.len().saturating_sub(LISTING_CONTEXT)];
+            }
+        }
+        cpu.pc
+    }
+
+    fn listing(&self, cpu: &CPU, start: Option<u16>, count: u16) -> String {
+        let mut address = start.unwrap_or_else(|| self.listing_start(cpu));
+        let mut output = String::new();
+        for _ in 0..count {
+            if let Some(name) = self.symbols.name_at(location(cpu, address)) {
+                let _ = writeln!(output, "{}:", name);
+            }
+            let (text, length) = self.disassemble(cpu, address);
+            let marker = if address == cpu.pc {
+                '>'
+            } else if self.has_breakpoint(cpu, address) {
+                '*'
+            } else {
+                ' '
+            };
+            let _ = writeln!(output, "{} {}", marker, text);
+            address = address.wrapping_add(length);
+        }
+        output
+    }
+}
@@ -0,0 +1,282 @@
+use crate::instructions::Instruction;
+use crate::registers::*;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write as _};
+
+// Operand text is laid out in the same columns as the hand-written disasm.txt
+const TAB_WIDTH: usize = 8;
+const COMMENT_COLUMN: usize = 32;
+
+/// An instruction decoded at a known address, so relative jumps can be resolved.
+#[derive(Clone, Copy, Debug)]
+pub struct Decoded {
+    pub address: u16,
+    pub opcode: u8,
+    pub instruction: Option<Instruction>,
+    pub length: u16,
+}
+
+/// Decodes the instruction at the start of `bytes`, which was read from `address`. Missing
+/// trailing bytes read as 0.
+pub fn decode(bytes: &[u8], address: u16) -> Decoded {
+    let instruction =
+        Instruction::decode(|offset| bytes.get(offset as usize).copied().unwrap_or(0));
+    Decoded {
+        address,
+        opcode: bytes.first().copied().unwrap_or(0),
+        instruction,
+        length: instruction.map_or(1, |instruction| instruction.length()),
+    }
+}
+
+fn register(target: ArithmeticTarget) -> &'static str {
+    match target {
+        ArithmeticTarget::A => "A",
+        ArithmeticTarget::B => "B",
+        ArithmeticTarget::C => "C",
+        ArithmeticTarget::D => "D",
+        ArithmeticTarget::E => "E",
+        ArithmeticTarget::H => "H",
+        ArithmeticTarget::L => "L",
+    }
+}
+
+fn register16(target: DoubleTarget) -> &'static str {
+    match target {
+        DoubleTarget::BC => "BC",
+        DoubleTarget::DE => "DE",
+        DoubleTarget::HL => "HL",
+        DoubleTarget::SP => "SP",
+    }
+}
+
+fn condition(condition: JumpCondition) -> &'static str {
+    match condition {
+        JumpCondition::Always => "",
+        JumpCondition::Zero => "Z,",
+        JumpCondition::NotZero => "NZ,",
+        JumpCondition::Carry => "C,",
+        JumpCondition::NotCarry => "NC,",
+    }
+}
+
+// Signed offsets as RGBDS writes them, e.g. -$02
+fn signed(value: i8) -> String {
+    if value < 0 {
+        format!("-${:02X}", value.unsigned_abs())
+    } else {
+        format!("${:02X}", value)
+    }
+}
+
+impl Decoded {
+    /// Where a JP, JR or CALL with an immediate target goes.
+    pub fn target(&self) -> Option<u16> {
+        match self.instruction? {
+            Instruction::JP(_, address) | Instruction::CALL(_, address) => Some(address),
+            Instruction::JR(_, offset) => Some(
+                self.address
+                    .wrapping_add(self.length)
+                    .wrapping_add(offset as u16),
+            ),
+            _ => None,
+        }
+    }
+
+    /// True when execution never continues with the next instruction.
+    pub fn ends_flow(&self) -> bool {
+        matches!(
+            self.instruction,
+            Some(
+                Instruction::JP(JumpCondition::Always, _)
+                    | Instruction::JR(JumpCondition::Always, _)
+                    | Instruction::JPHL(_)
+                    | Instruction::RET(JumpCondition::Always)
+                    | Instruction::RETI(_)
+            )
+        )
+    }
+
+    fn operand(&self, target: Target) -> String {
+        match target {
+            Target::Register(target) => register(target).to_string(),
+            Target::Register16(target) => register16(target).to_string(),
+            Target::MemoryR16(target) => format!("({})", register16(target)),
+            Target::Const8(value) => format!("${:02X}", value),
+            Target::Const16(value) => format!("${:04X}", value),
+            Target::MemoryConst16(address) => format!("(${:04X})", address),
+        }
+    }
+
+    fn high_operand(&self, operand: LDHRegister) -> String {
+        match operand {
+            LDHRegister::C => "($FF00+C)".to_string(),
+            LDHRegister::MemA8(offset) => format!("($FF00+${:02X})", offset),
+            LDHRegister::ArithmeticTarget => "A".to_string(),
+        }
+    }
+
+    /// The instruction text, with jump targets passed through `label`.
+    pub fn text(&self, label: impl Fn(u16) -> String) -> String {
+        let Some(instruction) = self.instruction else {
+            return format!(".DB ${:02X}", self.opcode);
+        };
+        let o = |target| self.operand(target);
+        match instruction {
+            Instruction::ADC(source) => format!("ADC A,{}", o(source)),
+            Instruction::ADD(Target::Register16(DoubleTarget::SP), Target::Const8(offset)) => {
+                format!("ADD SP,{}", signed(offset as i8))
+            }
+            Instruction::ADD(target, source) => format!("ADD {},{}", o(target), o(source)),
+            Instruction::SBC(_, source) => format!("SBC A,{}", o(source)),
+            Instruction::AND(_, source) => format!("AND {}", o(source)),
+            Instruction::OR(_, source) => format!("OR {}", o(source)),
+            Instruction::XOR(_, source) => format!("XOR {}", o(source)),
+            Instruction::CP(source) => format!("CP {}", o(source)),
+            Instruction::SUB(source) => format!("SUB {}", o(source)),
+            Instruction::INC(target) => format!("INC {}", o(target)),
+            Instruction::DEC(target) => format!("DEC {}", o(target)),
+            Instruction::BIT(bit, target) => format!("BIT {},{}", bit, o(target)),
+            Instruction::SET(bit, target) => format!("SET {},{}", bit, o(target)),
+            Instruction::RES(bit, target) => format!("RES {},{}", bit, o(target)),
+            Instruction::SRL(target) => format!("SRL {}", o(target)),
+            Instruction::RR(target) => format!("RR {}", o(target)),
+            Instruction::RL(target) => format!("RL {}", o(target)),
+            Instruction::RRC(target) => format!("RRC {}", o(target)),
+            Instruction::RLC(target) => format!("RLC {}", o(target)),
+            Instruction::SRA(target) => format!("SRA {}", o(target)),
+            Instruction::SLA(target) => format!("SLA {}", o(target)),
+            Instruction::SWAP(target) => format!("SWAP {}", o(target)),
+            Instruction::LD(target, source) => format!("LD {},{}", o(target), o(source)),
+            Instruction::LDI(Target::MemoryR16(_), source) => format!("LD (HL+),{}", o(source)),
+            Instruction::LDI(target, _) => format!("LD {},(HL+)", o(target)),
+            Instruction::LDD(Target::MemoryR16(_), source) => format!("LD (HL-),{}", o(source)),
+            Instruction::LDD(target, _) => format!("LD {},(HL-)", o(target)),
+            Instruction::LDH(target, source) => format!(
+                "LD {},{}",
+                self.high_operand(target),
+                self.high_operand(source)
+            ),
+            Instruction::LDHLSP(offset) => {
+                let offset = signed(offset);
+                if offset.starts_with('-') {
+                    format!("LD HL,SP{}", offset)
+                } else {
+                    format!("LD HL,SP+{}", offset)
+                }
+            }
+            Instruction::CCF() => "CCF".to_string(),
+            Instruction::CPL() => "CPL".to_string(),
+            Instruction::DAA() => "DAA".to_string(),
+            Instruction::NOP() => "NOP".to_string(),
+            Instruction::STOP() => "STOP".to_string(),
+            Instruction::SCF() => "SCF".to_string(),
+            Instruction::RRA() => "RRA".to_string(),
+            Instruction::RLA() => "RLA".to_string(),
+            Instruction::RLCA() => "RLCA".to_string(),
+            Instruction::RRCA() => "RRCA".to_string(),
+            Instruction::DI() => "DI".to_string(),
+            Instruction::EI() => "EI".to_string(),
+            Instruction::HALT() => "HALT".to_string(),
+            Instruction::JPHL(_) => "JP HL".to_string(),
+            Instruction::JP(jump, _) => {
+                format!("JP {}{}", condition(jump), label(self.target().unwrap()))
+            }
+            Instruction::JR(jump, _) => {
+                format!("JR {}{}", condition(jump), label(self.target().unwrap()))
+            }
+            Instruction::CALL(jump, _) => {
+                format!("CALL {}{}", condition(jump), label(self.target().unwrap()))
+            }
+            Instruction::RET(JumpCondition::Always) => "RET".to_string(),
+            Instruction::RET(jump) => format!("RET {}", condition(jump).trim_end_matches(',')),
+            Instruction::RETI(_) => "RETI".to_string(),
+            Instruction::RST(vector) => format!("RST ${:02X}", vector),
+            Instruction::PUSH(target) => format!("PUSH {}", o(target)),
+            Instruction::POP(target) => format!("POP {}", o(target)),
+            Instruction::PUSHAF() => "PUSH AF".to_string(),
+            Instruction::POPAF() => "POP AF".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text(|address| format!("${:04X}", address)))
+    }
+}
+
+fn label_name(address: u16) -> String {
+    format!("Addr_{:04X}", address)
+}
+
+/// Disassembles `code`, which is mapped at `base`, from `from` through `to`, so a listing can
+/// reach 0xFFFF. The range is clamped to the code. Jump targets inside that range get labels.
+/// The layout follows disasm.txt: one tab indented instruction per line with its address in a
+/// comment, and a blank line after every unconditional jump or return.
+pub fn listing(code: &[u8], base: u16, from: u16, to: u16) -> String {
+    listing_with_symbols(code, base, from, to, |_| None)
+}
+
+/// Like `listing`, but addresses `symbol` has a name for are labelled with it, whether or
+/// not anything jumps there.
+pub fn listing_with_symbols(
+    code: &[u8],
+    base: u16,
+    from: u16,
+    to: u16,
+    symbol: impl Fn(u16) -> Option<String>,
+) -> String {
+    if code.is_empty() {
+        return String::new();
+    }
+    let from = from.max(base);
+    let last = to.min(base.saturating_add((code.len() - 1).min(0xFFFF) as u16));
+    let decode_at = |address: u16| decode(&code[(address - base) as usize..], address);
+
+    let mut instructions = Vec::new();
+    let mut address = from;
+    while address <= last {
+        let decoded = decode_at(address);
+        instructions.push(decoded);
+        address = match address.checked_add(decoded.length) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    let labels: BTreeSet<u16> = instructions
+        .iter()
+        .filter_map(Decoded::target)
+        .filter(|target| (from..=last).contains(target))
+        .collect();
+    let label_at = |address: u16| {
+        symbol(address).or_else(|| labels.contains(&address).then(|| label_name(address)))
+    };
+
+    let mut output = String::new();
+    for decoded in &instructions {
+        if let Some(label) = label_at(decoded.address) {
+            let _ = writeln!(output, "{}:", label);
+        }
+        let text =
+            decoded.text(|target| label_at(target).unwrap_or_else(|| format!("${:04X}", target)));
+        let column = TAB_WIDTH + text.len();
+        let tabs = if column < COMMENT_COLUMN {
+            (COMMENT_COLUMN - column).div_ceil(TAB_WIDTH)
+        } else {
+            1
+        };
+        let _ = writeln!(
+            output,
+            "\t{}{}; ${:04X}",
+            text,
+            "\t".repeat(tabs),
+            decoded.address
+        );
+        if decoded.ends_flow() {
+            output.push('\n');
+        }
+    }
+    output
+}
@@ -0,0 +1,150 @@
+use crate::cartridge::Cartridge;
+use crate::cpu::{CpuError, CPU, CYCLES_PER_FRAME};
+use crate::joypad::Button;
+use crate::state::{StateReader, StateWriter};
+use std::io;
+use std::path::Path;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const STATE_MAGIC: &[u8; 4] = b"RMLS";
+// Bump whenever anything changes the layout written by the components
+const STATE_VERSION: u16 = 6;
+
+fn invalid_state(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A complete DMG, the entry point for frontends and tools embedding the emulator.
+pub struct GameBoy {
+    cpu: CPU,
+}
+
+impl GameBoy {
+    /// Starts a cartridge image right where the boot ROM would hand over to it.
+    pub fn new(rom: Vec<u8>) -> Self {
+        GameBoy {
+            cpu: CPU::with_cartridge(Cartridge::from_bytes(rom)),
+        }
+    }
+
+    pub fn load_rom(path: &Path) -> io::Result<Self> {
+        Ok(GameBoy {
+            cpu: CPU::new_with_rom(path)?,
+        })
+    }
+
+    /// Runs the 256 byte DMG boot ROM with no cartridge inserted.
+    pub fn load_boot_rom(path: &Path) -> io::Result<Self> {
+        Ok(GameBoy {
+            cpu: CPU::new_bootrom(path)?,
+        })
+    }
+
+    /// Runs the emulator for one frame worth of cycles.
+    pub fn run_frame(&mut self) -> Result<(), CpuError> {
+        let mut executed_cycles: u32 = 0;
+        while executed_cycles < CYCLES_PER_FRAME {
+            self.cpu.step()?;
+            executed_cycles += self.cpu.cycle_count as u32;
+        }
+        Ok(())
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.cpu.bus.set_button(button, pressed);
+    }
+
+    /// Held buttons as a mask of `Button::mask` bits.
+    pub fn buttons(&self) -> u8 {
+        self.cpu.bus.joypad.pressed()
+    }
+
+    pub fn set_buttons(&mut self, mask: u8) {
+        for button in Button::ALL {
+            self.set_button(button, mask & button.mask() != 0);
+        }
+    }
+
+    /// The current screen as `SCREEN_WIDTH * SCREEN_HEIGHT` 0xRRGGBBAA pixels, row by row.
+    pub fn framebuffer(&mut self) -> Vec<u32> {
+        self.cpu.bus.gpu.render_screen()
+    }
+
+    /// Interleaved stereo samples produced since the last call. There is no APU yet, so
+    /// this is always empty.
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        Vec::new()
+    }
+
+    /// CRC-32 identifying the inserted cartridge, 0 when running without one.
+    pub fn rom_checksum(&self) -> u32 {
+        self.cpu
+            .bus
+            .cartridge
+            .as_ref()
+            .map_or(0, |cartridge| cartridge.checksum())
+    }
+
+    /// Snapshots the whole machine. The state starts with a header holding a magic number,
+    /// the format version and a checksum of the ROM it was taken with.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for &byte in STATE_MAGIC {
+            writer.u8(byte);
+        }
+        writer.u16(STATE_VERSION);
+        writer.u32(self.rom_checksum());
+        self.cpu.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// Restores a state from `save_state`. States from another ROM, another version of the
+    /// format or damaged ones are rejected and leave the machine as it was.
+    pub fn load_state(&mut self, state: &[u8]) -> io::Result<()> {
+        let mut reader = StateReader::new(state);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = reader.u8()?;
+        }
+        if &magic != STATE_MAGIC {
+            return Err(invalid_state("Not a save state".to_string()));
+        }
+        let version = reader.u16()?;
+        if version != STATE_VERSION {
+            return Err(invalid_state(format!(
+                "Save state version {} is not supported, expected {}",
+                version, STATE_VERSION
+            )));
+        }
+        let checksum = reader.u32()?;
+        if checksum != self.rom_checksum() {
+            return Err(invalid_state(format!(
+                "Save state was taken with a different ROM (checksum {:08x}, loaded {:08x})",
+                checksum,
+                self.rom_checksum()
+            )));
+        }
+        // A state that turns out to be damaged halfway through mustn't leave a mix of both
+        let backup = self.save_state();
+        let result = self
+            .cpu
+            .load_state(&mut reader)
+            .and_then(|()| reader.finish());
+        if result.is_err() {
+            self.load_state(&backup)
+                .expect("Restoring the previous state can't fail");
+        }
+        result
+    }
+
+    /// Direct access to the CPU and everything on its bus, for debuggers and test harnesses.
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
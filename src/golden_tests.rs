@@ -0,0 +1,130 @@
+#[cfg(test)]
+mod golden {
+    use crate::cpu::{CPU, CYCLES_PER_FRAME};
+    use crate::gameboy::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::path::{Path, PathBuf};
+
+    const GOLDEN_DIR: &str = "tests/golden";
+    // Set to write the current output as the new golden frame instead of comparing
+    const BLESS_VARIABLE: &str = "RAMIEL_BLESS";
+
+    fn run_frames(cpu: &mut CPU, frames: u32) -> Vec<u32> {
+        for _ in 0..frames {
+            let mut executed_cycles: u32 = 0;
+            while executed_cycles < CYCLES_PER_FRAME {
+                cpu.step().unwrap();
+                executed_cycles += cpu.cycle_count as u32;
+            }
+        }
+        cpu.bus.gpu.render_screen()
+    }
+
+    // The framebuffer holds 0xRRGGBBAA colors, the PNGs are plain RGB
+    fn to_rgb(framebuffer: &[u32]) -> Vec<u8> {
+        framebuffer
+            .iter()
+            .flat_map(|pixel| [(pixel >> 24) as u8, (pixel >> 16) as u8, (pixel >> 8) as u8])
+            .collect()
+    }
+
+    fn load_png(path: &Path) -> Vec<u8> {
+        let file = File::open(path).unwrap_or_else(|e| {
+            panic!(
+                "Failed to open {}: {} (run with {}=1 to create it)",
+                path.display(),
+                e,
+                BLESS_VARIABLE
+            )
+        });
+        let mut reader = png::Decoder::new(file).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(
+            (info.width as usize, info.height as usize, info.color_type),
+            (SCREEN_WIDTH, SCREEN_HEIGHT, png::ColorType::Rgb),
+            "{} is not a {}x{} RGB image",
+            path.display(),
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT
+        );
+        pixels.truncate(info.buffer_size());
+        pixels
+    }
+
+    fn save_png(path: &Path, rgb: &[u8]) {
+        let file = File::create(path).unwrap();
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            SCREEN_WIDTH as u32,
+            SCREEN_HEIGHT as u32,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(rgb).unwrap();
+    }
+
+    // Differing pixels are red, matching ones are a faded copy of the expected frame
+    fn diff_image(expected: &[u8], actual: &[u8]) -> (Vec<u8>, usize) {
+        let mut mismatches = 0;
+        let diff = expected
+            .chunks(3)
+            .zip(actual.chunks(3))
+            .flat_map(|(expected, actual)| {
+                if expected == actual {
+                    let faded = 0xC0 + expected[0] / 4;
+                    [faded, faded, faded]
+                } else {
+                    mismatches += 1;
+                    [0xFF, 0x00, 0x00]
+                }
+            })
+            .collect();
+        (diff, mismatches)
+    }
+
+    /// Compares the frame shown after `frames` frames against `tests/golden/<name>.png`. On
+    /// a mismatch the actual frame and a diff image are written to a temp directory.
+    fn assert_matches_golden(name: &str, cpu: &mut CPU, frames: u32) {
+        let actual = to_rgb(&run_frames(cpu, frames));
+        let golden = Path::new(GOLDEN_DIR).join(format!("{}.png", name));
+
+        if std::env::var_os(BLESS_VARIABLE).is_some() {
+            std::fs::create_dir_all(GOLDEN_DIR).unwrap();
+            save_png(&golden, &actual);
+            println!("Wrote {}", golden.display());
+            return;
+        }
+
+        let expected = load_png(&golden);
+        let (diff, mismatches) = diff_image(&expected, &actual);
+        if mismatches == 0 {
+            return;
+        }
+
+        let output_dir: PathBuf = std::env::temp_dir().join("ramiel-golden");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let actual_path = output_dir.join(format!("{}-actual.png", name));
+        let diff_path = output_dir.join(format!("{}-diff.png", name));
+        save_png(&actual_path, &actual);
+        save_png(&diff_path, &diff);
+        panic!(
+            "{} differs from {} in {} pixels, see {} and {}",
+            name,
+            golden.display(),
+            mismatches,
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+
+    #[test]
+    fn boot_logo() {
+        // The boot ROM has finished scrolling the logo in by then and hangs on the header
+        // checksum, so the frame no longer changes
+        let mut cpu = CPU::new_bootrom(Path::new("roms/dmg_boot.bin")).unwrap();
+        assert_matches_golden("dmg_boot", &mut cpu, 300);
+    }
+}
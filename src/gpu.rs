@@ -1,4 +1,5 @@
 #![allow(unused_variables)]
+use crate::state::{StateReader, StateWriter};
 use std::fs::OpenOptions;
 use std::io::Write;
 
@@ -6,6 +7,13 @@ pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
 pub const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
 
+/// M-cycles per scanline, 456 dots.
+pub const LINE_CYCLES: u16 = 114;
+
+// OAM scan takes 80 dots, pixel transfer at least 172
+const OAM_SCAN_CYCLES: u16 = 20;
+const PIXEL_TRANSFER_CYCLES: u16 = 43;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TilePixelValue {
     Zero,
@@ -29,16 +37,26 @@ pub struct GPU {
     pub lcdc: u8, // LCD Control
     pub stat: u8, // LCDC Status
     mode_clock: u16,
-    pub bgp: u8, // Background Palette
+    pub bgp: u8,  // Background Palette
+    pub obp0: u8, // Object Palette 0
+    pub obp1: u8, // Object Palette 1
+    pub wy: u8,   // Window Y
+    pub wx: u8,   // Window X
+}
+
+impl Default for GPU {
+    fn default() -> Self {
+        GPU::new()
+    }
 }
 
 impl GPU {
+    /// Advances the LCD by `cycles` M-cycles and returns LY.
     pub fn step(&mut self, cycles: u16) -> u8 {
         self.mode_clock += cycles;
 
-        // Each scanline takes ~456 cycles
-        if self.mode_clock >= 456 {
-            self.mode_clock -= 456;
+        if self.mode_clock >= LINE_CYCLES {
+            self.mode_clock -= LINE_CYCLES;
 
             // Move to next scanline
             self.ly = (self.ly + 1) % 154;
@@ -48,7 +66,56 @@ impl GPU {
         }
 
         // No update
-        return self.ly;
+        self.ly
+    }
+
+    /// Reads one of the LCD registers at 0xFF40-0xFF4B. DMA sits in between but belongs to
+    /// the bus.
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF40 => self.lcdc,
+            0xFF41 => self.stat,
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF40 => self.lcdc = value,
+            0xFF41 => self.stat = value,
+            0xFF42 => self.scy = value,
+            0xFF43 => self.scx = value,
+            0xFF44 => self.ly = value,
+            0xFF45 => self.lyc = value,
+            0xFF47 => self.bgp = value,
+            0xFF48 => self.obp0 = value,
+            0xFF49 => self.obp1 = value,
+            0xFF4A => self.wy = value,
+            0xFF4B => self.wx = value,
+            _ => {}
+        }
+    }
+
+    /// The PPU owns OAM during OAM scan and pixel transfer of visible lines. There is no
+    /// mode timing yet, so pixel transfer always takes its shortest length.
+    pub fn oam_blocked(&self) -> bool {
+        self.lcdc & 0x80 != 0
+            && self.ly < 144
+            && self.mode_clock < OAM_SCAN_CYCLES + PIXEL_TRANSFER_CYCLES
+    }
+
+    /// M-cycles until the current scanline ends.
+    pub fn cycles_until_line_end(&self) -> u16 {
+        LINE_CYCLES - self.mode_clock
     }
 
     pub fn new() -> Self {
@@ -63,9 +130,51 @@ impl GPU {
             stat: 0,
             mode_clock: 0,
             bgp: 0xE4,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
         }
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.vram);
+        writer.u8(self.scx);
+        writer.u8(self.scy);
+        writer.u8(self.ly);
+        writer.u8(self.lyc);
+        writer.u8(self.lcdc);
+        writer.u8(self.stat);
+        writer.u16(self.mode_clock);
+        writer.u8(self.bgp);
+        writer.u8(self.obp0);
+        writer.u8(self.obp1);
+        writer.u8(self.wy);
+        writer.u8(self.wx);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> std::io::Result<()> {
+        let mut vram = [0; VRAM_SIZE];
+        reader.bytes_into(&mut vram)?;
+        // Writing VRAM back byte by byte also rebuilds the decoded tiles
+        for (index, value) in vram.into_iter().enumerate() {
+            self.write_vram(index, value);
+        }
+        self.scx = reader.u8()?;
+        self.scy = reader.u8()?;
+        self.ly = reader.u8()?;
+        self.lyc = reader.u8()?;
+        self.lcdc = reader.u8()?;
+        self.stat = reader.u8()?;
+        self.mode_clock = reader.u16()?;
+        self.bgp = reader.u8()?;
+        self.obp0 = reader.u8()?;
+        self.obp1 = reader.u8()?;
+        self.wy = reader.u8()?;
+        self.wx = reader.u8()?;
+        Ok(())
+    }
+
     pub fn read_vram(&self, address: usize) -> u8 {
         self.vram[address]
     }
@@ -108,10 +217,6 @@ impl GPU {
             return framebuffer;
         }
 
-        if self.scy == 0 {
-            self.dump_vram().unwrap();
-        };
-
         for y in 0..SCREEN_HEIGHT {
             for x in 0..SCREEN_WIDTH {
                 // Apply scroll values with wrapping
@@ -127,8 +232,7 @@ impl GPU {
                 let screen_width_tiles = 20; // Screen width in tiles (160/8)
                 let center_x = (screen_width_tiles - logo_width) / 2; // Center point = 4
 
-                let tile_index = if tile_y >= 8
-                    && tile_y < 10
+                let tile_index = if (8..10).contains(&tile_y)
                     && tile_x >= center_x
                     && tile_x < center_x + logo_width
                 {
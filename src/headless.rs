@@ -0,0 +1,137 @@
+use crate::cpu::{CpuError, CPU, CYCLES_PER_FRAME};
+use crate::serial::{BufferSerial, TeeSerial};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// When a headless run should stop. The run always ends after `frames` frames.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    pub frames: u32,
+    /// Stop once the serial output contains this text
+    pub until_serial: Option<String>,
+    /// Stop when the program counter reaches this address
+    pub until_pc: Option<u16>,
+    /// Stop when the CPU is stuck on an instruction that jumps to itself
+    pub until_loop: bool,
+}
+
+impl RunOptions {
+    fn has_condition(&self) -> bool {
+        self.until_serial.is_some() || self.until_pc.is_some() || self.until_loop
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    SerialMatched,
+    PcReached,
+    InfiniteLoop,
+    FrameLimit,
+    /// The CPU hit an illegal opcode or another fault, see `RunResult::error`
+    Crashed,
+}
+
+#[derive(Debug)]
+pub struct RunResult {
+    pub outcome: Outcome,
+    pub frames: u32,
+    pub serial_output: String,
+    pub error: Option<CpuError>,
+    // Whether the run was waiting for a condition that never happened
+    timed_out: bool,
+}
+
+impl RunResult {
+    /// 0 when a requested condition was met (or the frames ran out with nothing to wait
+    /// for), 1 when the frame limit was hit first, 2 when the CPU got stuck in a loop and 3
+    /// when it crashed.
+    pub fn exit_code(&self) -> i32 {
+        match self.outcome {
+            Outcome::SerialMatched | Outcome::PcReached => 0,
+            Outcome::InfiniteLoop => 2,
+            Outcome::Crashed => 3,
+            Outcome::FrameLimit => {
+                if self.timed_out {
+                    1
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+/// Runs the emulator without a window until one of the stop conditions in `options` is met.
+/// Serial output is captured so it can be matched against, unless a link cable is in use.
+/// It still reaches the connected serial device as well, which is plugged back in once the
+/// run is over.
+pub fn run(cpu: &mut CPU, options: &RunOptions) -> RunResult {
+    let serial = BufferSerial::new();
+    let device = Rc::new(RefCell::new(cpu.bus.serial.disconnect()));
+    cpu.bus
+        .serial
+        .connect(Box::new(TeeSerial::new(serial.clone(), device.clone())));
+
+    let result = run_frames(cpu, options, &serial);
+
+    // Unplugging the tee drops its handle, leaving ours as the only one
+    drop(cpu.bus.serial.disconnect());
+    let device = Rc::try_unwrap(device)
+        .ok()
+        .expect("The tee is the only other owner of the device");
+    cpu.bus.serial.connect(device.into_inner());
+    result
+}
+
+fn run_frames(cpu: &mut CPU, options: &RunOptions, serial: &BufferSerial) -> RunResult {
+    let serial_matched = || match &options.until_serial {
+        Some(text) => serial.text().contains(text.as_str()),
+        None => false,
+    };
+
+    let mut frames = 0;
+    let mut error = None;
+    let outcome = 'frames: loop {
+        if frames >= options.frames {
+            break Outcome::FrameLimit;
+        }
+
+        let mut executed_cycles: u32 = 0;
+        while executed_cycles < CYCLES_PER_FRAME {
+            let pc = cpu.pc;
+            if let Err(e) = cpu.step() {
+                error = Some(e);
+                break 'frames Outcome::Crashed;
+            }
+            executed_cycles += cpu.cycle_count as u32;
+
+            if options.until_pc == Some(cpu.pc) {
+                break 'frames Outcome::PcReached;
+            }
+            // A byte still being shifted out will complete even while the CPU spins
+            let spinning = cpu.is_locked_up()
+                || (cpu.pc == pc && !cpu.is_halted() && !cpu.bus.serial.transfer_active());
+            if options.until_loop && spinning {
+                // Test ROMs usually park themselves in a loop right after printing the result
+                if serial_matched() {
+                    break 'frames Outcome::SerialMatched;
+                }
+                break 'frames Outcome::InfiniteLoop;
+            }
+        }
+        frames += 1;
+
+        // Checking once per frame is plenty, test ROMs print far slower than that
+        if serial_matched() {
+            break Outcome::SerialMatched;
+        }
+    };
+
+    RunResult {
+        outcome,
+        frames,
+        serial_output: serial.text(),
+        error,
+        timed_out: outcome == Outcome::FrameLimit && options.has_condition(),
+    }
+}
@@ -0,0 +1,104 @@
+use crate::disasm::Decoded;
+use crate::registers::Registers;
+use crate::symbols::Location;
+use std::fmt;
+
+/// How many instructions the CPU remembers unless told otherwise.
+pub const DEFAULT_HISTORY_LENGTH: usize = 64;
+
+/// An instruction as it was about to execute, with the registers it saw.
+#[derive(Clone, Copy, Debug)]
+pub struct Executed {
+    pub bank: usize,
+    pub decoded: Decoded,
+    pub registers: Registers,
+    pub sp: u16,
+}
+
+impl Executed {
+    pub fn location(&self) -> Location {
+        Location::new(self.bank, self.decoded.address)
+    }
+}
+
+impl fmt::Display for Executed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+        write!(
+            f,
+            "{:02X}:{:04X}  {:<20} A:{:02X} F:{:02X} BC:{:02X}{:02X} DE:{:02X}{:02X} HL:{:02X}{:02X} SP:{:04X}",
+            self.bank,
+            self.decoded.address,
+            self.decoded.to_string(),
+            r.a,
+            u8::from(r.f),
+            r.b,
+            r.c,
+            r.d,
+            r.e,
+            r.h,
+            r.l,
+            self.sp
+        )
+    }
+}
+
+/// The last few executed instructions, oldest first, to find out how the CPU got into a
+/// state it shouldn't be in.
+pub struct History {
+    entries: Vec<Executed>,
+    capacity: usize,
+    // Where the next entry goes once the buffer is full, which is also the oldest one
+    next: usize,
+}
+
+impl History {
+    /// Remembers up to `capacity` instructions, 0 turns recording off.
+    pub fn new(capacity: usize) -> Self {
+        History {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(&mut self, executed: Executed) {
+        if self.entries.len() < self.capacity {
+            self.entries.push(executed);
+        } else if self.capacity > 0 {
+            self.entries[self.next] = executed;
+            self.next += 1;
+            if self.next == self.capacity {
+                self.next = 0;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Executed> {
+        let (newer, older) = self.entries.split_at(self.next);
+        older.iter().chain(newer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.next = 0;
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_HISTORY_LENGTH)
+    }
+}
@@ -1,5 +1,5 @@
 use super::registers::*;
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instruction {
     //u8 instructions
     ADC(Target),
@@ -26,7 +26,7 @@ pub enum Instruction {
     LDI(Target, Target),
     LDD(Target, Target),
     LDH(LDHRegister, LDHRegister),
-    LDHLSP(),
+    LDHLSP(i8),
     //No target instructions
     CCF(),
     CPL(),
@@ -44,7 +44,7 @@ pub enum Instruction {
     //jump instructions
     JPHL(Target),
     JP(JumpCondition, u16),
-    JR(JumpCondition),
+    JR(JumpCondition, i8),
 
     //stack instructions
     PUSH(Target),
@@ -55,6 +55,7 @@ pub enum Instruction {
     CALL(JumpCondition, u16),
     RET(JumpCondition),
     RETI(JumpCondition),
+    RST(u8),
     HALT(),
     RES(u8, Target),
 }
@@ -69,7 +70,7 @@ impl Instruction {
             0x04 => Some(Instruction::RLC(Target::Register(ArithmeticTarget::H))),
             0x05 => Some(Instruction::RLC(Target::Register(ArithmeticTarget::L))),
             0x06 => Some(Instruction::RLC(Target::MemoryR16(DoubleTarget::HL))),
-            0x07 => Some(Instruction::RLCA()),
+            0x07 => Some(Instruction::RLC(Target::Register(ArithmeticTarget::A))),
             0x08 => Some(Instruction::RRC(Target::Register(ArithmeticTarget::B))),
             0x09 => Some(Instruction::RRC(Target::Register(ArithmeticTarget::C))),
             0x0A => Some(Instruction::RRC(Target::Register(ArithmeticTarget::D))),
@@ -326,10 +327,10 @@ impl Instruction {
             0x00 => Some(Instruction::NOP()),
             0x01 => Some(Instruction::LD(
                 Target::Register16(DoubleTarget::BC),
-                Target::Const16(),
+                Target::Const16(0),
             )),
             0x02 => Some(Instruction::LD(
-                Target::Register16(DoubleTarget::BC),
+                Target::MemoryR16(DoubleTarget::BC),
                 Target::Register(ArithmeticTarget::A),
             )),
             0x03 => Some(Instruction::INC(Target::Register16(DoubleTarget::BC))),
@@ -337,11 +338,11 @@ impl Instruction {
             0x05 => Some(Instruction::DEC(Target::Register(ArithmeticTarget::B))),
             0x06 => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::B),
-                Target::Const8(),
+                Target::Const8(0),
             )),
-            0x07 => Some(Instruction::RLC(Target::Register(ArithmeticTarget::A))),
+            0x07 => Some(Instruction::RLCA()),
             0x08 => Some(Instruction::LD(
-                Target::MemoryConst16(),
+                Target::MemoryConst16(0),
                 Target::Register16(DoubleTarget::SP),
             )),
             0x09 => Some(Instruction::ADD(
@@ -357,13 +358,13 @@ impl Instruction {
             0x0D => Some(Instruction::DEC(Target::Register(ArithmeticTarget::C))),
             0x0E => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::C),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x0F => Some(Instruction::RRCA()),
             0x10 => Some(Instruction::STOP()),
             0x11 => Some(Instruction::LD(
                 Target::Register16(DoubleTarget::DE),
-                Target::Const16(),
+                Target::Const16(0),
             )),
             0x12 => Some(Instruction::LD(
                 Target::MemoryR16(DoubleTarget::DE),
@@ -374,10 +375,10 @@ impl Instruction {
             0x15 => Some(Instruction::DEC(Target::Register(ArithmeticTarget::D))),
             0x16 => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::D),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x17 => Some(Instruction::RLA()),
-            0x18 => Some(Instruction::JR(JumpCondition::Always)),
+            0x18 => Some(Instruction::JR(JumpCondition::Always, 0)),
             0x19 => Some(Instruction::ADD(
                 Target::Register16(DoubleTarget::HL),
                 Target::Register16(DoubleTarget::DE),
@@ -391,14 +392,14 @@ impl Instruction {
             0x1D => Some(Instruction::DEC(Target::Register(ArithmeticTarget::E))),
             0x1E => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::E),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x1F => Some(Instruction::RRA()),
 
-            0x20 => Some(Instruction::JR(JumpCondition::NotZero)),
+            0x20 => Some(Instruction::JR(JumpCondition::NotZero, 0)),
             0x21 => Some(Instruction::LD(
                 Target::Register16(DoubleTarget::HL),
-                Target::Const16(),
+                Target::Const16(0),
             )),
             0x22 => Some(Instruction::LDI(
                 Target::MemoryR16(DoubleTarget::HL),
@@ -409,30 +410,30 @@ impl Instruction {
             0x25 => Some(Instruction::DEC(Target::Register(ArithmeticTarget::H))),
             0x26 => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::H),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x27 => Some(Instruction::DAA()),
-            0x28 => Some(Instruction::JR(JumpCondition::Zero)),
+            0x28 => Some(Instruction::JR(JumpCondition::Zero, 0)),
             0x29 => Some(Instruction::ADD(
                 Target::Register16(DoubleTarget::HL),
                 Target::Register16(DoubleTarget::HL),
             )),
             0x2A => Some(Instruction::LDI(
                 Target::Register(ArithmeticTarget::A),
-                Target::Register16(DoubleTarget::HL),
+                Target::MemoryR16(DoubleTarget::HL),
             )),
             0x2B => Some(Instruction::DEC(Target::Register16(DoubleTarget::HL))),
             0x2C => Some(Instruction::INC(Target::Register(ArithmeticTarget::L))),
             0x2D => Some(Instruction::DEC(Target::Register(ArithmeticTarget::L))),
             0x2E => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::L),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x2F => Some(Instruction::CPL()),
-            0x30 => Some(Instruction::JR(JumpCondition::NotCarry)),
+            0x30 => Some(Instruction::JR(JumpCondition::NotCarry, 0)),
             0x31 => Some(Instruction::LD(
                 Target::Register16(DoubleTarget::SP),
-                Target::Const16(),
+                Target::Const16(0),
             )),
             0x32 => Some(Instruction::LDD(
                 Target::MemoryR16(DoubleTarget::HL),
@@ -443,10 +444,10 @@ impl Instruction {
             0x35 => Some(Instruction::DEC(Target::MemoryR16(DoubleTarget::HL))),
             0x36 => Some(Instruction::LD(
                 Target::MemoryR16(DoubleTarget::HL),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x37 => Some(Instruction::SCF()),
-            0x38 => Some(Instruction::JR(JumpCondition::Carry)),
+            0x38 => Some(Instruction::JR(JumpCondition::Carry, 0)),
             0x39 => Some(Instruction::ADD(
                 Target::Register16(DoubleTarget::HL),
                 Target::Register16(DoubleTarget::SP),
@@ -460,7 +461,7 @@ impl Instruction {
             0x3D => Some(Instruction::DEC(Target::Register(ArithmeticTarget::A))),
             0x3E => Some(Instruction::LD(
                 Target::Register(ArithmeticTarget::A),
-                Target::Const8(),
+                Target::Const8(0),
             )),
             0x3F => Some(Instruction::CCF()),
             0x40 => Some(Instruction::LD(
@@ -908,73 +909,191 @@ impl Instruction {
             0xC5 => Some(Instruction::PUSH(Target::Register16(DoubleTarget::BC))),
             0xC6 => Some(Instruction::ADD(
                 Target::Register(ArithmeticTarget::A),
-                Target::Const8(),
+                Target::Const8(0),
             )),
-            0xC7 => unimplemented!("RST instruction not implemented yet"),
+            0xC7 => Some(Instruction::RST(0x00)),
             0xC8 => Some(Instruction::RET(JumpCondition::Zero)),
             0xC9 => Some(Instruction::RET(JumpCondition::Always)),
             0xCA => Some(Instruction::JP(JumpCondition::Zero, 0)),
-            0xCB => panic!("No instruction to execute. Not reachable. Error this should be prefix instruction."),
+            0xCB => None, // Prefix byte, decoded by from_prefixed_byte
             0xCC => Some(Instruction::CALL(JumpCondition::Zero, 0)),
             0xCD => Some(Instruction::CALL(JumpCondition::Always, 0)),
-            0xCE => Some(Instruction::ADC(Target::Const8())),
-            0xCF => unimplemented!("RST instruction not implemented yet"),
+            0xCE => Some(Instruction::ADC(Target::Const8(0))),
+            0xCF => Some(Instruction::RST(0x08)),
             0xD0 => Some(Instruction::RET(JumpCondition::NotCarry)),
             0xD1 => Some(Instruction::POP(Target::Register16(DoubleTarget::DE))),
             0xD2 => Some(Instruction::JP(JumpCondition::NotCarry, 0)),
-            0xD3 => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
+            0xD3 => None,
             0xD4 => Some(Instruction::CALL(JumpCondition::NotCarry, 0)),
             0xD5 => Some(Instruction::PUSH(Target::Register16(DoubleTarget::DE))),
-            0xD6 => Some(Instruction::SUB(Target::Const8())),
-            0xD7 => unimplemented!("RST instruction not implemented yet"),
+            0xD6 => Some(Instruction::SUB(Target::Const8(0))),
+            0xD7 => Some(Instruction::RST(0x10)),
             0xD8 => Some(Instruction::RET(JumpCondition::Carry)),
             0xD9 => Some(Instruction::RETI(JumpCondition::Always)),
             0xDA => Some(Instruction::JP(JumpCondition::Carry, 0)),
-            0xDB => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
+            0xDB => None,
             0xDC => Some(Instruction::CALL(JumpCondition::Carry, 0)),
-            0xDD => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xDE => Some(Instruction::SBC(Target::Register(ArithmeticTarget::A),Target::Const8())),
-            0xDF => unimplemented!("RST instruction not implemented yet"),
-            0xE0 => Some(Instruction::LDH(LDHRegister::MemA8, LDHRegister::ArithmeticTarget)),
+            0xDD => None,
+            0xDE => Some(Instruction::SBC(Target::Register(ArithmeticTarget::A),Target::Const8(0))),
+            0xDF => Some(Instruction::RST(0x18)),
+            0xE0 => Some(Instruction::LDH(LDHRegister::MemA8(0), LDHRegister::ArithmeticTarget)),
             0xE1 => Some(Instruction::POP(Target::Register16(DoubleTarget::HL))),
             0xE2 => Some(Instruction::LDH(LDHRegister::C, LDHRegister::ArithmeticTarget)),
-            0xE3 => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xE4 => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
+            0xE3 => None,
+            0xE4 => None,
             0xE5 => Some(Instruction::PUSH(Target::Register16(DoubleTarget::HL))),
-            0xE6 => Some(Instruction::AND(Target::Register(ArithmeticTarget::A),Target::Const8())),
-            0xE7 => unimplemented!("RST instruction not implemented yet"),
-            0xE8 => Some(Instruction::ADD(Target::Register16(DoubleTarget::SP),Target::Const8())),
+            0xE6 => Some(Instruction::AND(Target::Register(ArithmeticTarget::A),Target::Const8(0))),
+            0xE7 => Some(Instruction::RST(0x20)),
+            0xE8 => Some(Instruction::ADD(Target::Register16(DoubleTarget::SP),Target::Const8(0))),
             0xE9 => Some(Instruction::JPHL(Target::Register16(DoubleTarget::HL))),
-            0xEA => Some(Instruction::LD(Target::MemoryConst16(), Target::Register(ArithmeticTarget::A))),
-            0xEB => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xEC => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xED => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xEE => Some(Instruction::XOR(Target::Register(ArithmeticTarget::A),Target::Const8())),
-            0xEF => unimplemented!("RST instruction not implemented yet"),
-            0xF0 => Some(Instruction::LDH(LDHRegister::ArithmeticTarget, LDHRegister::MemA8)),
+            0xEA => Some(Instruction::LD(Target::MemoryConst16(0), Target::Register(ArithmeticTarget::A))),
+            0xEB => None,
+            0xEC => None,
+            0xED => None,
+            0xEE => Some(Instruction::XOR(Target::Register(ArithmeticTarget::A),Target::Const8(0))),
+            0xEF => Some(Instruction::RST(0x28)),
+            0xF0 => Some(Instruction::LDH(LDHRegister::ArithmeticTarget, LDHRegister::MemA8(0))),
             0xF1 => Some(Instruction::POPAF()),
             0xF2 => Some(Instruction::LDH(LDHRegister::ArithmeticTarget, LDHRegister::C)),
             0xF3 => Some(Instruction::DI()),
-            0xF4 => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
+            0xF4 => None,
             0xF5 => Some(Instruction::PUSHAF()),
-            0xF6 => Some(Instruction::OR(Target::Register(ArithmeticTarget::A),Target::Const8())),
-            0xF7 => unimplemented!("RST instruction not implemented yet"),
-            0xF8 => Some(Instruction::LDHLSP()),
+            0xF6 => Some(Instruction::OR(Target::Register(ArithmeticTarget::A),Target::Const8(0))),
+            0xF7 => Some(Instruction::RST(0x30)),
+            0xF8 => Some(Instruction::LDHLSP(0)),
             0xF9 => Some(Instruction::LD(Target::Register16(DoubleTarget::SP),Target::Register16(DoubleTarget::HL))),
-            0xFA => Some(Instruction::LD(Target::Register(ArithmeticTarget::A), Target::MemoryConst16())),
+            0xFA => Some(Instruction::LD(Target::Register(ArithmeticTarget::A), Target::MemoryConst16(0))),
             0xFB => Some(Instruction::EI()),
-            0xFC => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xFD => panic!("No instruction to execute. This byte is empty: {:#X}", byte),
-            0xFE => Some(Instruction::CP(Target::Const8())),
-            0xFF => unimplemented!("RST instruction not implemented yet"),
+            0xFC => None,
+            0xFD => None,
+            0xFE => Some(Instruction::CP(Target::Const8(0))),
+            0xFF => Some(Instruction::RST(0x38)),
+        }
+    }
+
+    /// Size in bytes of the instruction, including the CB prefix and its operands.
+    pub fn length(&self) -> u16 {
+        match self {
+            Instruction::JP(..) | Instruction::CALL(..) => 3,
+            Instruction::JR(..) | Instruction::LDHLSP(_) | Instruction::STOP() => 2,
+            Instruction::LDH(LDHRegister::MemA8(_), _)
+            | Instruction::LDH(_, LDHRegister::MemA8(_)) => 2,
+            Instruction::BIT(..)
+            | Instruction::SET(..)
+            | Instruction::RES(..)
+            | Instruction::SRL(_)
+            | Instruction::RR(_)
+            | Instruction::RL(_)
+            | Instruction::RRC(_)
+            | Instruction::RLC(_)
+            | Instruction::SRA(_)
+            | Instruction::SLA(_)
+            | Instruction::SWAP(_) => 2,
+            Instruction::ADC(source) | Instruction::CP(source) | Instruction::SUB(source) => {
+                1 + source.immediate_size()
+            }
+            Instruction::ADD(target, source)
+            | Instruction::AND(target, source)
+            | Instruction::SBC(target, source)
+            | Instruction::OR(target, source)
+            | Instruction::XOR(target, source)
+            | Instruction::LD(target, source) => {
+                1 + target.immediate_size() + source.immediate_size()
+            }
+            _ => 1,
+        }
+    }
+
+    /// Decodes one instruction, reading its bytes through `fetch`, which gets the offset
+    /// from the first byte. Only the bytes that belong to the instruction are fetched.
+    /// Returns None for illegal opcodes.
+    pub fn decode(mut fetch: impl FnMut(u16) -> u8) -> Option<Instruction> {
+        let opcode = fetch(0);
+        if opcode == 0xCB {
+            return Instruction::from_prefixed_byte(fetch(1));
+        }
+        let instruction = Instruction::match_byte(opcode)?;
+        let immediate = match instruction.length() {
+            1 => return Some(instruction),
+            2 => fetch(1) as u16,
+            _ => u16::from_le_bytes([fetch(1), fetch(2)]),
+        };
+        Some(instruction.with_immediate(immediate))
+    }
+
+    /// Encodes the instruction back into its bytes. Returns None for operand combinations
+    /// the CPU doesn't have, like loading into a constant. This searches the opcode tables,
+    /// so it's meant for tools rather than anything running per instruction.
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let template = self.with_immediate(0);
+        let unprefixed = (0..=0xFF).find(|&byte| Instruction::match_byte(byte) == Some(template));
+        let mut bytes = match unprefixed {
+            Some(opcode) => vec![opcode],
+            None => {
+                let opcode = (0..=0xFF)
+                    .find(|&byte| Instruction::from_prefixed_byte(byte) == Some(template))?;
+                vec![0xCB, opcode]
+            }
+        };
+        let immediate = self.immediate().to_le_bytes();
+        bytes.extend_from_slice(&immediate[..self.length() as usize - bytes.len()]);
+        Some(bytes)
+    }
+
+    // The operand bytes after the opcode, 0 when there are none
+    fn immediate(&self) -> u16 {
+        match *self {
+            Instruction::ADC(source) | Instruction::CP(source) | Instruction::SUB(source) => {
+                source.immediate().unwrap_or(0)
+            }
+            Instruction::ADD(target, source)
+            | Instruction::AND(target, source)
+            | Instruction::SBC(target, source)
+            | Instruction::OR(target, source)
+            | Instruction::XOR(target, source)
+            | Instruction::LD(target, source) => {
+                target.immediate().or(source.immediate()).unwrap_or(0)
+            }
+            Instruction::LDH(LDHRegister::MemA8(offset), _)
+            | Instruction::LDH(_, LDHRegister::MemA8(offset)) => offset as u16,
+            Instruction::LDHLSP(offset) | Instruction::JR(_, offset) => offset as u8 as u16,
+            Instruction::JP(_, address) | Instruction::CALL(_, address) => address,
+            _ => 0,
+        }
+    }
+
+    // Fills the placeholder operands that match_byte leaves as zero
+    fn with_immediate(self, immediate: u16) -> Instruction {
+        let fill = |target: Target| target.with_immediate(immediate);
+        match self {
+            Instruction::ADC(source) => Instruction::ADC(fill(source)),
+            Instruction::CP(source) => Instruction::CP(fill(source)),
+            Instruction::SUB(source) => Instruction::SUB(fill(source)),
+            Instruction::ADD(target, source) => Instruction::ADD(fill(target), fill(source)),
+            Instruction::AND(target, source) => Instruction::AND(fill(target), fill(source)),
+            Instruction::SBC(target, source) => Instruction::SBC(fill(target), fill(source)),
+            Instruction::OR(target, source) => Instruction::OR(fill(target), fill(source)),
+            Instruction::XOR(target, source) => Instruction::XOR(fill(target), fill(source)),
+            Instruction::LD(target, source) => Instruction::LD(fill(target), fill(source)),
+            Instruction::LDH(LDHRegister::MemA8(_), source) => {
+                Instruction::LDH(LDHRegister::MemA8(immediate as u8), source)
+            }
+            Instruction::LDH(target, LDHRegister::MemA8(_)) => {
+                Instruction::LDH(target, LDHRegister::MemA8(immediate as u8))
+            }
+            Instruction::LDHLSP(_) => Instruction::LDHLSP(immediate as u8 as i8),
+            Instruction::JP(condition, _) => Instruction::JP(condition, immediate),
+            Instruction::JR(condition, _) => Instruction::JR(condition, immediate as u8 as i8),
+            Instruction::CALL(condition, _) => Instruction::CALL(condition, immediate),
+            other => other,
         }
     }
 
     pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
         if prefixed {
-            return Instruction::from_prefixed_byte(byte);
+            Instruction::from_prefixed_byte(byte)
         } else {
-            return Instruction::match_byte(byte);
+            Instruction::match_byte(byte)
         }
     }
 }
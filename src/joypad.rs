@@ -0,0 +1,108 @@
+use crate::state::{StateReader, StateWriter};
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    /// Bit in the pressed mask, the directions are the low nibble and the buttons the high one.
+    pub fn mask(self) -> u8 {
+        match self {
+            Button::Right => 1 << 0,
+            Button::Left => 1 << 1,
+            Button::Up => 1 << 2,
+            Button::Down => 1 << 3,
+            Button::A => 1 << 4,
+            Button::B => 1 << 5,
+            Button::Select => 1 << 6,
+            Button::Start => 1 << 7,
+        }
+    }
+}
+
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_BUTTONS: u8 = 1 << 5;
+
+pub struct Joypad {
+    select: u8, // Bits 4-5 of P1, a group is selected when its bit is 0
+    pressed: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            pressed: 0,
+        }
+    }
+
+    /// P1 reads 0 for every pressed button in the selected groups.
+    pub fn read(&self) -> u8 {
+        let mut low = 0x0F;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            low &= !self.pressed & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            low &= !(self.pressed >> 4) & 0x0F;
+        }
+        0xC0 | self.select | low
+    }
+
+    /// Held buttons as a mask of `Button::mask` bits.
+    pub fn pressed(&self) -> u8 {
+        self.pressed
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+    }
+
+    // Which buttons are held is input, not state, so only the group selection is saved
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.select);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.select = reader.u8()? & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+        Ok(())
+    }
+
+    /// Returns true when the joypad interrupt should be requested, which happens when a
+    /// button in a selected group goes down.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.read();
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        // Any P1 line going from high to low
+        before & !self.read() & 0x0F != 0
+    }
+}
@@ -0,0 +1,33 @@
+#![allow(clippy::upper_case_acronyms)]
+pub mod asm;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+mod gameboy;
+pub mod gpu;
+pub mod headless;
+pub mod history;
+pub mod instructions;
+pub mod joypad;
+pub mod link;
+pub mod mooneye;
+pub mod movie;
+pub mod registers;
+pub mod rewind;
+pub mod scheduler;
+pub mod serial;
+pub mod state;
+pub mod symbols;
+pub mod timer;
+pub mod watchpoint;
+
+mod golden_tests;
+mod rom_tests;
+mod single_step_tests;
+mod unit_tests;
+
+pub use cpu::CpuError;
+pub use gameboy::{GameBoy, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use joypad::Button;
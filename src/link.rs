@@ -0,0 +1,136 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Both ends stop and exchange their serial state every this many M-cycles, one byte worth
+// of bits at the internal clock rate
+pub const LINK_QUANTUM: u32 = 1024;
+
+const HANDSHAKE: &[u8; 4] = b"RMLK";
+
+const FLAG_CLOCKED: u8 = 0x01;
+const FLAG_WAITING: u8 = 0x02;
+
+pub trait LinkStream: Read + Write {}
+impl<T: Read + Write> LinkStream for T {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkRole {
+    Master,
+    Slave,
+}
+
+/// What one side of the cable looked like at a sync point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkMessage {
+    /// An internal clock transfer finished shifting during the last quantum
+    pub clocked: bool,
+    /// A transfer is armed and waiting for the other side to provide the clock
+    pub waiting: bool,
+    /// The byte being shifted out: the outgoing byte when clocked, SB otherwise
+    pub data: u8,
+}
+
+/// A link cable to another emulator instance. The two ends run in lockstep: every
+/// `LINK_QUANTUM` cycles both block until they have swapped a `LinkMessage`, so a transfer
+/// always completes on the same emulated cycle on both sides, no matter how fast either
+/// host is running.
+pub struct LinkCable {
+    stream: Box<dyn LinkStream>,
+    cycles_until_sync: u32,
+}
+
+impl LinkCable {
+    pub fn new(mut stream: Box<dyn LinkStream>, role: LinkRole) -> io::Result<Self> {
+        let mut reply = [0u8; 4];
+        match role {
+            LinkRole::Master => {
+                stream.write_all(HANDSHAKE)?;
+                stream.read_exact(&mut reply)?;
+            }
+            LinkRole::Slave => {
+                stream.read_exact(&mut reply)?;
+                stream.write_all(HANDSHAKE)?;
+            }
+        }
+        if &reply != HANDSHAKE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Peer is not a Ramiel link cable",
+            ));
+        }
+
+        Ok(LinkCable {
+            stream,
+            cycles_until_sync: LINK_QUANTUM,
+        })
+    }
+
+    /// Connects to an instance listening on `address` and becomes the master.
+    pub fn connect_tcp(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        LinkCable::new(Box::new(stream), LinkRole::Master)
+    }
+
+    /// Waits for the master to connect on `address`.
+    pub fn listen_tcp(address: &str) -> io::Result<Self> {
+        let (stream, peer) = TcpListener::bind(address)?.accept()?;
+        log::info!("Link cable connected to {}", peer);
+        stream.set_nodelay(true)?;
+        LinkCable::new(Box::new(stream), LinkRole::Slave)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(path: &std::path::Path) -> io::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        LinkCable::new(Box::new(stream), LinkRole::Master)
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix(path: &std::path::Path) -> io::Result<Self> {
+        // A socket file left behind by a previous session would make bind fail
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let (stream, _) = std::os::unix::net::UnixListener::bind(path)?.accept()?;
+        log::info!("Link cable connected on {}", path.display());
+        LinkCable::new(Box::new(stream), LinkRole::Slave)
+    }
+
+    /// Advances the local clock by `cycles` M-cycles. Returns true when a sync point was
+    /// reached and `exchange` has to be called.
+    pub fn advance(&mut self, cycles: u16) -> bool {
+        let cycles = cycles as u32;
+        if cycles < self.cycles_until_sync {
+            self.cycles_until_sync -= cycles;
+            return false;
+        }
+        self.cycles_until_sync = LINK_QUANTUM - (cycles - self.cycles_until_sync) % LINK_QUANTUM;
+        true
+    }
+
+    pub fn cycles_until_sync(&self) -> u32 {
+        self.cycles_until_sync
+    }
+
+    /// Sends our state and blocks until the peer's state for the same sync point arrives.
+    pub fn exchange(&mut self, message: LinkMessage) -> io::Result<LinkMessage> {
+        let mut flags = 0;
+        if message.clocked {
+            flags |= FLAG_CLOCKED;
+        }
+        if message.waiting {
+            flags |= FLAG_WAITING;
+        }
+        self.stream.write_all(&[flags, message.data])?;
+        self.stream.flush()?;
+
+        let mut reply = [0u8; 2];
+        self.stream.read_exact(&mut reply)?;
+        Ok(LinkMessage {
+            clocked: reply[0] & FLAG_CLOCKED != 0,
+            waiting: reply[0] & FLAG_WAITING != 0,
+            data: reply[1],
+        })
+    }
+}
@@ -1,53 +1,469 @@
-use cpu::CPU;
-use minifb::{Scale, Window, WindowOptions};
-use std::path::PathBuf;
-use clap::Parser;
-use std::io::{self, Write};
-
-mod cpu;
-mod gpu;
-mod instructions;
-mod registers;
-mod unit_tests;
+use clap::{Parser, Subcommand};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use ramiel::cartridge::ROM_BANK_SIZE;
+use ramiel::debugger::Debugger;
+use ramiel::disasm;
+use ramiel::headless::{self, RunOptions};
+use ramiel::history::{History, DEFAULT_HISTORY_LENGTH};
+use ramiel::link::LinkCable;
+use ramiel::mooneye;
+use ramiel::movie::Movie;
+use ramiel::rewind::Rewind;
+use ramiel::serial::{SerialDevice, SocketSerial, StdoutSerial};
+use ramiel::symbols::{Location, Symbols};
+use ramiel::{Button, GameBoy, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// Keyboard layout for the joypad
+const KEY_BINDINGS: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::Z, Button::A),
+    (Key::X, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
+// F1-F4 load a save state slot, with Shift held they save to it instead
+const STATE_SLOT_KEYS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+// Held to step back through the rewind buffer
+const REWIND_KEY: Key = Key::R;
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[clap(short, long)]
     /// Print debug info
     debug: bool,
-    #[clap(short, long)]
-    /// Execute one instruction at a time
-    step: bool,
+    #[clap(long)]
+    /// Start paused in the command line debugger, type `help` at the prompt for commands
+    debugger: bool,
+    #[clap(long = "break", requires = "debugger", value_name = "LOC")]
+    /// Set a debugger breakpoint at an address, BANK:ADDR or symbol, can be repeated
+    breakpoints: Vec<String>,
+    #[clap(long)]
+    /// Where serial output goes: `stdout`, `tcp:HOST:PORT` or `unix:PATH`
+    serial: Option<String>,
+    #[clap(long, conflicts_with_all = ["serial", "link_connect"])]
+    /// Wait for another instance to connect a link cable on `tcp:HOST:PORT` or `unix:PATH`
+    link_listen: Option<String>,
+    #[clap(long, conflicts_with = "serial")]
+    /// Connect a link cable to an instance started with --link-listen, acting as master
+    link_connect: Option<String>,
+    #[clap(long)]
+    /// Run without a window, see --frames and the --until-* conditions
+    headless: bool,
+    #[clap(long, default_value_t = 600)]
+    /// Number of frames to run in headless mode
+    frames: u32,
+    #[clap(long, requires = "headless")]
+    /// Stop once the serial output contains this text
+    until_serial: Option<String>,
+    #[clap(long, requires = "headless", value_parser = parse_address)]
+    /// Stop when PC reaches this address, e.g. 0x0100
+    until_pc: Option<u16>,
+    #[clap(long, requires = "headless")]
+    /// Stop when the CPU jumps to the same instruction forever
+    until_loop: bool,
+    #[clap(long, conflicts_with = "headless")]
+    /// Run every mooneye test ROM in this directory and print a pass/fail table
+    mooneye: Option<PathBuf>,
+    #[clap(long, conflicts_with_all = ["debugger", "play_movie"])]
+    /// Record the joypad input to this movie file, it is written when the window is closed
+    record_movie: Option<PathBuf>,
+    #[clap(long, conflicts_with = "debugger")]
+    /// Replay a movie recorded with --record-movie, then hand control back to the keyboard
+    play_movie: Option<PathBuf>,
+    #[clap(long, conflicts_with_all = ["headless", "mooneye", "record_movie", "play_movie"])]
+    /// Replay a movie without a window and exit with 0 if it ends on the recorded frame
+    verify_movie: Option<PathBuf>,
+    #[clap(long, default_value_t = 2)]
+    /// Frames between rewind snapshots
+    rewind_interval: u32,
+    #[clap(long, default_value_t = 64)]
+    /// Memory the rewind buffer may use, in MiB
+    rewind_budget: usize,
+    #[clap(long)]
+    /// Hang on illegal opcodes like a real DMG instead of stopping with an error
+    lockup: bool,
+    #[clap(long)]
+    /// Stop with an error when an instruction would wrap SP around the address space
+    stack_fault: bool,
+    #[clap(long, value_name = "FILE")]
+    /// Write the registers before every instruction to FILE, one line each in the format
+    /// Gameboy Doctor uses
+    trace: Option<PathBuf>,
+    #[clap(long, requires = "trace")]
+    /// End every trace line with the closest symbol, which Gameboy Doctor doesn't expect
+    trace_symbols: bool,
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_HISTORY_LENGTH)]
+    /// Instructions to remember for crash reports and the debugger's `history`, 0 turns
+    /// recording off
+    history: usize,
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["play_movie", "verify_movie"])]
+    /// Start from a save state, e.g. the one written next to the ROM after a crash
+    load_state: Option<PathBuf>,
     /// Path to the ROM file
     #[clap(default_value = "roms/dmg_boot.bin")]
     path: PathBuf,
 }
 
-fn wait_for_keypress() {
-    print!("Press Enter to continue...");
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print a disassembly of one ROM bank with labels for jump targets
+    Disasm {
+        /// Path to the ROM file
+        path: PathBuf,
+        #[clap(long, default_value_t = 0)]
+        /// Bank to disassemble, bank 0 is mapped at 0x0000 and the others at 0x4000
+        bank: usize,
+        #[clap(long, value_parser = parse_address)]
+        /// First address, the start of the bank by default
+        from: Option<u16>,
+        #[clap(long, value_parser = parse_address)]
+        /// Last address, the end of the bank by default
+        to: Option<u16>,
+    },
 }
 
-fn main() {
-    pub const SCREEN_WIDTH: usize = 160;
-    pub const SCREEN_HEIGHT: usize = 144;
+fn print_disassembly(
+    path: &Path,
+    bank: usize,
+    from: Option<u16>,
+    to: Option<u16>,
+) -> io::Result<()> {
+    let rom = std::fs::read(path)?;
+    let start = bank * ROM_BANK_SIZE;
+    if start >= rom.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no bank {}", path.display(), bank),
+        ));
+    }
+    let code = &rom[start..rom.len().min(start + ROM_BANK_SIZE)];
+    let base = if bank == 0 { 0 } else { ROM_BANK_SIZE as u16 };
+    let last = base + (ROM_BANK_SIZE - 1) as u16;
+    for address in [from, to].into_iter().flatten() {
+        if !(base..=last).contains(&address) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:#06x} is outside bank {}, which is mapped at {:#06x}-{:#06x}",
+                    address,
+                    bank,
+                    base,
+                    last
+                ),
+            ));
+        }
+    }
+    let symbols = load_symbols(path);
+    let symbol = |address: u16| {
+        symbols
+            .name_at(Location::new(bank, address))
+            .map(str::to_string)
+    };
+    print!(
+        "{}",
+        disasm::listing_with_symbols(code, base, from.unwrap_or(base), to.unwrap_or(last), symbol)
+    );
+    Ok(())
+}
+
+fn parse_address(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix('$'))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("Invalid address {}: {}", value, e))
+}
 
+// RGBDS writes them next to the ROM, e.g. game.sym. Missing ones are no error, most ROMs come
+// without symbols
+fn load_symbols(rom: &Path) -> Symbols {
+    let path = rom.with_extension("sym");
+    if !path.exists() {
+        return Symbols::default();
+    }
+    match Symbols::load(&path) {
+        Ok(symbols) => {
+            log::info!("Loaded {} symbols from {}", symbols.len(), path.display());
+            symbols
+        }
+        Err(e) => {
+            log::warn!("Ignoring symbols: {}", e);
+            Symbols::default()
+        }
+    }
+}
+
+// Slots live next to the ROM, e.g. game.ss1
+fn state_slot_path(rom: &Path, slot: usize) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+fn save_state_slot(gameboy: &GameBoy, rom: &Path, slot: usize) {
+    let path = state_slot_path(rom, slot);
+    match std::fs::write(&path, gameboy.save_state()) {
+        Ok(()) => log::info!("Saved state to {}", path.display()),
+        Err(e) => log::error!("Failed to save state to {}: {}", path.display(), e),
+    }
+}
+
+fn load_state_slot(gameboy: &mut GameBoy, rom: &Path, slot: usize) {
+    let path = state_slot_path(rom, slot);
+    match std::fs::read(&path).and_then(|state| gameboy.load_state(&state)) {
+        Ok(()) => log::info!("Loaded state from {}", path.display()),
+        Err(e) => log::error!("Failed to load state from {}: {}", path.display(), e),
+    }
+}
+
+// Pauses at the debugger prompt when needed, returns false once the user quits
+fn debugger_prompt(debugger: &mut Debugger, gameboy: &mut GameBoy) -> bool {
+    if debugger.is_paused() {
+        debugger
+            .prompt(
+                gameboy.cpu_mut(),
+                &mut io::stdin().lock(),
+                &mut io::stdout(),
+            )
+            .unwrap();
+    }
+    !debugger.quit_requested()
+}
+
+// Logs what led up to a fault and saves the machine next to the ROM, e.g. game.crash.ss, so
+// it can be reproduced with --load-state
+fn report_crash(gameboy: &GameBoy, rom: &Path, symbols: &Symbols, reason: &dyn std::fmt::Display) {
+    log::error!("{}", reason);
+    let history = &gameboy.cpu().history;
+    if !history.is_empty() {
+        let mut report = format!("Last {} instructions:", history.len());
+        for executed in history.iter() {
+            report.push_str(&format!("\n  {}", executed));
+            if let Some(name) = symbols.describe(executed.location()) {
+                report.push_str(&format!("  {}", name));
+            }
+        }
+        log::error!("{}", report);
+    }
+    let path = rom.with_extension("crash.ss");
+    match std::fs::write(&path, gameboy.save_state()) {
+        Ok(()) => log::error!("Saved the state at the crash to {}", path.display()),
+        Err(e) => log::error!("Failed to save state to {}: {}", path.display(), e),
+    }
+}
+
+// Runs some emulation, reporting a crash if anything in there panics
+fn catch_crash<T>(
+    gameboy: &mut GameBoy,
+    rom: &Path,
+    symbols: &Symbols,
+    f: impl FnOnce(&mut GameBoy) -> T,
+) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(gameboy))) {
+        Ok(value) => value,
+        Err(_) => {
+            report_crash(gameboy, rom, symbols, &"The emulator panicked");
+            exit(gameboy, 101);
+        }
+    }
+}
+
+// process::exit skips destructors, so the buffered trace has to be flushed by hand
+fn exit(gameboy: &mut GameBoy, code: i32) -> ! {
+    gameboy.cpu_mut().flush_trace();
+    std::process::exit(code);
+}
+
+fn open_serial_device(spec: &str) -> io::Result<Box<dyn SerialDevice>> {
+    if spec == "stdout" {
+        return Ok(Box::new(StdoutSerial));
+    }
+    if let Some(address) = spec.strip_prefix("tcp:") {
+        return Ok(Box::new(SocketSerial::connect_tcp(address)?));
+    }
+    #[cfg(unix)]
+    if let Some(path) = spec.strip_prefix("unix:") {
+        return Ok(Box::new(SocketSerial::connect_unix(path.as_ref())?));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Unknown serial device: {}", spec),
+    ))
+}
+
+fn open_link_cable(spec: &str, listen: bool) -> io::Result<LinkCable> {
+    if let Some(address) = spec.strip_prefix("tcp:") {
+        return if listen {
+            LinkCable::listen_tcp(address)
+        } else {
+            LinkCable::connect_tcp(address)
+        };
+    }
+    #[cfg(unix)]
+    if let Some(path) = spec.strip_prefix("unix:") {
+        return if listen {
+            LinkCable::listen_unix(path.as_ref())
+        } else {
+            LinkCable::connect_unix(path.as_ref())
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Unknown link cable address: {}", spec),
+    ))
+}
+
+fn main() {
     let args = Args::parse();
 
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    let mut cpu = CPU::new_bootrom(&args.path).unwrap();
+    if let Some(Command::Disasm {
+        path,
+        bank,
+        from,
+        to,
+    }) = &args.command
+    {
+        if let Err(e) = print_disassembly(path, *bank, *from, *to) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(dir) = &args.mooneye {
+        let results = mooneye::run_directory(dir, mooneye::DEFAULT_FRAME_LIMIT).unwrap();
+        print!("{}", mooneye::format_table(dir, &results));
+        let all_passed = results.iter().all(|(_, verdict)| verdict.is_pass());
+        std::process::exit(if all_passed { 0 } else { 1 });
+    }
+
+    // The boot ROM is exactly 256 bytes, anything else is a cartridge started past it
+    let is_bootrom = std::fs::metadata(&args.path).unwrap().len() == 0x100;
+    let mut gameboy = if is_bootrom {
+        GameBoy::load_boot_rom(&args.path).unwrap()
+    } else {
+        GameBoy::load_rom(&args.path).unwrap()
+    };
+    let symbols = load_symbols(&args.path);
+    let cpu = gameboy.cpu_mut();
     cpu.debug_mode = args.debug;
+    cpu.lock_up_on_illegal_opcode = args.lockup;
+    cpu.fault_on_stack_wrap = args.stack_fault;
+    if let Some(path) = &args.trace {
+        cpu.trace = Some(Box::new(BufWriter::new(File::create(path).unwrap())));
+        cpu.trace_symbols = args.trace_symbols.then(|| symbols.clone());
+    }
+    cpu.history = History::new(args.history);
+    if let Some(spec) = &args.serial {
+        cpu.bus.serial.connect(open_serial_device(spec).unwrap());
+    }
+    if let Some(spec) = &args.link_listen {
+        log::info!("Waiting for link cable on {}", spec);
+        cpu.bus.serial.connect_link(open_link_cable(spec, true).unwrap());
+    }
+    if let Some(spec) = &args.link_connect {
+        cpu.bus.serial.connect_link(open_link_cable(spec, false).unwrap());
+    }
+
+    if let Some(path) = &args.load_state {
+        let state = std::fs::read(path).unwrap();
+        gameboy.load_state(&state).unwrap();
+    }
+
+    if let Some(path) = &args.verify_movie {
+        let movie = Movie::load(path).unwrap();
+        match catch_crash(&mut gameboy, &args.path, &symbols, |gameboy| {
+            movie.verify(gameboy)
+        }) {
+            Ok(()) => {
+                log::info!(
+                    "{}: {} frames replayed, OK",
+                    path.display(),
+                    movie.inputs().len()
+                );
+                exit(&mut gameboy, 0);
+            }
+            Err(e) => {
+                log::error!("{}: {}", path.display(), e);
+                exit(&mut gameboy, 1);
+            }
+        }
+    }
+
+    let mut debugger = args.debugger.then(|| {
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(symbols.clone());
+        for location in &args.breakpoints {
+            if let Err(e) = debugger.add_breakpoint_at(location) {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        debugger
+    });
+
+    if let Some(debugger) = debugger.as_mut().filter(|_| args.headless) {
+        let mut frames = 0;
+        while frames < args.frames && debugger_prompt(debugger, &mut gameboy) {
+            let result = catch_crash(&mut gameboy, &args.path, &symbols, |gameboy| {
+                debugger.run_frame(gameboy.cpu_mut())
+            });
+            if let Err(e) = result {
+                log::error!("{}", e);
+            }
+            // A frame interrupted by a pause isn't finished yet
+            if !debugger.is_paused() {
+                frames += 1;
+            }
+        }
+        exit(&mut gameboy, 0);
+    }
+
+    if args.headless {
+        let options = RunOptions {
+            frames: args.frames,
+            until_serial: args.until_serial.clone(),
+            until_pc: args.until_pc,
+            until_loop: args.until_loop,
+        };
+        let result = catch_crash(&mut gameboy, &args.path, &symbols, |gameboy| {
+            headless::run(gameboy.cpu_mut(), &options)
+        });
+        // With --serial the device already got the output
+        if args.serial.is_none() {
+            print!("{}", result.serial_output);
+        }
+        if let Some(error) = &result.error {
+            report_crash(&gameboy, &args.path, &symbols, error);
+        }
+        log::info!(
+            "Stopped after {} frames: {:?} (PC: {:#06x})",
+            result.frames,
+            result.outcome,
+            gameboy.cpu().pc
+        );
+        exit(&mut gameboy, result.exit_code());
+    }
+
     let scale_factor = Scale::X4;
 
     let mut window = Window::new(
         "Game Boy Emulator",
-        160,
-        144,
+        SCREEN_WIDTH,
+        SCREEN_HEIGHT,
         WindowOptions {
             scale: scale_factor,
             ..WindowOptions::default()
@@ -55,31 +471,87 @@ fn main() {
     )
     .unwrap();
 
+    let mut rewind = Rewind::new(args.rewind_interval, args.rewind_budget << 20);
+    let mut recording = args.record_movie.as_ref().map(|_| Movie::record(&gameboy));
+    let mut playback = args.play_movie.as_ref().map(|path| {
+        let movie = Movie::load(path).unwrap();
+        movie.rewind_to_start(&mut gameboy).unwrap();
+        (movie, 0)
+    });
+    playback = playback.filter(|(movie, _)| !movie.inputs().is_empty());
+
     window.set_target_fps(60);
     while window.is_open() {
-        if args.step {
-            // In step mode, execute one instruction and wait for key press
-            cpu.step();
-            let framebuffer = cpu.bus.gpu.render_screen();
-            window.update_with_buffer(&framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
-            
-            if !window.is_open() {
-                break;
+        if let Some((movie, frame)) = &mut playback {
+            gameboy.set_buttons(movie.inputs()[*frame]);
+            *frame += 1;
+            if *frame == movie.inputs().len() {
+                log::info!("Movie finished after {} frames", frame);
+                playback = None;
             }
-            wait_for_keypress();
         } else {
-            // Normal mode - run at full speed
-            let mut executed_cycles: u32 = 0;
-            loop {
-                cpu.step();
-                executed_cycles += cpu.cycle_count as u32;
-                if executed_cycles >= 69905 {
-                    break;
+            for (key, button) in KEY_BINDINGS {
+                gameboy.set_button(button, window.is_key_down(key));
+            }
+        }
+        if let Some(movie) = &mut recording {
+            movie.record_frame(gameboy.buttons());
+        }
+
+        // Jumping around in time would desync a movie being recorded or played
+        let movie_active = recording.is_some() || playback.is_some();
+        let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+        for (index, key) in STATE_SLOT_KEYS.into_iter().enumerate() {
+            if window.is_key_pressed(key, KeyRepeat::No) && !movie_active {
+                if shift {
+                    save_state_slot(&gameboy, &args.path, index + 1);
+                } else {
+                    load_state_slot(&mut gameboy, &args.path, index + 1);
                 }
             }
+        }
+
+        if let Some(debugger) = &mut debugger {
+            // Errors leave the debugger paused at the faulting instruction
+            let result = catch_crash(&mut gameboy, &args.path, &symbols, |gameboy| {
+                debugger.run_frame(gameboy.cpu_mut())
+            });
+            if let Err(e) = result {
+                log::error!("{}", e);
+            }
+        } else if window.is_key_down(REWIND_KEY) && !movie_active {
+            rewind.rewind(&mut gameboy);
+        } else if let Err(e) = catch_crash(&mut gameboy, &args.path, &symbols, |gameboy| {
+            gameboy.run_frame().map(|()| rewind.record(gameboy))
+        }) {
+            report_crash(&gameboy, &args.path, &symbols, &e);
+            exit(&mut gameboy, 1);
+        }
+
+        let framebuffer = gameboy.framebuffer();
+        window
+            .update_with_buffer(&framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT)
+            .unwrap();
+        if args.debug && gameboy.cpu().bus.gpu.scy == 0 {
+            gameboy.cpu().bus.gpu.dump_vram().unwrap();
+        }
+
+        if let Some(debugger) = &mut debugger {
+            if !debugger_prompt(debugger, &mut gameboy) {
+                break;
+            }
+        }
+    }
 
-            let framebuffer = cpu.bus.gpu.render_screen();
-            window.update_with_buffer(&framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+    if let (Some(mut movie), Some(path)) = (recording, &args.record_movie) {
+        movie.finish(&mut gameboy);
+        match movie.save(path) {
+            Ok(()) => log::info!(
+                "Recorded {} frames to {}",
+                movie.inputs().len(),
+                path.display()
+            ),
+            Err(e) => log::error!("Failed to write {}: {}", path.display(), e),
         }
     }
 }
@@ -0,0 +1,127 @@
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Mooneye tests finish by executing LD B,B with these values in B, C, D, E, H and L.
+// A failing test loads 0x42 into all of them instead.
+const PASS_PATTERN: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAIL_PATTERN: [u8; 6] = [0x42; 6];
+
+/// How long a test may run before it is considered stuck, about 10 seconds of emulated time.
+pub const DEFAULT_FRAME_LIMIT: u32 = 600;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Passed,
+    Failed,
+    /// LD B,B was hit with a register pattern that is neither pass nor fail
+    UnexpectedRegisters([u8; 6]),
+    TimedOut,
+    /// The CPU stopped with an error, usually an illegal opcode
+    Crashed(String),
+}
+
+impl Verdict {
+    pub fn is_pass(&self) -> bool {
+        *self == Verdict::Passed
+    }
+}
+
+/// Reads the result of a test from the registers, called once the breakpoint was hit.
+pub fn verdict(cpu: &CPU) -> Verdict {
+    let registers = [
+        cpu.registers.b,
+        cpu.registers.c,
+        cpu.registers.d,
+        cpu.registers.e,
+        cpu.registers.h,
+        cpu.registers.l,
+    ];
+    if registers == PASS_PATTERN {
+        Verdict::Passed
+    } else if registers == FAIL_PATTERN {
+        Verdict::Failed
+    } else {
+        Verdict::UnexpectedRegisters(registers)
+    }
+}
+
+/// Steps `cpu` until it hits the LD B,B breakpoint or `frame_limit` frames have passed.
+pub fn run_until_breakpoint(cpu: &mut CPU, frame_limit: u32) -> Verdict {
+    for _ in 0..frame_limit {
+        let mut executed_cycles: u32 = 0;
+        while executed_cycles < CYCLES_PER_FRAME {
+            if let Err(e) = cpu.step() {
+                return Verdict::Crashed(e.to_string());
+            }
+            executed_cycles += cpu.cycle_count as u32;
+            if cpu.take_breakpoint() {
+                return verdict(cpu);
+            }
+        }
+    }
+    Verdict::TimedOut
+}
+
+pub fn run_rom(path: &Path, frame_limit: u32) -> Verdict {
+    let mut cpu = match CPU::new_with_rom(path) {
+        Ok(cpu) => cpu,
+        Err(e) => return Verdict::Crashed(e.to_string()),
+    };
+    run_until_breakpoint(&mut cpu, frame_limit)
+}
+
+fn collect_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_roms(&path, roms)?;
+        } else if path.extension().is_some_and(|extension| extension == "gb") {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Runs every `.gb` file under `dir`, including subdirectories, in path order.
+pub fn run_directory(dir: &Path, frame_limit: u32) -> io::Result<Vec<(PathBuf, Verdict)>> {
+    let mut roms = Vec::new();
+    collect_roms(dir, &mut roms)?;
+    roms.sort();
+    Ok(roms
+        .into_iter()
+        .map(|rom| {
+            let verdict = run_rom(&rom, frame_limit);
+            (rom, verdict)
+        })
+        .collect())
+}
+
+pub fn format_table(dir: &Path, results: &[(PathBuf, Verdict)]) -> String {
+    let names: Vec<String> = results
+        .iter()
+        .map(|(path, _)| path.strip_prefix(dir).unwrap_or(path).display().to_string())
+        .collect();
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+
+    let mut table = String::new();
+    for (name, (_, verdict)) in names.iter().zip(results) {
+        let status = match verdict {
+            Verdict::Passed => "pass".to_string(),
+            Verdict::Failed => "FAIL".to_string(),
+            Verdict::UnexpectedRegisters(registers) => {
+                format!("FAIL (registers {:02X?})", registers)
+            }
+            Verdict::TimedOut => "FAIL (timed out)".to_string(),
+            Verdict::Crashed(message) => format!("FAIL (crashed: {})", message),
+        };
+        table.push_str(&format!("{:width$}  {}\n", name, status, width = width));
+    }
+    let passed = results
+        .iter()
+        .filter(|(_, verdict)| verdict.is_pass())
+        .count();
+    table.push_str(&format!("{}/{} passed\n", passed, results.len()));
+    table
+}
@@ -0,0 +1,189 @@
+use crate::cpu::CpuError;
+use crate::state::{StateReader, StateWriter};
+use crate::GameBoy;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+const MOVIE_MAGIC: &[u8; 4] = b"RMLM";
+// 2 added the machine state to the final hash
+const MOVIE_VERSION: u16 = 2;
+
+/// Recorded joypad input, one button mask per frame, starting from a save state.
+///
+/// Replaying the inputs from the same state on the same ROM reproduces the run exactly, and
+/// a hash of where the run ends lets a movie check that it still does.
+pub struct Movie {
+    rom_checksum: u32,
+    start_state: Vec<u8>,
+    inputs: Vec<u8>,
+    final_hash: u64,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Rejected(io::Error),
+    Crashed { frame: usize, error: CpuError },
+    Desynced { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Rejected(error) => write!(f, "Can't play the movie: {}", error),
+            VerifyError::Crashed { frame, error } => {
+                write!(f, "Crashed on frame {}: {}", frame, error)
+            }
+            VerifyError::Desynced { expected, actual } => write!(
+                f,
+                "Final hash is {:016x}, expected {:016x}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+fn invalid_movie(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// FNV-1a
+fn hash_bytes(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+    hash
+}
+
+/// FNV-1a over the pixels, enough to tell two frames apart.
+pub fn hash_framebuffer(framebuffer: &[u32]) -> u64 {
+    hash_bytes(framebuffer.iter().flat_map(|pixel| pixel.to_le_bytes()))
+}
+
+/// Hashes the screen together with the whole machine state. Runs that diverge often draw
+/// the same frame for a while, but rarely end up with the same memory and registers.
+pub fn hash_machine(gameboy: &mut GameBoy) -> u64 {
+    let framebuffer = gameboy.framebuffer();
+    let pixels = framebuffer.iter().flat_map(|pixel| pixel.to_le_bytes());
+    hash_bytes(pixels.chain(gameboy.save_state()))
+}
+
+impl Movie {
+    /// Starts recording from the current state of `gameboy`.
+    pub fn record(gameboy: &GameBoy) -> Self {
+        Movie {
+            rom_checksum: gameboy.rom_checksum(),
+            start_state: gameboy.save_state(),
+            inputs: Vec::new(),
+            final_hash: 0,
+        }
+    }
+
+    /// Call right before running every frame, with the buttons held for it.
+    pub fn record_frame(&mut self, buttons: u8) {
+        self.inputs.push(buttons);
+    }
+
+    /// Stops recording and remembers the frame and state the movie ends on.
+    pub fn finish(&mut self, gameboy: &mut GameBoy) {
+        self.final_hash = hash_machine(gameboy);
+    }
+
+    pub fn inputs(&self) -> &[u8] {
+        &self.inputs
+    }
+
+    /// For editing a recording. The final hash stays as recorded, so an edit that changes
+    /// the outcome makes `verify` report a desync.
+    pub fn inputs_mut(&mut self) -> &mut [u8] {
+        &mut self.inputs
+    }
+
+    pub fn final_hash(&self) -> u64 {
+        self.final_hash
+    }
+
+    /// Puts `gameboy` back in the state the recording started from.
+    pub fn rewind_to_start(&self, gameboy: &mut GameBoy) -> io::Result<()> {
+        if gameboy.rom_checksum() != self.rom_checksum {
+            return Err(invalid_movie(format!(
+                "Movie was recorded with a different ROM (checksum {:08x}, loaded {:08x})",
+                self.rom_checksum,
+                gameboy.rom_checksum()
+            )));
+        }
+        gameboy.load_state(&self.start_state)
+    }
+
+    /// Replays the whole movie as fast as possible and checks it ends on the recorded frame
+    /// and state.
+    pub fn verify(&self, gameboy: &mut GameBoy) -> Result<(), VerifyError> {
+        self.rewind_to_start(gameboy).map_err(VerifyError::Rejected)?;
+        for (frame, &buttons) in self.inputs.iter().enumerate() {
+            gameboy.set_buttons(buttons);
+            gameboy
+                .run_frame()
+                .map_err(|error| VerifyError::Crashed { frame, error })?;
+        }
+        let actual = hash_machine(gameboy);
+        if actual != self.final_hash {
+            return Err(VerifyError::Desynced {
+                expected: self.final_hash,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for &byte in MOVIE_MAGIC {
+            writer.u8(byte);
+        }
+        writer.u16(MOVIE_VERSION);
+        writer.u32(self.rom_checksum);
+        writer.bytes(&self.start_state);
+        writer.bytes(&self.inputs);
+        writer.u64(self.final_hash);
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = reader.u8()?;
+        }
+        if &magic != MOVIE_MAGIC {
+            return Err(invalid_movie("Not a movie".to_string()));
+        }
+        let version = reader.u16()?;
+        if version != MOVIE_VERSION {
+            return Err(invalid_movie(format!(
+                "Movie version {} is not supported, expected {}",
+                version, MOVIE_VERSION
+            )));
+        }
+        let rom_checksum = reader.u32()?;
+        let movie = Movie {
+            rom_checksum,
+            start_state: reader.bytes()?,
+            inputs: reader.bytes()?,
+            final_hash: reader.u64()?,
+        };
+        reader.finish()?;
+        Ok(movie)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Movie::from_bytes(&std::fs::read(path)?)
+    }
+}
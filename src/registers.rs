@@ -3,6 +3,7 @@ const SUBTRACT_FLAG_BYTE_POSITION: u8 = 6;
 const HALF_CARRY_FLAG_BYTE_POSITION: u8 = 5;
 const CARRY_FLAG_BYTE_POSITION: u8 = 4;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct FlagsRegister {
     pub zero: bool,
     pub subtract: bool,
@@ -10,7 +11,7 @@ pub struct FlagsRegister {
     pub carry: bool,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum JumpCondition {
     Always,
     Zero,
@@ -19,6 +20,7 @@ pub enum JumpCondition {
     NotCarry,
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -30,7 +32,7 @@ pub struct Registers {
     pub l: u8,
     pub ime: bool,
 }
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ArithmeticTarget {
     A,
     B,
@@ -41,18 +43,46 @@ pub enum ArithmeticTarget {
     L,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Target {
     Register(ArithmeticTarget),
     Register16(DoubleTarget),
-    MemoryR8(ArithmeticTarget),
     MemoryR16(DoubleTarget),
-    Const8(),
-    Const16(),
-    MemoryConst16(),
+    Const8(u8),
+    Const16(u16),
+    MemoryConst16(u16),
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Target {
+    /// Number of operand bytes this target takes after the opcode.
+    pub fn immediate_size(&self) -> u16 {
+        match self {
+            Target::Const8(_) => 1,
+            Target::Const16(_) | Target::MemoryConst16(_) => 2,
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn immediate(&self) -> Option<u16> {
+        match *self {
+            Target::Const8(value) => Some(value as u16),
+            Target::Const16(value) | Target::MemoryConst16(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Puts the decoded immediate into a placeholder operand
+    pub(crate) fn with_immediate(self, immediate: u16) -> Target {
+        match self {
+            Target::Const8(_) => Target::Const8(immediate as u8),
+            Target::Const16(_) => Target::Const16(immediate),
+            Target::MemoryConst16(_) => Target::MemoryConst16(immediate),
+            other => other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DoubleTarget {
     BC,
     DE,
@@ -60,11 +90,10 @@ pub enum DoubleTarget {
     SP,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LDHRegister {
     C,
-    MemA8,
-    MemoryConst16(DoubleTarget),
+    MemA8(u8),
     ArithmeticTarget,
 }
 
@@ -0,0 +1,154 @@
+use crate::GameBoy;
+use std::collections::VecDeque;
+
+/// Ring buffer of past machine states for scrubbing back in time.
+///
+/// Only the newest snapshot is kept whole. Every older one is stored as the run length
+/// encoded XOR against the snapshot that followed it, which is mostly zeros since little
+/// changes between a few frames.
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    frames_since_capture: u32,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // Oldest first
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    /// Captures a snapshot every `interval` frames, dropping the oldest ones once they take
+    /// up more than `budget` bytes.
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames_since_capture: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Call once after every emulated frame.
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        self.frames_since_capture += 1;
+        if self.frames_since_capture < self.interval {
+            return;
+        }
+        self.frames_since_capture = 0;
+        self.push(gameboy.save_state());
+    }
+
+    fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            // The layout never changes while running one ROM, but start over rather than
+            // build a chain that can't be decoded
+            if previous.len() == state.len() {
+                let delta = encode_delta(&previous, &state);
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+        self.newest = Some(state);
+
+        while self.memory_used() > self.budget {
+            let Some(oldest) = self.deltas.pop_front() else {
+                break;
+            };
+            self.delta_bytes -= oldest.len();
+        }
+    }
+
+    /// Loads the newest snapshot and makes the one before it the next to load. Returns false
+    /// when there is nothing to go back to. The oldest snapshot is never discarded, so
+    /// holding rewind stops there.
+    pub fn rewind(&mut self, gameboy: &mut GameBoy) -> bool {
+        let Some(newest) = &mut self.newest else {
+            return false;
+        };
+        if gameboy.load_state(newest).is_err() {
+            self.clear();
+            return false;
+        }
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            apply_delta(newest, &delta);
+        }
+        self.frames_since_capture = 0;
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    /// Number of snapshots that can still be rewound to.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.delta_bytes
+    }
+}
+
+// The delta is a sequence of (zero run, literal count, literal bytes) with the counts as
+// little endian u16s. XORing it into `newer` gives back `older`.
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut position = 0;
+    while position < older.len() {
+        let zeros_start = position;
+        while position < older.len()
+            && older[position] == newer[position]
+            && position - zeros_start < u16::MAX as usize
+        {
+            position += 1;
+        }
+        let zeros = position - zeros_start;
+
+        let literals_start = position;
+        while position < older.len()
+            && older[position] != newer[position]
+            && position - literals_start < u16::MAX as usize
+        {
+            position += 1;
+        }
+
+        output.extend_from_slice(&(zeros as u16).to_le_bytes());
+        output.extend_from_slice(&((position - literals_start) as u16).to_le_bytes());
+        output.extend(
+            older[literals_start..position]
+                .iter()
+                .zip(&newer[literals_start..position])
+                .map(|(older, newer)| older ^ newer),
+        );
+    }
+    output
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut position = 0;
+    let mut input = delta;
+    while input.len() >= 4 {
+        let zeros = u16::from_le_bytes([input[0], input[1]]) as usize;
+        let literals = u16::from_le_bytes([input[2], input[3]]) as usize;
+        position += zeros;
+        for (byte, xor) in state[position..position + literals]
+            .iter_mut()
+            .zip(&input[4..4 + literals])
+        {
+            *byte ^= xor;
+        }
+        position += literals;
+        input = &input[4 + literals..];
+    }
+}
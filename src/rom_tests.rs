@@ -0,0 +1,168 @@
+#[cfg(test)]
+mod blargg {
+    use crate::cpu::{CPU, CYCLES_PER_FRAME};
+    use crate::serial::BufferSerial;
+    use std::path::Path;
+
+    // Newer blargg ROMs also report through cartridge RAM: a status byte at 0xA000 (0x80
+    // while the test is still running) and the same text as on serial from 0xA004, once
+    // the signature below has been written
+    const STATUS_ADDRESS: u16 = 0xA000;
+    const SIGNATURE_ADDRESS: u16 = 0xA001;
+    const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+    const TEXT_ADDRESS: u16 = 0xA004;
+    const STATUS_RUNNING: u8 = 0x80;
+
+    struct BlarggResult {
+        serial: String,
+        // Status and text from the memory protocol, if the ROM uses it
+        memory: Option<(u8, String)>,
+        cycles: u64,
+    }
+
+    impl BlarggResult {
+        fn passed(&self) -> bool {
+            match &self.memory {
+                Some((status, _)) => *status == 0,
+                None => self.serial.contains("Passed"),
+            }
+        }
+
+        fn output(&self) -> &str {
+            match &self.memory {
+                Some((_, text)) if !text.is_empty() => text,
+                _ => &self.serial,
+            }
+        }
+    }
+
+    fn read_memory_result(cpu: &mut CPU) -> Option<(u8, String)> {
+        let signature: Vec<u8> = (0..3)
+            .map(|offset| cpu.bus.read_byte(SIGNATURE_ADDRESS + offset))
+            .collect();
+        if signature != SIGNATURE {
+            return None;
+        }
+        let status = cpu.bus.read_byte(STATUS_ADDRESS);
+        if status == STATUS_RUNNING {
+            return None;
+        }
+
+        let mut text = Vec::new();
+        let mut address = TEXT_ADDRESS;
+        while address < 0xC000 {
+            let byte = cpu.bus.read_byte(address);
+            if byte == 0 {
+                break;
+            }
+            text.push(byte);
+            address += 1;
+        }
+        Some((status, String::from_utf8_lossy(&text).into_owned()))
+    }
+
+    /// Runs a ROM until it reports a result or `cycle_budget` M-cycles have passed.
+    fn run_blargg(path: &str, cycle_budget: u64) -> BlarggResult {
+        let mut cpu = CPU::new_with_rom(Path::new(path))
+            .unwrap_or_else(|e| panic!("Failed to load {}: {}", path, e));
+        let serial = BufferSerial::new();
+        cpu.bus.serial.connect(Box::new(serial.clone()));
+
+        let mut cycles: u64 = 0;
+        let mut memory = None;
+        while cycles < cycle_budget {
+            let mut executed_cycles: u32 = 0;
+            while executed_cycles < CYCLES_PER_FRAME {
+                cpu.step().unwrap();
+                executed_cycles += cpu.cycle_count as u32;
+            }
+            cycles += executed_cycles as u64;
+
+            memory = read_memory_result(&mut cpu);
+            let text = serial.text();
+            if memory.is_some() || text.contains("Passed") || text.contains("Failed") {
+                break;
+            }
+        }
+
+        let result = BlarggResult {
+            serial: serial.text(),
+            memory,
+            cycles,
+        };
+        println!(
+            "{}: {} after {} cycles",
+            path,
+            if result.passed() { "passed" } else { "FAILED" },
+            result.cycles
+        );
+        result
+    }
+
+    fn assert_passed(path: &str, cycle_budget: u64) {
+        let result = run_blargg(path, cycle_budget);
+        assert!(
+            result.passed(),
+            "{} did not pass within {} cycles, output:\n{}",
+            path,
+            cycle_budget,
+            result.output()
+        );
+    }
+
+    #[test]
+    fn cpu_instrs() {
+        assert_passed("roms/cpu_instrs.gb", 60_000_000);
+    }
+
+    #[test]
+    fn special() {
+        assert_passed("roms/01-special.gb", 5_000_000);
+    }
+
+    #[test]
+    fn op_a_hl() {
+        assert_passed("roms/11-op a,(hl).gb", 20_000_000);
+    }
+
+    // The timing ROMs aren't shipped with the repo. Drop them into roms/ and run these with
+    // `cargo test -- --ignored`. A missing ROM fails the test rather than passing it. Neither
+    // has been run against the per-access bus timing yet, so a failure here is news.
+    #[test]
+    #[ignore = "roms/instr_timing.gb is not in the repo"]
+    fn instr_timing() {
+        assert_passed("roms/instr_timing.gb", 10_000_000);
+    }
+
+    #[test]
+    #[ignore = "roms/mem_timing.gb is not in the repo"]
+    fn mem_timing() {
+        assert_passed("roms/mem_timing.gb", 10_000_000);
+    }
+}
+
+#[cfg(test)]
+mod mooneye {
+    use crate::mooneye::*;
+    use std::path::Path;
+
+    // The suite isn't shipped with the repo, drop the acceptance ROMs in here and run them
+    // with `cargo test -- --ignored`. A missing directory fails the test.
+    const SUITE_DIR: &str = "roms/mooneye";
+
+    #[test]
+    #[ignore = "roms/mooneye is not in the repo"]
+    fn acceptance() {
+        let dir = Path::new(SUITE_DIR);
+        let results = run_directory(dir, DEFAULT_FRAME_LIMIT)
+            .unwrap_or_else(|e| panic!("Failed to run {}: {}", SUITE_DIR, e));
+        assert!(!results.is_empty(), "No ROMs found in {}", SUITE_DIR);
+        let table = format_table(dir, &results);
+        println!("{}", table);
+        assert!(
+            results.iter().all(|(_, verdict)| verdict.is_pass()),
+            "Some mooneye tests failed:\n{}",
+            table
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::state::{StateReader, StateWriter};
+use std::io;
+
+/// Something a peripheral needs to do at a known point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The LCD finishes a scanline and LY changes
+    LineEnd,
+    /// TIMA overflows and requests the timer interrupt
+    TimerOverflow,
+    /// A serial transfer finishes shifting, or the link cable reaches a sync point
+    Serial,
+    /// The last byte of an OAM DMA has been copied
+    DmaEnd,
+}
+
+impl Event {
+    pub const ALL: [Event; 4] = [
+        Event::LineEnd,
+        Event::TimerOverflow,
+        Event::Serial,
+        Event::DmaEnd,
+    ];
+}
+
+const NEVER: u64 = u64::MAX;
+
+/// Keeps one deadline per kind of event, so peripherals don't have to be stepped on every
+/// M-cycle. Timestamps count M-cycles since power on.
+pub struct Scheduler {
+    now: u64,
+    deadlines: [u64; Event::ALL.len()],
+    // Earliest of the deadlines, checked on every tick
+    next: u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            now: 0,
+            deadlines: [NEVER; Event::ALL.len()],
+            next: NEVER,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Moves time forward. Returns true when an event is due.
+    #[inline(always)]
+    pub fn advance(&mut self, cycles: u16) -> bool {
+        self.now += cycles as u64;
+        self.now >= self.next
+    }
+
+    /// Schedules `event` `cycles` M-cycles from now, replacing any earlier deadline for it.
+    pub fn schedule(&mut self, event: Event, cycles: u64) {
+        self.deadlines[event as usize] = self.now + cycles;
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.deadlines[event as usize] = NEVER;
+        self.update_next();
+    }
+
+    pub fn deadline(&self, event: Event) -> Option<u64> {
+        let deadline = self.deadlines[event as usize];
+        (deadline != NEVER).then_some(deadline)
+    }
+
+    /// M-cycles left until the next event, if any is scheduled.
+    pub fn cycles_until_next(&self) -> Option<u64> {
+        (self.next != NEVER).then(|| self.next.saturating_sub(self.now))
+    }
+
+    /// Takes the earliest event that is due, events due at the same time come out in the
+    /// order of `Event::ALL`.
+    pub fn pop_due(&mut self) -> Option<Event> {
+        if self.now < self.next {
+            return None;
+        }
+        let event = Event::ALL
+            .into_iter()
+            .filter(|&event| self.deadlines[event as usize] <= self.now)
+            .min_by_key(|&event| self.deadlines[event as usize])?;
+        self.cancel(event);
+        Some(event)
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.deadlines.iter().copied().min().unwrap_or(NEVER);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u64(self.now);
+        for deadline in self.deadlines {
+            writer.u64(deadline);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.now = reader.u64()?;
+        for deadline in &mut self.deadlines {
+            *deadline = reader.u64()?;
+        }
+        self.update_next();
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use crate::state::{StateReader, StateWriter};
+use crate::link::{LinkCable, LinkMessage};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+
+// The internal clock runs at 8192 Hz, so one bit takes 512 T-cycles (128 M-cycles)
+const CYCLES_PER_BIT: u16 = 128;
+
+const SC_TRANSFER_START: u8 = 0x80;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+
+/// Whatever is plugged into the link port.
+pub trait SerialDevice {
+    /// Exchanges one byte with the other side of the cable. `byte` is the value shifted
+    /// out of SB, the returned value ends up in SB once the transfer completes.
+    fn transfer(&mut self, byte: u8) -> u8;
+}
+
+/// Nothing connected, the input line is pulled high.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+/// Prints every transferred byte to stdout, handy for test ROMs.
+pub struct StdoutSerial;
+
+impl SerialDevice for StdoutSerial {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+        0xFF
+    }
+}
+
+/// Collects transferred bytes into a shared buffer that can be inspected from outside the bus.
+#[derive(Clone, Default)]
+pub struct BufferSerial {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferSerial {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).into_owned()
+    }
+}
+
+impl SerialDevice for BufferSerial {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.buffer.borrow_mut().push(byte);
+        0xFF
+    }
+}
+
+/// Copies everything sent to another device into a `BufferSerial`, so output can be
+/// captured without unplugging what the user connected. The device is shared so whoever set
+/// up the tee can plug it back in afterwards.
+pub struct TeeSerial {
+    capture: BufferSerial,
+    device: Rc<RefCell<Box<dyn SerialDevice>>>,
+}
+
+impl TeeSerial {
+    pub fn new(capture: BufferSerial, device: Rc<RefCell<Box<dyn SerialDevice>>>) -> Self {
+        TeeSerial { capture, device }
+    }
+}
+
+impl SerialDevice for TeeSerial {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.capture.transfer(byte);
+        self.device.borrow_mut().transfer(byte)
+    }
+}
+
+/// Another emulator on the end of a socket. Each transfer sends our byte and blocks until
+/// the peer answers with its own.
+pub struct SocketSerial<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> SocketSerial<S> {
+    pub fn new(stream: S) -> Self {
+        SocketSerial { stream }
+    }
+}
+
+impl SocketSerial<TcpStream> {
+    pub fn connect_tcp(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(SocketSerial::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl SocketSerial<std::os::unix::net::UnixStream> {
+    pub fn connect_unix(path: &std::path::Path) -> io::Result<Self> {
+        Ok(SocketSerial::new(std::os::unix::net::UnixStream::connect(
+            path,
+        )?))
+    }
+}
+
+impl<S: Read + Write> SerialDevice for SocketSerial<S> {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut incoming = [0xFF];
+        if self.stream.write_all(&[byte]).is_err() || self.stream.read_exact(&mut incoming).is_err()
+        {
+            log::warn!("Serial peer disconnected");
+            return 0xFF;
+        }
+        incoming[0]
+    }
+}
+
+pub struct Serial {
+    pub sb: u8, // Serial transfer data
+    pub sc: u8, // Serial transfer control
+    outgoing: u8,
+    bits_remaining: u8,
+    bit_clock: u16,
+    device: Box<dyn SerialDevice>,
+    link: Option<LinkCable>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            outgoing: 0,
+            bits_remaining: 0,
+            bit_clock: 0,
+            device: Box::new(Disconnected),
+            link: None,
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    /// Unplugs the serial device and returns it.
+    pub fn disconnect(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, Box::new(Disconnected))
+    }
+
+    /// Plugs in a cable to another emulator. While connected, transfers are resolved at the
+    /// cable's sync points instead of going to the serial device.
+    pub fn connect_link(&mut self, link: LinkCable) {
+        self.link = Some(link);
+    }
+
+    /// Reads SB or SC at 0xFF01-0xFF02.
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF01 => self.sb,
+            _ => self.read_sc(),
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF01 => self.sb = value,
+            _ => self.write_sc(value),
+        }
+    }
+
+    pub fn read_sc(&self) -> u8 {
+        // Bits 1-6 are unused and always read back as 1
+        self.sc | 0x7E
+    }
+
+    pub fn write_sc(&mut self, value: u8) {
+        self.sc = value;
+        if value & SC_TRANSFER_START != 0 {
+            self.outgoing = self.sb;
+            self.bits_remaining = 8;
+            self.bit_clock = 0;
+        } else {
+            self.bits_remaining = 0;
+        }
+    }
+
+    /// Advances the transfer by `cycles` M-cycles. Returns true when a transfer has just
+    /// finished and the serial interrupt should be requested.
+    pub fn step(&mut self, cycles: u16) -> bool {
+        if self.bits_remaining > 0 && self.sc & SC_INTERNAL_CLOCK != 0 {
+            self.bit_clock += cycles;
+            while self.bit_clock >= CYCLES_PER_BIT && self.bits_remaining > 0 {
+                self.bit_clock -= CYCLES_PER_BIT;
+                self.bits_remaining -= 1;
+                self.sb = (self.sb << 1) | 1;
+            }
+        }
+
+        if self.link.is_some() {
+            return self.step_link(cycles);
+        }
+
+        // Without a clock coming from the other side an external clock transfer never ends
+        if !self.clocked_out() {
+            return false;
+        }
+
+        self.sb = self.device.transfer(self.outgoing);
+        self.sc &= !SC_TRANSFER_START;
+        true
+    }
+
+    // The device and link cable are part of the setup rather than the state
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.sb);
+        writer.u8(self.sc);
+        writer.u8(self.outgoing);
+        writer.u8(self.bits_remaining);
+        writer.u16(self.bit_clock);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.sb = reader.u8()?;
+        self.sc = reader.u8()?;
+        self.outgoing = reader.u8()?;
+        self.bits_remaining = reader.u8()?;
+        self.bit_clock = reader.u16()?;
+        Ok(())
+    }
+
+    pub fn transfer_active(&self) -> bool {
+        self.sc & SC_TRANSFER_START != 0
+    }
+
+    /// M-cycles until `step` has something to do: the end of an internal clock transfer or
+    /// the next sync point of the link cable.
+    pub fn cycles_until_event(&self) -> Option<u64> {
+        if let Some(link) = &self.link {
+            return Some(link.cycles_until_sync() as u64);
+        }
+        if !self.transfer_active() || self.sc & SC_INTERNAL_CLOCK == 0 {
+            return None;
+        }
+        let total = self.bits_remaining as u64 * CYCLES_PER_BIT as u64;
+        Some(total.saturating_sub(self.bit_clock as u64))
+    }
+
+    /// An internal clock transfer has shifted out all 8 bits.
+    fn clocked_out(&self) -> bool {
+        self.transfer_active() && self.sc & SC_INTERNAL_CLOCK != 0 && self.bits_remaining == 0
+    }
+
+    fn step_link(&mut self, cycles: u16) -> bool {
+        let clocked = self.clocked_out();
+        let waiting = self.transfer_active() && self.sc & SC_INTERNAL_CLOCK == 0;
+        let message = LinkMessage {
+            clocked,
+            waiting,
+            data: if clocked { self.outgoing } else { self.sb },
+        };
+
+        let link = self.link.as_mut().unwrap();
+        if !link.advance(cycles) {
+            return false;
+        }
+        let peer = match link.exchange(message) {
+            Ok(peer) => peer,
+            Err(e) => {
+                log::warn!("Link cable disconnected: {}", e);
+                self.link = None;
+                return false;
+            }
+        };
+
+        if clocked {
+            // Only a peer armed for an external clock shifts its byte back to us
+            self.sb = if peer.waiting { peer.data } else { 0xFF };
+        } else if waiting && peer.clocked {
+            self.sb = peer.data;
+        } else {
+            return false;
+        }
+        self.sc &= !SC_TRANSFER_START;
+        true
+    }
+}
@@ -0,0 +1,284 @@
+#[cfg(test)]
+mod sm83 {
+    use crate::bus::BusAccess;
+    use crate::cpu::*;
+    use crate::registers::FlagsRegister;
+    use crate::watchpoint::Access;
+    use serde::Deserialize;
+    use std::path::Path;
+
+    // Point this at a checkout of the SingleStepTests sm83 `v1` directory to run the tests
+    const TESTS_DIR_VARIABLE: &str = "SM83_TESTS_DIR";
+
+    // Only the first few mismatches of every file are printed, the rest are just counted
+    const REPORTED_FAILURES: usize = 5;
+
+    #[derive(Debug, Deserialize)]
+    struct State {
+        pc: u16,
+        sp: u16,
+        a: u8,
+        b: u8,
+        c: u8,
+        d: u8,
+        e: u8,
+        f: u8,
+        h: u8,
+        l: u8,
+        #[serde(default)]
+        ime: u8,
+        ie: Option<u8>,
+        ram: Vec<(u16, u8)>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct TestCase {
+        name: String,
+        initial: State,
+        #[serde(rename = "final")]
+        expected: State,
+        // One entry per M-cycle: the address, the value if one was on the data bus and pins
+        // like "r-m" for a read or "-wm" for a write. Internal cycles may be null.
+        cycles: Vec<Option<(u16, Option<u8>, String)>>,
+    }
+
+    // What the bus did in one M-cycle
+    fn describe_cycle(access: Option<(Access, u16, u8)>) -> String {
+        match access {
+            Some((Access::Read, address, value)) => {
+                format!("read {:#06x} = {:#04x}", address, value)
+            }
+            Some((Access::Write, address, value)) => {
+                format!("write {:#06x} = {:#04x}", address, value)
+            }
+            None => "no access".to_string(),
+        }
+    }
+
+    // The address on the bus during internal cycles isn't compared
+    fn expected_cycle(cycle: &Option<(u16, Option<u8>, String)>) -> Option<(Access, u16, u8)> {
+        let (address, value, pins) = cycle.as_ref()?;
+        let access = if pins.starts_with('r') {
+            Access::Read
+        } else if pins.get(1..2) == Some("w") {
+            Access::Write
+        } else {
+            return None;
+        };
+        Some((access, *address, value.unwrap_or(0)))
+    }
+
+    /// Compares the accesses made during the step against the test's cycles, reporting the
+    /// first one that differs.
+    fn compare_cycles(
+        accesses: &[BusAccess],
+        start: u64,
+        cycles: usize,
+        expected: &[Option<(u16, Option<u8>, String)>],
+        mismatches: &mut Vec<String>,
+    ) {
+        if cycles != expected.len() {
+            mismatches.push(format!(
+                "cycles: expected {}, got {}",
+                expected.len(),
+                cycles
+            ));
+        }
+        // Accesses happen at the end of the M-cycle they were ticked for
+        let actual = |index: usize| {
+            accesses
+                .iter()
+                .find(|access| access.cycle == start + index as u64 + 1)
+                .map(|access| (access.access, access.address, access.value))
+        };
+        for index in 0..cycles.max(expected.len()) {
+            let expected = expected.get(index).and_then(expected_cycle);
+            let actual = actual(index);
+            if expected != actual {
+                mismatches.push(format!(
+                    "cycle {}: expected {}, got {}",
+                    index + 1,
+                    describe_cycle(expected),
+                    describe_cycle(actual)
+                ));
+                return;
+            }
+        }
+    }
+
+    fn load_state(cpu: &mut CPU, state: &State) {
+        cpu.pc = state.pc;
+        cpu.sp = state.sp;
+        cpu.registers.a = state.a;
+        cpu.registers.b = state.b;
+        cpu.registers.c = state.c;
+        cpu.registers.d = state.d;
+        cpu.registers.e = state.e;
+        cpu.registers.f = FlagsRegister::from(state.f);
+        cpu.registers.h = state.h;
+        cpu.registers.l = state.l;
+        cpu.registers.ime = state.ime != 0;
+        if let Some(ie) = state.ie {
+            cpu.bus.write_byte(INTERRUPT_ENABLE_ADDRESS, ie);
+        }
+        for &(address, value) in &state.ram {
+            cpu.bus.write_byte(address, value);
+        }
+    }
+
+    fn compare_state(cpu: &CPU, expected: &State, mismatches: &mut Vec<String>) {
+        let mut check = |name: &str, expected: u16, actual: u16| {
+            if expected != actual {
+                mismatches.push(format!(
+                    "{}: expected {:#06x}, got {:#06x}",
+                    name, expected, actual
+                ));
+            }
+        };
+        check("PC", expected.pc, cpu.pc);
+        check("SP", expected.sp, cpu.sp);
+        check("A", expected.a as u16, cpu.registers.a as u16);
+        check("B", expected.b as u16, cpu.registers.b as u16);
+        check("C", expected.c as u16, cpu.registers.c as u16);
+        check("D", expected.d as u16, cpu.registers.d as u16);
+        check("E", expected.e as u16, cpu.registers.e as u16);
+        check("F", expected.f as u16, u8::from(cpu.registers.f) as u16);
+        check("H", expected.h as u16, cpu.registers.h as u16);
+        check("L", expected.l as u16, cpu.registers.l as u16);
+        check("IME", expected.ime as u16, cpu.registers.ime as u16);
+        for &(address, value) in &expected.ram {
+            check(
+                &format!("[{:#06x}]", address),
+                value as u16,
+                cpu.bus.read_byte(address) as u16,
+            );
+        }
+    }
+
+    /// Runs a single test case and returns everything that didn't match.
+    fn run_case(case: &TestCase) -> Vec<String> {
+        let mut cpu = CPU::default();
+        cpu.bus.set_flat(true);
+        load_state(&mut cpu, &case.initial);
+
+        cpu.bus.log_accesses(true);
+        let start = cpu.bus.now();
+        if let Err(e) = cpu.step() {
+            return vec![e.to_string()];
+        }
+        let accesses = cpu.bus.take_accesses();
+        cpu.bus.log_accesses(false);
+
+        let mut mismatches = Vec::new();
+        compare_state(&cpu, &case.expected, &mut mismatches);
+        compare_cycles(
+            &accesses,
+            start,
+            cpu.cycle_count as usize,
+            &case.cycles,
+            &mut mismatches,
+        );
+        mismatches
+    }
+
+    /// Runs every case in a test file and returns how many passed, printing the failures.
+    fn run_file(path: &Path) -> (usize, usize) {
+        let json = std::fs::read_to_string(path).unwrap();
+        let cases: Vec<TestCase> = serde_json::from_str(&json)
+            .unwrap_or_else(|e| panic!("Failed to parse {}: {}", path.display(), e));
+
+        let mut passed = 0;
+        let mut reported = 0;
+        for case in &cases {
+            let mismatches = run_case(case);
+            if mismatches.is_empty() {
+                passed += 1;
+            } else if reported < REPORTED_FAILURES {
+                println!("  {}: {}", case.name, mismatches.join(", "));
+                reported += 1;
+            }
+        }
+        (passed, cases.len())
+    }
+
+    #[test]
+    #[ignore = "needs SM83_TESTS_DIR"]
+    fn single_step_tests() {
+        let dir = std::env::var(TESTS_DIR_VARIABLE)
+            .unwrap_or_else(|_| panic!("{} is not set", TESTS_DIR_VARIABLE));
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", dir, e))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            })
+            .collect();
+        files.sort();
+
+        let mut failed_files = Vec::new();
+        for file in &files {
+            let name = file.file_stem().unwrap().to_string_lossy().into_owned();
+            let (passed, total) = run_file(file);
+            println!("{}: {}/{} passed", name, passed, total);
+            if passed != total {
+                failed_files.push(name);
+            }
+        }
+        assert!(
+            failed_files.is_empty(),
+            "{} of {} opcodes have failing cases: {}",
+            failed_files.len(),
+            files.len(),
+            failed_files.join(", ")
+        );
+    }
+
+    // A hand-written case in the same format, so the harness itself is always exercised
+    const INC_B_CASE: &str = r#"{
+        "name": "04 0000",
+        "initial": {
+            "pc": 49152, "sp": 65534, "a": 0, "b": 15, "c": 0, "d": 0, "e": 0,
+            "f": 16, "h": 0, "l": 0, "ime": 0, "ie": 0,
+            "ram": [[49152, 4]]
+        },
+        "final": {
+            "a": 0, "b": 16, "c": 0, "d": 0, "e": 0, "f": 48, "h": 0, "l": 0,
+            "pc": 49153, "sp": 65534, "ime": 0,
+            "ram": [[49152, 4]]
+        },
+        "cycles": [[49152, 4, "r-m"]]
+    }"#;
+
+    #[test]
+    fn harness_runs_a_case() {
+        let mut case: TestCase = serde_json::from_str(INC_B_CASE).unwrap();
+        assert_eq!(run_case(&case), Vec::<String>::new());
+
+        case.expected.b = 0x11;
+        assert_eq!(run_case(&case), vec!["B: expected 0x0011, got 0x0010"]);
+    }
+
+    #[test]
+    fn harness_compares_bus_activity() {
+        let mut case: TestCase = serde_json::from_str(INC_B_CASE).unwrap();
+        case.cycles[0] = Some((0xC000, Some(0x05), "r-m".to_string()));
+        assert_eq!(
+            run_case(&case),
+            vec!["cycle 1: expected read 0xc000 = 0x05, got read 0xc000 = 0x04"]
+        );
+
+        // An extra internal cycle is reported, along with the wrong count
+        case.cycles[0] = Some((0xC000, Some(0x04), "r-m".to_string()));
+        case.cycles.push(None);
+        assert_eq!(run_case(&case), vec!["cycles: expected 2, got 1"]);
+        case.cycles[1] = Some((0xC001, Some(0x00), "-wm".to_string()));
+        assert_eq!(
+            run_case(&case),
+            vec![
+                "cycles: expected 2, got 1",
+                "cycle 2: expected write 0xc001 = 0x00, got no access"
+            ]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// A place in a banked address space, as RGBDS writes it in symbol files: `bank:address`.
+/// Only the switchable ROM area has banks that can be told apart on a DMG, everywhere else
+/// the bank is dropped so e.g. WRAM symbols match whatever RGBDS numbered their section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    bank: usize,
+    address: u16,
+}
+
+impl Location {
+    pub fn new(bank: usize, address: u16) -> Self {
+        let bank = if (0x4000..0x8000).contains(&address) {
+            bank
+        } else {
+            0
+        };
+        Location { bank, address }
+    }
+
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.address)
+    }
+}
+
+// Symbols are only used as a base for offsets within the same kind of memory, so e.g. a
+// WRAM address isn't shown as an offset from the last label in ROM
+fn area(address: u16) -> u8 {
+    match address {
+        0x0000..=0x3FFF => 0,
+        0x4000..=0x7FFF => 1,
+        0x8000..=0x9FFF => 2,
+        0xA000..=0xBFFF => 3,
+        0xC000..=0xFDFF => 4,
+        0xFE00..=0xFF7F => 5,
+        _ => 6,
+    }
+}
+
+/// Labels from an RGBDS `.sym` file, looked up by name or by location.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    by_name: HashMap<String, Location>,
+    // The first name given for a location wins, which is the global label in RGBDS output
+    by_location: BTreeMap<Location, String>,
+}
+
+impl Symbols {
+    /// Parses lines like `01:4000 Main.loop`, skipping blank lines and `;` comments.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || format!("Line {}: expected BANK:ADDRESS NAME", index + 1);
+            let (location, name) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let (bank, address) = location.split_once(':').ok_or_else(error)?;
+            let bank = usize::from_str_radix(bank, 16).map_err(|_| error())?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| error())?;
+            symbols.insert(name.trim(), Location::new(bank, address));
+        }
+        Ok(symbols)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Symbols::parse(&text).map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        })
+    }
+
+    pub fn insert(&mut self, name: &str, location: Location) {
+        self.by_name.insert(name.to_string(), location);
+        self.by_location
+            .entry(location)
+            .or_insert_with(|| name.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Location> {
+        self.by_name.get(name).copied()
+    }
+
+    /// The label placed exactly at `location`.
+    pub fn name_at(&self, location: Location) -> Option<&str> {
+        self.by_location.get(&location).map(String::as_str)
+    }
+
+    /// The closest label at or before `location`, with the offset from it when there is one,
+    /// e.g. `Main.loop+$03`.
+    pub fn describe(&self, location: Location) -> Option<String> {
+        let (start, name) = self.by_location.range(..=location).next_back()?;
+        if start.bank != location.bank || area(start.address) != area(location.address) {
+            return None;
+        }
+        Some(match location.address - start.address {
+            0 => name.clone(),
+            offset => format!("{}+${:02X}", name, offset),
+        })
+    }
+}
@@ -0,0 +1,140 @@
+use crate::state::{StateReader, StateWriter};
+use std::io;
+
+pub struct Timer {
+    // DIV is the upper byte of this counter, it advances by 4 every M-cycle
+    counter: u16,
+    pub tima: u8, // Timer counter
+    pub tma: u8,  // Timer modulo
+    pub tac: u8,  // Timer control
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+        }
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    pub fn read_tac(&self) -> u8 {
+        self.tac | 0xF8
+    }
+
+    // TIMA is clocked by the falling edge of one of the counter bits
+    fn input_bit_index(&self) -> Option<u32> {
+        if self.tac & 0x04 == 0 {
+            return None;
+        }
+        Some(match self.tac & 0x03 {
+            0 => 9, // 4096 Hz
+            1 => 3, // 262144 Hz
+            2 => 5, // 65536 Hz
+            _ => 7, // 16384 Hz
+        })
+    }
+
+    fn input_bit(&self) -> bool {
+        self.input_bit_index()
+            .is_some_and(|bit| self.counter & (1 << bit) != 0)
+    }
+
+    fn increment_tima(&mut self) -> bool {
+        let (value, overflow) = self.tima.overflowing_add(1);
+        self.tima = if overflow { self.tma } else { value };
+        overflow
+    }
+
+    /// Resetting DIV can produce a falling edge and tick TIMA. Returns true on overflow.
+    pub fn write_div(&mut self) -> bool {
+        let before = self.input_bit();
+        self.counter = 0;
+        before && self.increment_tima()
+    }
+
+    pub fn write_tac(&mut self, value: u8) -> bool {
+        let before = self.input_bit();
+        self.tac = value & 0x07;
+        before && !self.input_bit() && self.increment_tima()
+    }
+
+    /// Reads DIV, TIMA, TMA or TAC at 0xFF04-0xFF07.
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => self.div(),
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => self.read_tac(),
+        }
+    }
+
+    /// Writes DIV, TIMA, TMA or TAC. Returns true when the write made TIMA overflow.
+    pub fn write_register(&mut self, address: u16, value: u8) -> bool {
+        match address {
+            0xFF04 => self.write_div(),
+            0xFF05 => {
+                self.tima = value;
+                false
+            }
+            0xFF06 => {
+                self.tma = value;
+                false
+            }
+            _ => self.write_tac(value),
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.u8(self.tima);
+        writer.u8(self.tma);
+        writer.u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.counter = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()?;
+        Ok(())
+    }
+
+    /// Advances the timer by `cycles` M-cycles. Returns true when TIMA overflowed and the
+    /// timer interrupt should be requested.
+    pub fn step(&mut self, cycles: u16) -> bool {
+        let before = self.counter as u32;
+        let after = before + 4 * cycles as u32;
+        self.counter = after as u16;
+        let Some(bit) = self.input_bit_index() else {
+            return false;
+        };
+        // The input bit falls every time the counter crosses a multiple of twice its weight
+        let edges = (after >> (bit + 1)) - (before >> (bit + 1));
+        let mut overflow = false;
+        for _ in 0..edges {
+            overflow |= self.increment_tima();
+        }
+        overflow
+    }
+
+    /// M-cycles until TIMA overflows, None while the timer is stopped.
+    pub fn cycles_until_overflow(&self) -> Option<u64> {
+        let bit = self.input_bit_index()?;
+        let period = 1u64 << (bit + 1);
+        let first_edge = period - self.counter as u64 % period;
+        let remaining_edges = 0xFF - self.tima as u64;
+        Some((first_edge + remaining_edges * period) / 4)
+    }
+}
@@ -1,3 +1,41 @@
+// Loads `bytes` at `base` into a CPU whose whole address space is plain RAM and points PC
+// at them, for the modules below that single-step small hand-assembled programs
+#[cfg(test)]
+fn flat_program(base: u16, bytes: &[u8], sp: u16) -> crate::cpu::CPU {
+    let mut cpu = crate::cpu::CPU::default();
+    cpu.bus.set_flat(true);
+    load_program(cpu, base, bytes, sp)
+}
+
+// Like flat_program, but keeps the real memory map for programs that talk to the I/O registers
+#[cfg(test)]
+fn bus_program(base: u16, bytes: &[u8], sp: u16) -> crate::cpu::CPU {
+    load_program(crate::cpu::CPU::default(), base, bytes, sp)
+}
+
+#[cfg(test)]
+fn load_program(mut cpu: crate::cpu::CPU, base: u16, bytes: &[u8], sp: u16) -> crate::cpu::CPU {
+    for (address, &byte) in (base..).zip(bytes) {
+        cpu.bus.poke_byte(address, byte);
+    }
+    cpu.pc = base;
+    cpu.sp = sp;
+    cpu
+}
+
+// To be loaded at 0x0100: LD A,$05 / CALL $0110 / INC A / JR -3 ... 0110: INC B / INC B / RET
+#[cfg(test)]
+const CALL_PROGRAM: [u8; 19] = [
+    0x3E, 0x05, 0xCD, 0x10, 0x01, 0x3C, 0x18, 0xFD, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x04, 0x04, 0xC9,
+];
+
+// Blargg's cpu_instrs, for the modules below that need a real cartridge to run
+#[cfg(test)]
+fn cpu_instrs() -> crate::GameBoy {
+    crate::GameBoy::load_rom(std::path::Path::new("roms/cpu_instrs.gb")).unwrap()
+}
+
 #[cfg(test)]
 mod instructions_unit {
     use crate::{cpu::*, instructions::*, registers::*};
@@ -10,7 +48,7 @@ mod instructions_unit {
         cpu.execute(Instruction::ADD(
             Target::Register(ArithmeticTarget::A),
             Target::Register(ArithmeticTarget::B),
-        ));
+        )).unwrap();
 
         // Debug prints
         println!("Register A: {}", cpu.registers.a);
@@ -29,7 +67,7 @@ mod instructions_unit {
         cpu.execute(Instruction::ADD(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 3);
     }
 
@@ -44,7 +82,7 @@ mod instructions_unit {
         cpu.execute(Instruction::ADD(
             Target::Register16(DoubleTarget::HL),
             Target::Register16(DoubleTarget::BC),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.h, 0x68);
         assert_eq!(cpu.registers.l, 0xAC);
     }
@@ -59,7 +97,7 @@ mod instructions_unit {
         cpu.execute(Instruction::ADD(
             Target::Register16(DoubleTarget::HL),
             Target::Register16(DoubleTarget::SP),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.h, 0x68);
         assert_eq!(cpu.registers.l, 0xAC);
     }
@@ -73,7 +111,7 @@ mod instructions_unit {
         cpu.execute(Instruction::AND(
             Target::Register(ArithmeticTarget::A),
             Target::Register(ArithmeticTarget::B),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0b10001000);
     }
 
@@ -87,7 +125,7 @@ mod instructions_unit {
         cpu.execute(Instruction::AND(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0b10001000);
     }
 
@@ -97,8 +135,8 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.a = 3;
         cpu.registers.b = 255;
-        cpu.execute(Instruction::ADC(Target::Register(ArithmeticTarget::B)));
-        assert_eq!(cpu.registers.a, 3);
+        cpu.execute(Instruction::ADC(Target::Register(ArithmeticTarget::B))).unwrap();
+        assert_eq!(cpu.registers.a, 2);
         assert!(cpu.registers.f.carry);
     }
 
@@ -110,7 +148,7 @@ mod instructions_unit {
         cpu.registers.a = 0x34;
         cpu.registers.f.carry = false;
 
-        cpu.execute(Instruction::ADC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::ADC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
 
         assert_eq!(cpu.registers.a, 0x46);
         assert!(!cpu.registers.f.zero);
@@ -127,7 +165,7 @@ mod instructions_unit {
         cpu.registers.a = 0x34;
         cpu.registers.f.carry = true;
 
-        cpu.execute(Instruction::ADC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::ADC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
 
         assert_eq!(cpu.registers.a, 0x47);
         assert!(!cpu.registers.f.zero);
@@ -140,7 +178,7 @@ mod instructions_unit {
     fn srl_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
-        cpu.execute(Instruction::SRL(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::SRL(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b01000000);
         assert!(!cpu.registers.f.carry);
     }
@@ -149,7 +187,7 @@ mod instructions_unit {
     fn sra_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
-        cpu.execute(Instruction::SRA(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::SRA(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b11000000);
         assert!(!cpu.registers.f.carry);
     }
@@ -159,7 +197,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b10000000);
-        cpu.execute(Instruction::SRA(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::SRA(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b11000000);
         assert!(!cpu.registers.f.carry);
     }
@@ -168,7 +206,7 @@ mod instructions_unit {
     fn sla_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
-        cpu.execute(Instruction::SLA(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::SLA(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b00000000);
         assert!(cpu.registers.f.carry);
     }
@@ -178,7 +216,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b10000000);
-        cpu.execute(Instruction::SLA(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::SLA(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b00000000);
         assert!(cpu.registers.f.carry);
     }
@@ -188,7 +226,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b10000000);
-        cpu.execute(Instruction::SRL(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::SRL(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b01000000);
         assert!(!cpu.registers.f.carry);
     }
@@ -201,7 +239,7 @@ mod instructions_unit {
         cpu.registers.a = 0x01;
         cpu.registers.f.carry = true;
 
-        cpu.execute(Instruction::ADC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::ADC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
 
         assert_eq!(cpu.registers.a, 0x01);
         assert!(!cpu.registers.f.zero);
@@ -215,7 +253,7 @@ mod instructions_unit {
         //BIT r8
         let mut cpu = CPU::default();
         cpu.registers.a = 0b00001000;
-        cpu.execute(Instruction::BIT(3, Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::BIT(3, Target::Register(ArithmeticTarget::A))).unwrap();
         assert!(!cpu.registers.f.zero);
     }
 
@@ -225,7 +263,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b00001000);
-        cpu.execute(Instruction::BIT(3, Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::BIT(3, Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert!(!cpu.registers.f.zero);
     }
 
@@ -234,7 +272,7 @@ mod instructions_unit {
         //CCF
         let mut cpu = CPU::default();
         cpu.registers.f.carry = true;
-        cpu.execute(Instruction::CCF());
+        cpu.execute(Instruction::CCF()).unwrap();
         assert!(!cpu.registers.f.carry);
     }
 
@@ -243,7 +281,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.a = 0x12;
         cpu.registers.b = 0x12;
-        cpu.execute(Instruction::CP(Target::Register(ArithmeticTarget::B)));
+        cpu.execute(Instruction::CP(Target::Register(ArithmeticTarget::B))).unwrap();
         assert!(cpu.registers.f.zero);
     }
 
@@ -253,7 +291,7 @@ mod instructions_unit {
         cpu.registers.a = 0x12;
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0x12);
-        cpu.execute(Instruction::CP(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::CP(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert!(cpu.registers.f.zero);
     }
 
@@ -262,7 +300,7 @@ mod instructions_unit {
         //CPL
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10101010;
-        cpu.execute(Instruction::CPL());
+        cpu.execute(Instruction::CPL()).unwrap();
         assert_eq!(cpu.registers.a, 0b01010101);
         assert!(cpu.registers.f.subtract);
         assert!(cpu.registers.f.half_carry);
@@ -274,7 +312,7 @@ mod instructions_unit {
         cpu.registers.a = 0x3c; // 0x3c = 60 DEC
         cpu.registers.f.half_carry = true;
         cpu.registers.f.carry = false;
-        cpu.execute(Instruction::DAA());
+        cpu.execute(Instruction::DAA()).unwrap();
         assert_eq!(cpu.registers.a, 0x42); // Sprawdź, czy A = 42 DEC
         assert!(!cpu.registers.f.zero);
         assert!(!cpu.registers.f.half_carry);
@@ -285,7 +323,7 @@ mod instructions_unit {
     fn dec() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0x01;
-        cpu.execute(Instruction::DEC(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::DEC(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0x00);
         assert!(cpu.registers.f.zero);
         assert!(cpu.registers.f.subtract);
@@ -295,7 +333,7 @@ mod instructions_unit {
     fn dec_r16() {
         let mut cpu = CPU::default();
         cpu.registers.set_bc(0x1234);
-        cpu.execute(Instruction::DEC(Target::Register16(DoubleTarget::BC)));
+        cpu.execute(Instruction::DEC(Target::Register16(DoubleTarget::BC))).unwrap();
         assert_eq!(cpu.registers.get_bc(), 0x1233);
     }
 
@@ -304,7 +342,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0x12);
-        cpu.execute(Instruction::DEC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::DEC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x11);
     }
 
@@ -312,7 +350,7 @@ mod instructions_unit {
     fn inc() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0x01;
-        cpu.execute(Instruction::INC(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::INC(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0x02);
         assert!(!cpu.registers.f.zero);
         assert!(!cpu.registers.f.subtract);
@@ -327,7 +365,7 @@ mod instructions_unit {
         cpu.execute(Instruction::SBC(
             Target::Register(ArithmeticTarget::A),
             Target::Register(ArithmeticTarget::B),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0x00);
         assert!(cpu.registers.f.zero);
         assert!(cpu.registers.f.subtract);
@@ -344,7 +382,7 @@ mod instructions_unit {
         cpu.execute(Instruction::SBC(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0x00);
         assert!(cpu.registers.f.zero);
         assert!(cpu.registers.f.subtract);
@@ -356,7 +394,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.a = 0x02;
         cpu.registers.b = 0x01;
-        cpu.execute(Instruction::SUB(Target::Register(ArithmeticTarget::B)));
+        cpu.execute(Instruction::SUB(Target::Register(ArithmeticTarget::B))).unwrap();
         assert_eq!(cpu.registers.a, 0x01);
         assert!(!cpu.registers.f.zero);
         assert!(cpu.registers.f.subtract);
@@ -368,7 +406,7 @@ mod instructions_unit {
         cpu.registers.a = 0x02;
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0x01);
-        cpu.execute(Instruction::SUB(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::SUB(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.registers.a, 0x01);
         assert!(!cpu.registers.f.zero);
         assert!(cpu.registers.f.subtract);
@@ -379,7 +417,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0x12);
-        cpu.execute(Instruction::INC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::INC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x13);
     }
 
@@ -387,7 +425,7 @@ mod instructions_unit {
     fn inc_r16() {
         let mut cpu = CPU::default();
         cpu.registers.set_bc(0x1234);
-        cpu.execute(Instruction::INC(Target::Register16(DoubleTarget::BC)));
+        cpu.execute(Instruction::INC(Target::Register16(DoubleTarget::BC))).unwrap();
         assert_eq!(cpu.registers.get_bc(), 0x1235);
     }
 
@@ -395,7 +433,7 @@ mod instructions_unit {
     fn inc_sp() {
         let mut cpu = CPU::default();
         cpu.sp = 0x1234;
-        cpu.execute(Instruction::INC(Target::Register16(DoubleTarget::SP)));
+        cpu.execute(Instruction::INC(Target::Register16(DoubleTarget::SP))).unwrap();
         assert_eq!(cpu.sp, 0x1235);
     }
 
@@ -403,7 +441,10 @@ mod instructions_unit {
     fn ei() {
         let mut cpu = CPU::default();
         cpu.registers.ime = false;
-        cpu.execute(Instruction::EI());
+        cpu.execute(Instruction::EI()).unwrap();
+        // IME is only set once the next instruction starts
+        assert!(!cpu.registers.ime);
+        cpu.step().unwrap();
         assert!(cpu.registers.ime);
     }
 
@@ -411,7 +452,7 @@ mod instructions_unit {
     fn di() {
         let mut cpu = CPU::default();
         cpu.registers.ime = true;
-        cpu.execute(Instruction::DI());
+        cpu.execute(Instruction::DI()).unwrap();
         assert!(!cpu.registers.ime);
     }
 
@@ -423,7 +464,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LD(
             Target::Register(ArithmeticTarget::B),
             Target::Register(ArithmeticTarget::A),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.b, 0x01);
     }
 
@@ -436,7 +477,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LD(
             Target::MemoryR16(DoubleTarget::HL),
             Target::Register(ArithmeticTarget::A),
-        ));
+        )).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x01);
     }
 
@@ -449,7 +490,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LD(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0x01);
     }
 
@@ -462,7 +503,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LD(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::BC),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0x01);
     }
 
@@ -474,7 +515,7 @@ mod instructions_unit {
         cpu.execute(Instruction::XOR(
             Target::Register(ArithmeticTarget::A),
             Target::Register(ArithmeticTarget::B),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0b01100110);
     }
 
@@ -487,7 +528,7 @@ mod instructions_unit {
         cpu.execute(Instruction::XOR(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0b01100110);
     }
 
@@ -499,7 +540,7 @@ mod instructions_unit {
         cpu.execute(Instruction::OR(
             Target::Register(ArithmeticTarget::A),
             Target::Register(ArithmeticTarget::B),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0b11101110);
     }
 
@@ -512,7 +553,7 @@ mod instructions_unit {
         cpu.execute(Instruction::OR(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.registers.a, 0b11101110);
     }
 
@@ -521,7 +562,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
         cpu.registers.f.carry = true;
-        cpu.execute(Instruction::RLA());
+        cpu.execute(Instruction::RLA()).unwrap();
         assert_eq!(cpu.registers.a, 0b00000001);
         assert!(cpu.registers.f.carry);
     }
@@ -530,7 +571,7 @@ mod instructions_unit {
     fn rl_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
-        cpu.execute(Instruction::RL(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::RL(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b00000000);
         assert!(cpu.registers.f.carry);
     }
@@ -540,7 +581,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b10000000);
-        cpu.execute(Instruction::RL(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::RL(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b00000000);
         assert!(cpu.registers.f.carry);
     }
@@ -549,7 +590,7 @@ mod instructions_unit {
     fn rlc_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
-        cpu.execute(Instruction::RLC(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::RLC(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b00000001);
         assert!(cpu.registers.f.carry);
     }
@@ -559,7 +600,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b10000000);
-        cpu.execute(Instruction::RLC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::RLC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b00000001);
         assert!(cpu.registers.f.carry);
     }
@@ -568,7 +609,7 @@ mod instructions_unit {
     fn rlca() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b10000000;
-        cpu.execute(Instruction::RLCA());
+        cpu.execute(Instruction::RLCA()).unwrap();
         assert_eq!(cpu.registers.a, 0b00000001);
         assert!(cpu.registers.f.carry);
     }
@@ -578,7 +619,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b00000001;
         cpu.registers.f.carry = true;
-        cpu.execute(Instruction::RR(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::RR(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b10000000);
         assert!(cpu.registers.f.carry);
     }
@@ -589,7 +630,7 @@ mod instructions_unit {
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b00000001);
         cpu.registers.f.carry = true;
-        cpu.execute(Instruction::RR(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::RR(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b10000000);
         assert!(cpu.registers.f.carry);
     }
@@ -598,7 +639,7 @@ mod instructions_unit {
     fn rrc_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b00000001;
-        cpu.execute(Instruction::RRC(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::RRC(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b10000000);
         assert!(cpu.registers.f.carry);
     }
@@ -608,7 +649,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b00000001);
-        cpu.execute(Instruction::RRC(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::RRC(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b10000000);
         assert!(cpu.registers.f.carry);
     }
@@ -618,7 +659,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b00000001;
         cpu.registers.f.carry = true;
-        cpu.execute(Instruction::RRA());
+        cpu.execute(Instruction::RRA()).unwrap();
         assert_eq!(cpu.registers.a, 0b10000000);
         assert!(cpu.registers.f.carry);
     }
@@ -627,7 +668,7 @@ mod instructions_unit {
     fn rrca() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b00000001;
-        cpu.execute(Instruction::RRCA());
+        cpu.execute(Instruction::RRCA()).unwrap();
         assert_eq!(cpu.registers.a, 0b10000000);
         assert!(cpu.registers.f.carry);
     }
@@ -635,7 +676,7 @@ mod instructions_unit {
     #[test]
     fn jp() {
         let mut cpu = CPU::default();
-        cpu.execute(Instruction::JP(JumpCondition::Always, 0x1234));
+        cpu.execute(Instruction::JP(JumpCondition::Always, 0x1234)).unwrap();
         assert_eq!(cpu.pc, 0x1234);
     }
 
@@ -643,7 +684,7 @@ mod instructions_unit {
     fn jp_nz() {
         let mut cpu = CPU::default();
         cpu.registers.f.zero = false;
-        cpu.execute(Instruction::JP(JumpCondition::NotZero, 0x1234));
+        cpu.execute(Instruction::JP(JumpCondition::NotZero, 0x1234)).unwrap();
         assert_eq!(cpu.pc, 0x1234);
     }
 
@@ -651,14 +692,14 @@ mod instructions_unit {
     fn jp_hl() {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
-        cpu.execute(Instruction::JPHL(Target::Register16(DoubleTarget::HL)));
+        cpu.execute(Instruction::JPHL(Target::Register16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.pc, 0x1234);
     }
     #[test]
     fn swap_r8() {
         let mut cpu = CPU::default();
         cpu.registers.a = 0b11110000;
-        cpu.execute(Instruction::SWAP(Target::Register(ArithmeticTarget::A)));
+        cpu.execute(Instruction::SWAP(Target::Register(ArithmeticTarget::A))).unwrap();
         assert_eq!(cpu.registers.a, 0b00001111);
         assert!(!cpu.registers.f.zero);
         assert!(!cpu.registers.f.subtract);
@@ -671,7 +712,7 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.registers.set_hl(0x1234);
         cpu.bus.write_byte(0x1234, 0b11110000);
-        cpu.execute(Instruction::SWAP(Target::MemoryR16(DoubleTarget::HL)));
+        cpu.execute(Instruction::SWAP(Target::MemoryR16(DoubleTarget::HL))).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0b00001111);
         assert!(!cpu.registers.f.zero);
         assert!(!cpu.registers.f.subtract);
@@ -687,10 +728,11 @@ mod instructions_unit {
                          // In the hardware it is normally set in the boot ROM
 
         cpu.registers.set_bc(0x1234);
-        cpu.execute(Instruction::PUSH(Target::Register16(DoubleTarget::BC)));
-        assert_eq!(cpu.bus.read_byte(cpu.sp), 0x12);
-        assert_eq!(cpu.bus.read_byte(cpu.sp + 1), 0x34);
-        cpu.execute(Instruction::POP(Target::Register16(DoubleTarget::BC)));
+        cpu.execute(Instruction::PUSH(Target::Register16(DoubleTarget::BC))).unwrap();
+        // Little endian, the high byte is pushed first
+        assert_eq!(cpu.bus.read_byte(cpu.sp), 0x34);
+        assert_eq!(cpu.bus.read_byte(cpu.sp + 1), 0x12);
+        cpu.execute(Instruction::POP(Target::Register16(DoubleTarget::BC))).unwrap();
         assert_eq!(cpu.registers.get_bc(), 0x1234);
     }
 
@@ -699,10 +741,10 @@ mod instructions_unit {
         let mut cpu = CPU::default();
         cpu.pc = 0x1234;
         cpu.sp = 0xFFEE;
-        cpu.execute(Instruction::CALL(JumpCondition::Always, 0x5678));
+        cpu.execute(Instruction::CALL(JumpCondition::Always, 0x5678)).unwrap();
         assert_eq!(cpu.pc, 0x5678);
 
-        cpu.execute(Instruction::RET(JumpCondition::Always));
+        cpu.execute(Instruction::RET(JumpCondition::Always)).unwrap();
         assert_eq!(cpu.pc, 0x1237);
     }
 
@@ -714,7 +756,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LDI(
             Target::MemoryR16(DoubleTarget::HL),
             Target::Register(ArithmeticTarget::A),
-        ));
+        )).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x12);
         assert_eq!(cpu.registers.get_hl(), 0x1235);
     }
@@ -727,7 +769,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LDI(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x12);
         assert_eq!(cpu.registers.get_hl(), 0x1235);
     }
@@ -740,7 +782,7 @@ mod instructions_unit {
         cpu.execute(Instruction::LDD(
             Target::MemoryR16(DoubleTarget::HL),
             Target::Register(ArithmeticTarget::A),
-        ));
+        )).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x12);
         assert_eq!(cpu.registers.get_hl(), 0x1233);
     }
@@ -753,8 +795,1710 @@ mod instructions_unit {
         cpu.execute(Instruction::LDD(
             Target::Register(ArithmeticTarget::A),
             Target::MemoryR16(DoubleTarget::HL),
-        ));
+        )).unwrap();
         assert_eq!(cpu.bus.read_byte(0x1234), 0x12);
         assert_eq!(cpu.registers.get_hl(), 0x1233);
     }
 }
+
+#[cfg(test)]
+mod serial_unit {
+    use crate::{cpu::*, instructions::*, serial::*};
+
+    #[test]
+    fn internal_clock_transfer() {
+        let mut cpu = CPU::default();
+        let output = BufferSerial::new();
+        cpu.bus.serial.connect(Box::new(output.clone()));
+        cpu.bus.write_byte(0xFF01, b'P');
+        cpu.bus.write_byte(0xFF02, 0x81);
+
+        // 8 bits at 128 M-cycles each
+        for _ in 0..1023 {
+            cpu.execute(Instruction::NOP()).unwrap();
+        }
+        assert_eq!(cpu.bus.read_byte(0xFF02) & 0x80, 0x80);
+        assert!(output.text().is_empty());
+
+        cpu.execute(Instruction::NOP()).unwrap();
+        assert_eq!(output.text(), "P");
+        assert_eq!(cpu.bus.read_byte(0xFF02) & 0x80, 0);
+        assert_eq!(cpu.bus.read_byte(0xFF01), 0xFF);
+        assert_eq!(cpu.bus.read_byte(0xFF0F) & SERIAL_INTERRUPT, SERIAL_INTERRUPT);
+    }
+
+    #[test]
+    fn external_clock_waits_for_peer() {
+        let mut cpu = CPU::default();
+        let output = BufferSerial::new();
+        cpu.bus.serial.connect(Box::new(output.clone()));
+        cpu.bus.write_byte(0xFF01, 0x42);
+        cpu.bus.write_byte(0xFF02, 0x80);
+        for _ in 0..2048 {
+            cpu.execute(Instruction::NOP()).unwrap();
+        }
+        assert!(output.text().is_empty());
+        assert_eq!(cpu.bus.read_byte(0xFF02), 0xFE);
+        assert_eq!(cpu.bus.read_byte(0xFF0F) & SERIAL_INTERRUPT, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_exchanges_bytes() {
+        use std::os::unix::net::UnixStream;
+        use std::io::{Read, Write};
+
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let peer = std::thread::spawn(move || {
+            let mut byte = [0];
+            remote.read_exact(&mut byte).unwrap();
+            remote.write_all(&[0x99]).unwrap();
+            byte[0]
+        });
+
+        let mut serial = SocketSerial::new(local);
+        assert_eq!(serial.transfer(0x12), 0x99);
+        assert_eq!(peer.join().unwrap(), 0x12);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod link_unit {
+    use crate::{cpu::*, instructions::*, link::*};
+    use std::os::unix::net::UnixStream;
+
+    // Runs a transfer on one instance and returns what ended up in SB and how many
+    // NOPs it took until the serial interrupt fired
+    fn run_side(stream: UnixStream, role: LinkRole, sb: u8, sc: u8) -> (u8, Option<usize>) {
+        let mut cpu = CPU::default();
+        let link = LinkCable::new(Box::new(stream), role).unwrap();
+        cpu.bus.serial.connect_link(link);
+        cpu.bus.write_byte(0xFF01, sb);
+        cpu.bus.write_byte(0xFF02, sc);
+
+        let mut completed_at = None;
+        for i in 0..(LINK_QUANTUM as usize * 4) {
+            cpu.execute(Instruction::NOP()).unwrap();
+            if completed_at.is_none() && cpu.bus.read_byte(0xFF0F) & SERIAL_INTERRUPT != 0 {
+                completed_at = Some(i);
+            }
+        }
+        (cpu.bus.read_byte(0xFF01), completed_at)
+    }
+
+    #[test]
+    fn lockstep_transfer() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let slave = std::thread::spawn(move || run_side(slave, LinkRole::Slave, 0xB2, 0x80));
+        let (master_sb, master_done) = run_side(master, LinkRole::Master, 0xA1, 0x81);
+        let (slave_sb, slave_done) = slave.join().unwrap();
+
+        assert_eq!(master_sb, 0xB2);
+        assert_eq!(slave_sb, 0xA1);
+        // Both ends complete on the same sync point
+        assert!(master_done.is_some());
+        assert_eq!(master_done, slave_done);
+    }
+
+    #[test]
+    fn nobody_listening() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let slave = std::thread::spawn(move || run_side(slave, LinkRole::Slave, 0xB2, 0x00));
+        let (master_sb, master_done) = run_side(master, LinkRole::Master, 0xA1, 0x81);
+        let (slave_sb, slave_done) = slave.join().unwrap();
+
+        assert_eq!(master_sb, 0xFF);
+        assert!(master_done.is_some());
+        assert_eq!(slave_sb, 0xB2);
+        assert_eq!(slave_done, None);
+    }
+}
+
+#[cfg(test)]
+mod headless_unit {
+    use super::bus_program;
+    use crate::headless::*;
+    use crate::serial::{BufferSerial, SerialDevice};
+
+    // Prints "ok" over serial, waiting for each byte to be shifted out, then jumps to
+    // itself forever
+    const PRINT_AND_LOOP: [u8; 24] = [
+        0x3E, b'o', // LD A,'o'
+        0xE0, 0x01, // LDH (SB),A
+        0x3E, 0x81, // LD A,$81
+        0xE0, 0x02, // LDH (SC),A
+        0xF0, 0x02, // LDH A,(SC)
+        0xCB, 0x7F, // BIT 7,A
+        0x20, 0xFA, // JR NZ,-6
+        0x3E, b'k', // LD A,'k'
+        0xE0, 0x01, // LDH (SB),A
+        0x3E, 0x81, // LD A,$81
+        0xE0, 0x02, // LDH (SC),A
+        0x18, 0xFE, // JR -2
+    ];
+
+    #[test]
+    fn stops_on_serial_match() {
+        let mut cpu = bus_program(0x0000, &PRINT_AND_LOOP, 0xFFFE);
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 10,
+                until_serial: Some(String::from("ok")),
+                until_loop: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.outcome, Outcome::SerialMatched);
+        assert_eq!(result.serial_output, "ok");
+        assert_eq!(result.exit_code(), 0);
+    }
+
+    #[test]
+    fn keeps_the_connected_device() {
+        let mut cpu = bus_program(0x0000, &PRINT_AND_LOOP, 0xFFFE);
+        let device = BufferSerial::new();
+        let boxed: Box<dyn SerialDevice> = Box::new(device.clone());
+        let connected: *const dyn SerialDevice = &*boxed;
+        cpu.bus.serial.connect(boxed);
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 10,
+                until_loop: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.serial_output, "ok");
+        assert_eq!(device.text(), "ok");
+        // The very same device is plugged back in, not the tee wrapping it
+        assert!(std::ptr::addr_eq(&*cpu.bus.serial.disconnect(), connected));
+    }
+
+    #[test]
+    fn reconnects_the_device_after_a_crash() {
+        let mut cpu = bus_program(0x0000, &[0xD3], 0xFFFE);
+        let boxed: Box<dyn SerialDevice> = Box::new(BufferSerial::new());
+        let connected: *const dyn SerialDevice = &*boxed;
+        cpu.bus.serial.connect(boxed);
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 1,
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.outcome, Outcome::Crashed);
+        assert!(std::ptr::addr_eq(&*cpu.bus.serial.disconnect(), connected));
+    }
+
+    #[test]
+    fn stops_on_loop() {
+        let mut cpu = bus_program(0x0000, &PRINT_AND_LOOP, 0xFFFE);
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 10,
+                until_serial: Some(String::from("Passed")),
+                until_loop: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.outcome, Outcome::InfiniteLoop);
+        assert_eq!(cpu.pc, 0x16);
+        assert_eq!(result.exit_code(), 2);
+    }
+
+    #[test]
+    fn stops_on_pc() {
+        let mut cpu = bus_program(0x0000, &PRINT_AND_LOOP, 0xFFFE);
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 10,
+                until_pc: Some(0x0008),
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.outcome, Outcome::PcReached);
+        assert_eq!(result.exit_code(), 0);
+    }
+
+    #[test]
+    fn frame_limit() {
+        let mut cpu = bus_program(0x0000, &PRINT_AND_LOOP, 0xFFFE);
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 2,
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.outcome, Outcome::FrameLimit);
+        assert_eq!(result.frames, 2);
+        assert_eq!(result.exit_code(), 0);
+
+        let result = run(
+            &mut cpu,
+            &RunOptions {
+                frames: 2,
+                until_pc: Some(0x4000),
+                ..Default::default()
+            },
+        );
+        assert_eq!(result.outcome, Outcome::FrameLimit);
+        assert_eq!(result.exit_code(), 1);
+    }
+}
+
+#[cfg(test)]
+mod mooneye_unit {
+    use super::flat_program;
+    use crate::{cpu::*, mooneye::*};
+
+    // Loads the given value into B, C, D, E, H and L, then hits the LD B,B breakpoint
+    fn program_cpu(values: [u8; 6]) -> CPU {
+        let opcodes = [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E];
+        let mut program: Vec<u8> = opcodes
+            .iter()
+            .zip(values)
+            .flat_map(|(&opcode, value)| [opcode, value])
+            .collect();
+        program.push(0x40); // LD B,B
+        flat_program(0x0000, &program, 0xFFFE)
+    }
+
+    #[test]
+    fn fibonacci_passes() {
+        let mut cpu = program_cpu([3, 5, 8, 13, 21, 34]);
+        assert_eq!(run_until_breakpoint(&mut cpu, 1), Verdict::Passed);
+        assert_eq!(cpu.pc, 13);
+    }
+
+    #[test]
+    fn failure_pattern_fails() {
+        let mut cpu = program_cpu([0x42; 6]);
+        assert_eq!(run_until_breakpoint(&mut cpu, 1), Verdict::Failed);
+    }
+
+    #[test]
+    fn no_breakpoint_times_out() {
+        let mut cpu = flat_program(0x0000, &[0x18, 0xFE], 0xFFFE); // JR -2
+        assert_eq!(run_until_breakpoint(&mut cpu, 1), Verdict::TimedOut);
+        assert!(!cpu.take_breakpoint());
+    }
+}
+
+#[cfg(test)]
+mod errors_unit {
+    use crate::{cpu::*, instructions::*, registers::*};
+
+    #[test]
+    fn illegal_opcode() {
+        let mut cpu = CPU::default();
+        cpu.pc = 0xC000;
+        cpu.bus.poke_byte(0xC000, 0xD3);
+        let error = cpu.step().unwrap_err();
+        assert!(matches!(
+            error,
+            CpuError::IllegalOpcode {
+                opcode: 0xD3,
+                address: 0xC000
+            }
+        ));
+        assert_eq!(error.to_string(), "Illegal opcode 0xd3 at 0xc000");
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up() {
+        let mut cpu = CPU::default();
+        cpu.lock_up_on_illegal_opcode = true;
+        cpu.registers.ime = true;
+        cpu.bus.poke_byte(0, 0xFD);
+        cpu.step().unwrap();
+        assert!(cpu.is_locked_up());
+
+        // Not even an interrupt gets it going again
+        cpu.bus.write_byte(INTERRUPT_ENABLE_ADDRESS, VBLANK_INTERRUPT);
+        cpu.bus.request_interrupt(VBLANK_INTERRUPT);
+        for _ in 0..10 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0);
+    }
+
+    #[test]
+    fn invalid_operand() {
+        let mut cpu = CPU::default();
+        cpu.pc = 0x1234;
+        let result = cpu.execute(Instruction::LD(
+            Target::Const8(0x12),
+            Target::Register(ArithmeticTarget::A),
+        ));
+        assert!(matches!(
+            result,
+            Err(CpuError::InvalidOperand {
+                address: 0x1234,
+                ..
+            })
+        ));
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn stack_wraps_around() {
+        // Like on hardware, the high byte lands at 0x0000 and the low one at 0xFFFF
+        let mut cpu = CPU::default();
+        cpu.sp = 0x0001;
+        cpu.registers.set_bc(0x1234);
+        cpu.execute(Instruction::PUSH(Target::Register16(DoubleTarget::BC)))
+            .unwrap();
+        assert_eq!(cpu.sp, 0xFFFF);
+        assert_eq!(cpu.bus.peek_byte(0xFFFF), 0x34);
+        cpu.execute(Instruction::POP(Target::Register16(DoubleTarget::DE)))
+            .unwrap();
+        assert_eq!(cpu.sp, 0x0001);
+        assert_eq!(cpu.registers.get_de() & 0x00FF, 0x34);
+    }
+
+    #[test]
+    fn stack_wrap_faults_when_enabled() {
+        let mut cpu = CPU::default();
+        cpu.fault_on_stack_wrap = true;
+        cpu.pc = 0xC000;
+        cpu.sp = 0x0001;
+        let error = cpu
+            .execute(Instruction::PUSH(Target::Register16(DoubleTarget::BC)))
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            CpuError::StackFault {
+                sp: 0x0001,
+                address: 0xC000
+            }
+        ));
+        assert_eq!(error.to_string(), "Stack fault with SP 0x0001 at 0xc000");
+        assert_eq!(cpu.sp, 0x0001);
+
+        cpu.sp = 0xFFFE;
+        assert!(matches!(
+            cpu.execute(Instruction::RET(JumpCondition::Always)),
+            Err(CpuError::StackFault { sp: 0xFFFE, .. })
+        ));
+    }
+
+    #[test]
+    fn every_opcode_has_valid_operands() {
+        for prefixed in [false, true] {
+            for byte in 0..=0xFF {
+                let Some(instruction) = Instruction::from_byte(byte, prefixed) else {
+                    continue;
+                };
+                let mut cpu = CPU::default();
+                cpu.sp = 0xFFFE;
+                if let Err(e) = cpu.execute(instruction) {
+                    panic!("{:#04x} (prefixed: {}): {}", byte, prefixed, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod joypad_unit {
+    use crate::{cpu::*, joypad::*};
+
+    #[test]
+    fn reads_selected_group() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Down, true);
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.write(0x20); // Directions
+        assert_eq!(joypad.read(), 0xE7);
+        joypad.write(0x10); // Buttons
+        assert_eq!(joypad.read(), 0xDE);
+    }
+
+    #[test]
+    fn press_requests_interrupt() {
+        let mut cpu = CPU::default();
+        cpu.bus.write_byte(0xFF00, 0x10);
+        cpu.bus.set_button(Button::Up, true);
+        assert_eq!(cpu.bus.read_byte(INTERRUPT_FLAG_ADDRESS) & JOYPAD_INTERRUPT, 0);
+
+        cpu.bus.set_button(Button::Start, true);
+        assert_eq!(cpu.bus.read_byte(0xFF00), 0xD7);
+        assert_ne!(cpu.bus.read_byte(INTERRUPT_FLAG_ADDRESS) & JOYPAD_INTERRUPT, 0);
+    }
+}
+
+#[cfg(test)]
+mod gameboy_unit {
+    use super::cpu_instrs;
+    use crate::GameBoy;
+    use std::path::Path;
+
+    #[test]
+    fn state_round_trip() {
+        let mut gameboy = cpu_instrs();
+        for _ in 0..30 {
+            gameboy.run_frame().unwrap();
+        }
+        let state = gameboy.save_state();
+        for _ in 0..10 {
+            gameboy.run_frame().unwrap();
+        }
+        let pc = gameboy.cpu().pc;
+        let framebuffer = gameboy.framebuffer();
+
+        // A fresh instance picks up exactly where the state was taken
+        let mut restored = cpu_instrs();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        for _ in 0..10 {
+            restored.run_frame().unwrap();
+        }
+        assert_eq!(restored.cpu().pc, pc);
+        assert_eq!(restored.framebuffer(), framebuffer);
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let mut gameboy = cpu_instrs();
+        let state = gameboy.save_state();
+        assert!(gameboy.load_state(&state[..state.len() - 1]).is_err());
+
+        let mut longer = state.clone();
+        longer.push(0);
+        assert!(gameboy.load_state(&longer).is_err());
+
+        // States from a cartridge can't be loaded without one
+        let mut boot = GameBoy::load_boot_rom(Path::new("roms/dmg_boot.bin")).unwrap();
+        assert!(boot.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn only_saves_rom_area_without_cartridge() {
+        let mut boot = GameBoy::load_boot_rom(Path::new("roms/dmg_boot.bin")).unwrap();
+        let boot_state = boot.save_state();
+        boot.load_state(&boot_state).unwrap();
+        assert_eq!(boot.save_state(), boot_state);
+
+        // The cartridge's 8 KiB of RAM and 4 banking bytes take the place of the 32 KiB ROM area
+        let cartridge_state = cpu_instrs().save_state();
+        assert_eq!(boot_state.len() - cartridge_state.len(), 0x8000 - 0x2000 - 4);
+    }
+
+    #[test]
+    fn checks_state_header() {
+        let mut gameboy = cpu_instrs();
+        let state = gameboy.save_state();
+        assert_eq!(&state[..4], b"RMLS");
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = 0;
+        assert!(gameboy.load_state(&bad_magic).is_err());
+
+        let mut bad_version = state.clone();
+        bad_version[4] = 0xFF;
+        assert!(gameboy.load_state(&bad_version).is_err());
+
+        let mut other_rom = std::fs::read("roms/cpu_instrs.gb").unwrap();
+        other_rom[0x0134] ^= 0xFF;
+        let error = GameBoy::new(other_rom).load_state(&state).unwrap_err();
+        assert!(error.to_string().contains("different ROM"));
+    }
+
+    #[test]
+    fn patching_rom_updates_checksum() {
+        let mut rom = std::fs::read("roms/cpu_instrs.gb").unwrap();
+        let mut gameboy = GameBoy::new(rom.clone());
+        let checksum = gameboy.rom_checksum();
+
+        rom[0x0150] ^= 0xFF;
+        gameboy.cpu_mut().bus.poke_byte(0x0150, rom[0x0150]);
+        assert_eq!(gameboy.rom_checksum(), GameBoy::new(rom).rom_checksum());
+        assert_ne!(gameboy.rom_checksum(), checksum);
+    }
+
+    #[test]
+    fn failed_load_keeps_state() {
+        let mut gameboy = cpu_instrs();
+        let state = gameboy.save_state();
+        gameboy.run_frame().unwrap();
+        let current = gameboy.save_state();
+        assert!(gameboy.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(gameboy.save_state(), current);
+    }
+}
+
+#[cfg(test)]
+mod rewind_unit {
+    use crate::rewind::Rewind;
+    use crate::GameBoy;
+    use std::path::Path;
+
+    #[test]
+    fn steps_back_through_snapshots() {
+        let mut gameboy = GameBoy::load_rom(Path::new("roms/cpu_instrs.gb")).unwrap();
+        let mut rewind = Rewind::new(2, usize::MAX);
+        let mut states = Vec::new();
+        for frame in 1..=20 {
+            gameboy.run_frame().unwrap();
+            rewind.record(&gameboy);
+            if frame % 2 == 0 {
+                states.push(gameboy.save_state());
+            }
+        }
+        assert_eq!(rewind.len(), 10);
+
+        for expected in states.iter().rev() {
+            assert!(rewind.rewind(&mut gameboy));
+            assert_eq!(&gameboy.save_state(), expected);
+        }
+        // Holding on keeps showing the oldest snapshot
+        assert!(rewind.rewind(&mut gameboy));
+        assert_eq!(gameboy.save_state(), states[0]);
+    }
+
+    #[test]
+    fn stays_within_budget() {
+        let mut gameboy = GameBoy::load_rom(Path::new("roms/cpu_instrs.gb")).unwrap();
+        let state_size = gameboy.save_state().len();
+        let mut rewind = Rewind::new(1, state_size + 1024);
+        for _ in 0..30 {
+            gameboy.run_frame().unwrap();
+            rewind.record(&gameboy);
+        }
+        assert!(rewind.memory_used() <= state_size + 1024);
+        assert!(rewind.len() > 1 && rewind.len() < 30);
+    }
+
+    #[test]
+    fn empty_buffer_does_nothing() {
+        let mut gameboy = GameBoy::load_rom(Path::new("roms/cpu_instrs.gb")).unwrap();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        assert!(rewind.is_empty());
+        assert!(!rewind.rewind(&mut gameboy));
+    }
+}
+
+#[cfg(test)]
+mod movie_unit {
+    use super::cpu_instrs;
+    use crate::movie::{Movie, VerifyError};
+    use crate::{Button, GameBoy};
+    use std::path::Path;
+
+    fn record(frames: usize) -> Movie {
+        record_on(cpu_instrs(), frames)
+    }
+
+    fn record_on(mut gameboy: GameBoy, frames: usize) -> Movie {
+        gameboy.run_frame().unwrap();
+        let mut movie = Movie::record(&gameboy);
+        for frame in 0..frames {
+            gameboy.set_button(Button::A, frame % 3 == 0);
+            gameboy.set_button(Button::Down, frame % 5 == 0);
+            movie.record_frame(gameboy.buttons());
+            gameboy.run_frame().unwrap();
+        }
+        movie.finish(&mut gameboy);
+        movie
+    }
+
+    #[test]
+    fn replays_recording() {
+        let movie = Movie::from_bytes(&record(40).to_bytes()).unwrap();
+        assert_eq!(movie.inputs().len(), 40);
+        assert_eq!(movie.inputs()[0], Button::A.mask() | Button::Down.mask());
+
+        // The machine it is replayed on has already been running, the start state fixes that
+        let mut gameboy = cpu_instrs();
+        for _ in 0..10 {
+            gameboy.run_frame().unwrap();
+        }
+        movie.verify(&mut gameboy).unwrap();
+    }
+
+    #[test]
+    fn detects_desync() {
+        // Tetris keeps the held buttons in RAM, so holding something else than was recorded
+        // ends in another state even though the copyright screen looks the same
+        let tetris = || GameBoy::load_rom(Path::new("roms/Tetris.gb")).unwrap();
+        let mut movie = record_on(tetris(), 120);
+        movie.verify(&mut tetris()).unwrap();
+        for buttons in &mut movie.inputs_mut()[100..] {
+            *buttons = Button::Start.mask();
+        }
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert!(matches!(
+            movie.verify(&mut tetris()),
+            Err(VerifyError::Desynced { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_other_rom() {
+        let movie = record(1);
+        let mut boot = GameBoy::load_boot_rom(Path::new("roms/dmg_boot.bin")).unwrap();
+        assert!(matches!(
+            movie.verify(&mut boot),
+            Err(VerifyError::Rejected(_))
+        ));
+        assert!(Movie::from_bytes(b"RMLS").is_err());
+    }
+}
+
+#[cfg(test)]
+mod debugger_unit {
+    use super::{flat_program, CALL_PROGRAM};
+    use crate::cartridge::Cartridge;
+    use crate::cpu::CPU;
+    use crate::debugger::Debugger;
+
+    fn program() -> CPU {
+        flat_program(0x0100, &CALL_PROGRAM, 0xFFFE)
+    }
+
+    // Runs until the debugger pauses again
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, command: &str) -> String {
+        let output = debugger.execute(cpu, command).unwrap();
+        for _ in 0..1000 {
+            if debugger.is_paused() {
+                return output;
+            }
+            debugger.step(cpu).unwrap();
+        }
+        panic!("{} never paused", command);
+    }
+
+    #[test]
+    fn step_and_next() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "step");
+        assert_eq!(cpu.pc, 0x0102);
+        // Steps over the whole call
+        run(&mut debugger, &mut cpu, "next");
+        assert_eq!((cpu.pc, cpu.registers.b), (0x0105, 2));
+        run(&mut debugger, &mut cpu, "s 2");
+        assert_eq!((cpu.pc, cpu.registers.a), (0x0105, 6));
+    }
+
+    #[test]
+    fn breakpoints_and_finish() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "b 111");
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.pc, 0x0111);
+        run(&mut debugger, &mut cpu, "finish");
+        assert_eq!((cpu.pc, cpu.sp), (0x0105, 0xFFFE));
+
+        assert!(debugger.execute(&mut cpu, "delete 111").is_ok());
+        assert!(debugger.execute(&mut cpu, "delete 111").is_err());
+    }
+
+    #[test]
+    fn inspects_and_edits() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, "set hl C000").unwrap();
+        debugger.execute(&mut cpu, "set f 90").unwrap();
+        let registers = debugger.execute(&mut cpu, "regs").unwrap();
+        assert!(registers.contains("HL=C000"), "{}", registers);
+        assert!(registers.contains("[Z--C]"), "{}", registers);
+
+        debugger.execute(&mut cpu, "write C000 48 69").unwrap();
+        let dump = debugger.execute(&mut cpu, "x C000 2").unwrap();
+        assert_eq!(dump, "C000: 48 69                                            Hi\n");
+        assert!(debugger.execute(&mut cpu, "set a 100").is_err());
+        assert!(debugger.execute(&mut cpu, "frobnicate").is_err());
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        // The CALL pushes the return address
+        debugger.execute(&mut cpu, "watch FFFC-FFFD").unwrap();
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.pc, 0x0110);
+        let mut output = Vec::new();
+        debugger
+            .prompt(&mut cpu, &mut "q\n".as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with("Watchpoint 0: wrote 01 to FFFD by 0102: CD 10 01"),
+            "{}",
+            output
+        );
+
+        debugger.execute(&mut cpu, "unwatch").unwrap();
+        debugger.execute(&mut cpu, "rwatch 0101 05").unwrap();
+        cpu.pc = 0x0100;
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.pc, 0x0102);
+    }
+
+    #[test]
+    fn disassembles_around_pc() {
+        let mut cpu = program();
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "s");
+        run(&mut debugger, &mut cpu, "next");
+        let listing = debugger.execute(&mut cpu, "l").unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert!(lines[1].starts_with("  0100: 3E 05"), "{}", listing);
+        assert!(lines[2].starts_with("  0102: CD 10 01"), "{}", listing);
+        assert!(lines[3].starts_with("> 0105: 3C"), "{}", listing);
+    }
+
+    #[test]
+    fn assembles_into_rom() {
+        let mut cpu = CPU::with_cartridge(Cartridge::from_bytes(vec![0; 0x8000]));
+        let mut debugger = Debugger::new();
+        assert_eq!(
+            debugger.execute(&mut cpu, "asm 150 JP $0200").unwrap(),
+            "0150: C3 00 02  JP $0200\n"
+        );
+        // The ROM changed rather than the bank controller
+        assert_eq!(cpu.bus.peek_byte(0x0150), 0xC3);
+        assert!(debugger.execute(&mut cpu, "asm 150 LD (BC),B").is_err());
+        assert!(debugger.execute(&mut cpu, "asm 150").is_err());
+    }
+}
+
+#[cfg(test)]
+mod watchpoint_unit {
+    use crate::cpu::{CPU, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS};
+    use crate::debugger::Debugger;
+    use crate::watchpoint::{Access, WatchHit, Watchpoint};
+
+    #[test]
+    fn io_register_writes() {
+        let mut cpu = CPU::default();
+        cpu.bus.add_watchpoint(Watchpoint {
+            start: 0xFF40,
+            end: 0xFF40,
+            on_read: false,
+            on_write: true,
+            value: Some(0x91),
+        });
+        cpu.bus.write_byte(0xFF40, 0x11);
+        cpu.bus.read_byte(0xFF40);
+        assert_eq!(cpu.bus.take_watch_hit(), None);
+
+        cpu.bus.write_byte(0xFF40, 0x91);
+        cpu.bus.write_byte(0xFF40, 0x91);
+        assert_eq!(
+            cpu.bus.take_watch_hit(),
+            Some(WatchHit {
+                watchpoint: 0,
+                address: 0xFF40,
+                access: Access::Write,
+                value: 0x91
+            })
+        );
+        assert_eq!(cpu.bus.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn peeking_is_invisible() {
+        let mut cpu = CPU::default();
+        cpu.bus.add_watchpoint(Watchpoint {
+            start: 0xC000,
+            end: 0xDFFF,
+            on_read: true,
+            on_write: false,
+            value: None,
+        });
+        cpu.bus.peek_byte(0xC123);
+        assert_eq!(cpu.bus.take_watch_hit(), None);
+        cpu.bus.read_byte(0xC123);
+        assert!(cpu.bus.take_watch_hit().is_some());
+    }
+
+    #[test]
+    fn interrupt_polling_is_invisible() {
+        // Flat memory is all NOPs
+        let mut cpu = CPU::default();
+        cpu.bus.set_flat(true);
+        cpu.pc = 0xC000;
+        cpu.sp = 0xD000;
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut cpu, "rwatch FF0F").unwrap();
+        debugger.execute(&mut cpu, "rwatch FFFF").unwrap();
+        for _ in 0..10 {
+            cpu.step().unwrap();
+            assert_eq!(cpu.bus.take_watch_hit(), None);
+        }
+
+        // Neither does dispatch clearing the flag
+        debugger.execute(&mut cpu, "awatch FF0F").unwrap();
+        cpu.bus.poke_byte(INTERRUPT_ENABLE_ADDRESS, 0x01);
+        cpu.bus.poke_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
+        cpu.registers.ime = true;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(cpu.bus.take_watch_hit(), None);
+    }
+}
+
+#[cfg(test)]
+mod disasm_unit {
+    use crate::disasm::{decode, listing};
+
+    fn text(bytes: &[u8], address: u16) -> String {
+        decode(bytes, address).to_string()
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(text(&[0xF0, 0x44], 0), "LD A,($FF00+$44)");
+        assert_eq!(text(&[0xE2], 0), "LD ($FF00+C),A");
+        assert_eq!(text(&[0x20, 0xFB], 0x000A), "JR NZ,$0007");
+        assert_eq!(text(&[0xC3, 0x50, 0x01], 0), "JP $0150");
+        assert_eq!(text(&[0xDC, 0x34, 0x12], 0), "CALL C,$1234");
+        assert_eq!(text(&[0xD8], 0), "RET C");
+        assert_eq!(text(&[0x32], 0), "LD (HL-),A");
+        assert_eq!(text(&[0x2A], 0), "LD A,(HL+)");
+        assert_eq!(text(&[0xF8, 0xFE], 0), "LD HL,SP-$02");
+        assert_eq!(text(&[0xE8, 0x10], 0), "ADD SP,$10");
+        assert_eq!(text(&[0x08, 0x00, 0xC0], 0), "LD ($C000),SP");
+        assert_eq!(text(&[0xCB, 0x7C], 0), "BIT 7,H");
+        assert_eq!(text(&[0xFF], 0), "RST $38");
+        assert_eq!(text(&[0xD3], 0), ".DB $D3");
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(decode(&[0x00], 0).length, 1);
+        assert_eq!(decode(&[0xCB, 0x11], 0).length, 2);
+        assert_eq!(decode(&[0xFA, 0x00, 0xC0], 0).length, 3);
+        // Every opcode decodes to something printable
+        for opcode in 0..=0xFF {
+            let decoded = decode(&[opcode, 0x00, 0x00], 0);
+            assert!(!decoded.to_string().is_empty());
+        }
+    }
+
+    #[test]
+    fn clamps_the_range() {
+        let code = [0x00, 0x3C];
+        let expected = "\tNOP\t\t\t; $4000\n\tINC A\t\t\t; $4001\n";
+        assert_eq!(listing(&code, 0x4000, 0x0100, 0x8000), expected);
+        assert_eq!(listing(&code, 0x4000, 0x4002, 0x4010), "");
+        assert_eq!(listing(&code, 0x4000, 0x4000, 0x4000), "\tNOP\t\t\t; $4000\n");
+        assert_eq!(listing(&[], 0x4000, 0x4000, 0x7FFF), "");
+    }
+
+    #[test]
+    fn lists_the_top_of_memory() {
+        let code = [0x00, 0x3C];
+        assert_eq!(
+            listing(&code, 0xFFFE, 0xFFFE, 0xFFFF),
+            "\tNOP\t\t\t; $FFFE\n\tINC A\t\t\t; $FFFF\n"
+        );
+    }
+
+    #[test]
+    fn labels_jump_targets() {
+        // 0100: LD B,$03 / DEC B / JR NZ,$0102 / JP $0200
+        let code = [0x06, 0x03, 0x05, 0x20, 0xFD, 0xC3, 0x00, 0x02];
+        assert_eq!(
+            listing(&code, 0x0100, 0x0100, 0x0107),
+            "\tLD B,$03\t\t; $0100\n\
+             Addr_0102:\n\
+             \tDEC B\t\t\t; $0102\n\
+             \tJR NZ,Addr_0102\t\t; $0103\n\
+             \tJP $0200\t\t; $0105\n\
+             \n"
+        );
+    }
+}
+
+#[cfg(test)]
+mod decode_unit {
+    use crate::cpu::CPU;
+    use crate::instructions::Instruction;
+    use crate::registers::*;
+
+    fn decode(bytes: &[u8]) -> Option<Instruction> {
+        Instruction::decode(|offset| bytes[offset as usize])
+    }
+
+    #[test]
+    fn decodes_immediates() {
+        assert!(matches!(
+            decode(&[0xC3, 0x50, 0x01]),
+            Some(Instruction::JP(JumpCondition::Always, 0x0150))
+        ));
+        assert!(matches!(
+            decode(&[0x20, 0xFE]),
+            Some(Instruction::JR(JumpCondition::NotZero, -2))
+        ));
+        assert!(matches!(
+            decode(&[0xEA, 0x00, 0xC0]),
+            Some(Instruction::LD(
+                Target::MemoryConst16(0xC000),
+                Target::Register(ArithmeticTarget::A)
+            ))
+        ));
+        assert!(matches!(
+            decode(&[0xF0, 0x44]),
+            Some(Instruction::LDH(
+                LDHRegister::ArithmeticTarget,
+                LDHRegister::MemA8(0x44)
+            ))
+        ));
+        assert!(matches!(
+            decode(&[0xF8, 0x80]),
+            Some(Instruction::LDHLSP(-128))
+        ));
+        assert!(decode(&[0xD3]).is_none());
+    }
+
+    #[test]
+    fn fetches_only_its_own_bytes() {
+        for opcode in 0..=0xFF {
+            let mut fetched = 0;
+            let instruction = Instruction::decode(|offset| {
+                fetched = fetched.max(offset + 1);
+                if offset == 0 {
+                    opcode
+                } else {
+                    0
+                }
+            });
+            if let Some(instruction) = instruction {
+                assert_eq!(fetched, instruction.length(), "opcode {:02X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn calls_push_the_next_instruction() {
+        let mut cpu = CPU::default();
+        cpu.pc = 0xC000;
+        cpu.sp = 0xFFFE;
+        // CALL $C010 / RST $08
+        for (address, byte) in (0xC000..).zip([0xCD, 0x10, 0xC0]) {
+            cpu.bus.write_byte(address, byte);
+        }
+        cpu.bus.poke_byte(0xC010, 0xCF);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xC010);
+        assert_eq!(cpu.pop().unwrap(), 0xC003);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0008);
+        assert_eq!(cpu.pop().unwrap(), 0xC011);
+    }
+}
+
+#[cfg(test)]
+mod asm_unit {
+    use crate::asm::{assemble, parse};
+    use crate::disasm::decode;
+    use crate::instructions::Instruction;
+    use crate::registers::*;
+
+    #[test]
+    fn encodes_every_opcode() {
+        for opcode in 0..=0xFF {
+            let Some(instruction) = Instruction::match_byte(opcode) else {
+                continue;
+            };
+            // STOP's second byte is ignored, so it always encodes as 0
+            let bytes = [opcode, if opcode == 0x10 { 0x00 } else { 0x34 }, 0x12];
+            let bytes = &bytes[..instruction.length() as usize];
+            let decoded = Instruction::decode(|offset| bytes[offset as usize]).unwrap();
+            assert_eq!(decoded.encode().unwrap(), bytes, "{:?}", decoded);
+        }
+        for opcode in 0..=0xFF {
+            let instruction = Instruction::from_prefixed_byte(opcode).unwrap();
+            assert_eq!(instruction.encode().unwrap(), [0xCB, opcode]);
+        }
+    }
+
+    #[test]
+    fn assembles_disassembly() {
+        for prefixed in [false, true] {
+            for opcode in 0..=0xFF {
+                let bytes = match prefixed {
+                    false if opcode == 0x10 => vec![0x10, 0x00],
+                    false => vec![opcode, 0xF4, 0x12],
+                    true => vec![0xCB, opcode],
+                };
+                let decoded = decode(&bytes, 0x0150);
+                if decoded.instruction.is_none() {
+                    continue;
+                }
+                let text = decoded.to_string();
+                assert_eq!(
+                    assemble(&text, 0x0150).as_deref(),
+                    Ok(&bytes[..decoded.length as usize]),
+                    "{}",
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn alternative_syntax() {
+        assert_eq!(assemble("ld a, [hli]", 0), Ok(vec![0x2A]));
+        assert_eq!(assemble("LDH ($FF44),A", 0), Ok(vec![0xE0, 0x44]));
+        assert_eq!(assemble("LDH A,(C)", 0), Ok(vec![0xF2]));
+        assert_eq!(assemble("CP A,10", 0), Ok(vec![0xFE, 0x0A]));
+        assert_eq!(assemble("LD B,%101", 0), Ok(vec![0x06, 0x05]));
+        assert_eq!(assemble("LD HL,SP-2", 0), Ok(vec![0xF8, 0xFE]));
+        assert_eq!(assemble("JR C,$0100", 0x0100), Ok(vec![0x38, 0xFE]));
+        assert!(matches!(
+            parse("JP NZ,$1234", 0),
+            Ok(Instruction::JP(JumpCondition::NotZero, 0x1234))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_instructions() {
+        assert!(assemble("LD (BC),B", 0).is_err());
+        assert!(assemble("JR $0200", 0).is_err());
+        assert!(assemble("RST $09", 0).is_err());
+        assert!(assemble("BIT 8,A", 0).is_err());
+        assert!(assemble("LD A,$100", 0).is_err());
+        assert!(assemble("FOO", 0).is_err());
+    }
+}
+
+#[cfg(test)]
+mod timing_unit {
+    use crate::cartridge::Cartridge;
+    use crate::cpu::CPU;
+
+    fn program(bytes: &[u8]) -> CPU {
+        let mut cpu = CPU::with_cartridge(Cartridge::from_bytes(vec![0; 0x8000]));
+        for (i, byte) in bytes.iter().enumerate() {
+            cpu.bus.write_byte(0xC000 + i as u16, *byte);
+        }
+        cpu.pc = 0xC000;
+        cpu.sp = 0xDFF0;
+        cpu.registers.h = 0xC8;
+        cpu.registers.l = 0x00;
+        cpu
+    }
+
+    #[test]
+    fn instruction_cycles() {
+        let cases: [(&[u8], u16); 10] = [
+            (&[0x00], 1),             // NOP
+            (&[0xC5], 4),             // PUSH BC
+            (&[0xC1], 3),             // POP BC
+            (&[0xCD, 0x00, 0xD0], 6), // CALL $D000
+            (&[0xC9], 4),             // RET
+            (&[0xFF], 4),             // RST $38
+            (&[0x18, 0x00], 3),       // JR 0
+            (&[0x34], 3),             // INC (HL)
+            (&[0xE0, 0x80], 3),       // LDH ($80),A
+            (&[0x08, 0x00, 0xC8], 5), // LD ($C800),SP
+        ];
+        for (bytes, cycles) in cases {
+            let mut cpu = program(bytes);
+            cpu.step().unwrap();
+            assert_eq!(cpu.cycle_count, cycles, "{:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn conditional_return() {
+        // RET NZ checks the flag on an internal cycle of its own
+        let mut cpu = program(&[0xC0]);
+        cpu.registers.f.zero = true;
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycle_count, 2);
+
+        let mut cpu = program(&[0xC0]);
+        cpu.registers.f.zero = false;
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycle_count, 5);
+    }
+
+    #[test]
+    fn dma_copies_one_byte_per_cycle() {
+        // LDH ($46),A followed by NOPs
+        let mut cpu = program(&[0xE0, 0x46]);
+        cpu.registers.a = 0xC2;
+        for i in 0..160u16 {
+            cpu.bus.write_byte(0xC200 + i, i as u8 + 1);
+        }
+
+        // The transfer starts with the write on the last cycle of LDH
+        cpu.step().unwrap();
+        cpu.bus.sync();
+        assert_eq!(cpu.bus.peek_byte(0xFE00), 0);
+
+        // Every following M-cycle moves exactly one byte
+        cpu.step().unwrap();
+        cpu.bus.sync();
+        assert_eq!(cpu.bus.peek_byte(0xFE00), 1);
+        assert_eq!(cpu.bus.peek_byte(0xFE01), 0);
+
+        for _ in 0..158 {
+            cpu.step().unwrap();
+        }
+        cpu.bus.sync();
+        assert_eq!(cpu.bus.peek_byte(0xFE9E), 159);
+        assert_eq!(cpu.bus.peek_byte(0xFE9F), 0);
+        cpu.step().unwrap();
+        cpu.bus.sync();
+        assert_eq!(cpu.bus.peek_byte(0xFE9F), 160);
+    }
+}
+
+#[cfg(test)]
+mod scheduler_unit {
+    use crate::cpu::{CPU, TIMER_INTERRUPT};
+    use crate::scheduler::{Event, Scheduler};
+    use crate::state::StateWriter;
+    use std::path::Path;
+
+    #[test]
+    fn pops_due_events_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::DmaEnd, 10);
+        scheduler.schedule(Event::Serial, 4);
+        scheduler.schedule(Event::LineEnd, 10);
+        assert!(!scheduler.advance(3));
+        assert_eq!(scheduler.pop_due(), None);
+
+        assert!(scheduler.advance(8));
+        assert_eq!(scheduler.pop_due(), Some(Event::Serial));
+        assert_eq!(scheduler.pop_due(), Some(Event::LineEnd));
+        assert_eq!(scheduler.pop_due(), Some(Event::DmaEnd));
+        assert_eq!(scheduler.pop_due(), None);
+        assert_eq!(scheduler.cycles_until_next(), None);
+    }
+
+    #[test]
+    fn rescheduling_replaces_the_deadline() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::TimerOverflow, 2);
+        scheduler.schedule(Event::TimerOverflow, 20);
+        assert_eq!(scheduler.deadline(Event::TimerOverflow), Some(20));
+        assert!(!scheduler.advance(5));
+        assert_eq!(scheduler.cycles_until_next(), Some(15));
+
+        scheduler.cancel(Event::TimerOverflow);
+        assert!(!scheduler.advance(50));
+        assert_eq!(scheduler.deadline(Event::TimerOverflow), None);
+    }
+
+    #[test]
+    fn timer_interrupt_on_time() {
+        let mut cpu = CPU::default();
+        // TIMA ticks every 4 M-cycles, so 0xFE overflows after the 8th
+        cpu.bus.write_byte(0xFF05, 0xFE);
+        cpu.bus.write_byte(0xFF07, 0x05);
+        cpu.pc = 0xC000;
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.bus.interrupt_flag & TIMER_INTERRUPT, 0);
+        cpu.step().unwrap();
+        assert_ne!(cpu.bus.interrupt_flag & TIMER_INTERRUPT, 0);
+    }
+
+    fn run(eager_sync: bool) -> Vec<u8> {
+        let mut cpu = CPU::new_with_rom(Path::new("roms/cpu_instrs.gb")).unwrap();
+        cpu.bus.eager_sync = eager_sync;
+        for _ in 0..2_000_000 {
+            cpu.step().unwrap();
+        }
+        cpu.bus.sync();
+        let mut writer = StateWriter::new();
+        cpu.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn matches_eager_stepping() {
+        assert!(run(false) == run(true));
+    }
+}
+
+#[cfg(test)]
+mod interrupt_unit {
+    use super::flat_program;
+    use crate::cpu::{CPU, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS};
+
+    // The vblank interrupt is requested and enabled, but IME is off
+    fn program(bytes: &[u8]) -> CPU {
+        let mut cpu = flat_program(0xC000, bytes, 0xD000);
+        cpu.bus.poke_byte(INTERRUPT_ENABLE_ADDRESS, 0x01);
+        cpu.bus.poke_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
+        cpu
+    }
+
+    fn return_address(cpu: &CPU) -> u16 {
+        u16::from_le_bytes([
+            cpu.bus.peek_byte(cpu.sp),
+            cpu.bus.peek_byte(cpu.sp + 1),
+        ])
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP; NOP
+        let mut cpu = program(&[0xFB, 0x00, 0x00]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xC001);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xC002);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(return_address(&cpu), 0xC002);
+    }
+
+    #[test]
+    fn ei_di_lets_nothing_through() {
+        // EI; DI; NOP
+        let mut cpu = program(&[0xFB, 0xF3, 0x00]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0xC003);
+        assert!(!cpu.registers.ime);
+    }
+
+    #[test]
+    fn ei_halt_returns_after_halt() {
+        // EI; HALT
+        let mut cpu = program(&[0xFB, 0x76]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(return_address(&cpu), 0xC002);
+    }
+
+    #[test]
+    fn dispatch_takes_five_cycles() {
+        let mut cpu = program(&[0x00]);
+        cpu.registers.ime = true;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(cpu.cycle_count, 5);
+        assert_eq!(cpu.bus.peek_byte(INTERRUPT_FLAG_ADDRESS), 0x00);
+        assert!(!cpu.registers.ime);
+    }
+
+    #[test]
+    fn ie_push_cancels_dispatch() {
+        // With SP at 0 the high byte of PC lands in IE, 0xC0 disables vblank
+        let mut cpu = program(&[0x00]);
+        cpu.registers.ime = true;
+        cpu.sp = 0x0000;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.bus.peek_byte(INTERRUPT_ENABLE_ADDRESS), 0xC0);
+        // The request stays around since nothing was serviced
+        assert_eq!(cpu.bus.peek_byte(INTERRUPT_FLAG_ADDRESS), 0x01);
+        assert_eq!(cpu.cycle_count, 5);
+    }
+
+    #[test]
+    fn ie_push_picks_another_interrupt() {
+        // Vblank and timer are requested, pushing 0xC4 into IE swaps vblank for timer
+        let mut cpu = program(&[]);
+        cpu.bus.poke_byte(INTERRUPT_FLAG_ADDRESS, 0x05);
+        cpu.registers.ime = true;
+        cpu.pc = 0xC400;
+        cpu.sp = 0x0000;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.peek_byte(INTERRUPT_FLAG_ADDRESS), 0x01);
+
+        // Anything that keeps vblank enabled doesn't change a thing
+        let mut cpu = program(&[]);
+        cpu.registers.ime = true;
+        cpu.pc = 0xC100;
+        cpu.sp = 0x0000;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0040);
+    }
+}
+
+#[cfg(test)]
+mod memory_map_unit {
+    use crate::cpu::{CPU, TIMER_INTERRUPT};
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut cpu = CPU::default();
+        cpu.bus.write_byte(0xC123, 0x42);
+        assert_eq!(cpu.bus.read_byte(0xE123), 0x42);
+        cpu.bus.write_byte(0xFDFF, 0x24);
+        assert_eq!(cpu.bus.read_byte(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn unusable_region() {
+        let mut cpu = CPU::default();
+        cpu.bus.write_byte(0xFEA0, 0x42);
+        cpu.bus.write_byte(0xFEFF, 0x42);
+        assert_eq!(cpu.bus.read_byte(0xFEA0), 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFEFF), 0x00);
+        // OAM right below it is still plain memory
+        cpu.bus.write_byte(0xFE9F, 0x42);
+        assert_eq!(cpu.bus.read_byte(0xFE9F), 0x42);
+    }
+
+    #[test]
+    fn unusable_region_while_oam_is_blocked() {
+        let mut cpu = CPU::default();
+        // OAM scan and pixel transfer of a visible line
+        cpu.bus.gpu.lcdc = 0x80;
+        assert_eq!(cpu.bus.read_byte(0xFEA0), 0xFF);
+        cpu.bus.gpu.step(63);
+        assert_eq!(cpu.bus.read_byte(0xFEA0), 0x00);
+        cpu.bus.gpu.step(51);
+        assert_eq!(cpu.bus.read_byte(0xFEA0), 0xFF);
+        cpu.bus.gpu.ly = 144;
+        assert_eq!(cpu.bus.read_byte(0xFEA0), 0x00);
+
+        // OAM DMA blocks it no matter what the LCD does
+        cpu.bus.gpu.lcdc = 0x00;
+        cpu.bus.write_byte(0xFF46, 0xC0);
+        assert_eq!(cpu.bus.read_byte(0xFEFF), 0xFF);
+    }
+
+    #[test]
+    fn io_read_masks() {
+        let mut cpu = CPU::default();
+        for address in [0xFF03, 0xFF07, 0xFF0F, 0xFF41, 0xFF4C, 0xFF7F] {
+            cpu.bus.write_byte(address, 0x00);
+        }
+        // Unmapped
+        assert_eq!(cpu.bus.read_byte(0xFF03), 0xFF);
+        assert_eq!(cpu.bus.read_byte(0xFF4C), 0xFF);
+        assert_eq!(cpu.bus.read_byte(0xFF7F), 0xFF);
+        // Unused bits
+        assert_eq!(cpu.bus.read_byte(0xFF07), 0xF8);
+        assert_eq!(cpu.bus.read_byte(0xFF0F), 0xE0);
+        assert_eq!(cpu.bus.read_byte(0xFF41), 0x80);
+
+        // Fully readable registers are left alone
+        cpu.bus.write_byte(0xFF30, 0x5A);
+        assert_eq!(cpu.bus.read_byte(0xFF30), 0x5A);
+        cpu.bus.write_byte(0xFF80, 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFF80), 0x00);
+        cpu.bus.write_byte(0xFFFF, 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFFFF), 0x00);
+    }
+
+    #[test]
+    fn registers_have_one_owner() {
+        let mut cpu = CPU::default();
+        cpu.bus.write_byte(0xFF47, 0x1B);
+        assert_eq!(cpu.bus.gpu.bgp, 0x1B);
+        cpu.bus.gpu.scx = 0x42;
+        assert_eq!(cpu.bus.read_byte(0xFF43), 0x42);
+
+        cpu.bus.request_interrupt(TIMER_INTERRUPT);
+        assert_eq!(cpu.bus.read_byte(0xFF0F), 0xE0 | TIMER_INTERRUPT);
+        cpu.bus.write_byte(0xFFFF, 0x1F);
+        assert_eq!(cpu.bus.interrupt_enable, 0x1F);
+
+        cpu.bus.write_byte(0xFF46, 0xC1);
+        assert_eq!(cpu.bus.read_byte(0xFF46), 0xC1);
+    }
+}
+
+#[cfg(test)]
+mod trace_unit {
+    use super::flat_program;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    // Lets the test read what the CPU wrote into its boxed trace
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn line_format() {
+        let mut cpu = flat_program(0xC000, &[0x3E, 0x12, 0x00, 0x76], 0xFFFE);
+        cpu.registers.a = 0x01;
+        cpu.registers.f = 0xB0.into();
+        cpu.registers.b = 0x00;
+        cpu.registers.c = 0x13;
+        cpu.registers.d = 0x00;
+        cpu.registers.e = 0xD8;
+        cpu.registers.h = 0x01;
+        cpu.registers.l = 0x4D;
+        assert_eq!(
+            cpu.trace_line(),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:3E,12,00,76"
+        );
+    }
+
+    #[test]
+    fn one_line_per_instruction() {
+        // LD A,0x12; NOP; HALT
+        let mut cpu = flat_program(0xC000, &[0x3E, 0x12, 0x00, 0x76], 0xFFFE);
+        let buffer = SharedBuffer::default();
+        cpu.trace = Some(Box::new(buffer.clone()));
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.is_halted());
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let pcs: Vec<&str> = trace
+            .lines()
+            .map(|line| line.split(' ').nth(9).unwrap())
+            .collect();
+        // Steps spent halted don't execute anything, so they aren't traced
+        assert_eq!(pcs, ["PC:C000", "PC:C002", "PC:C003"]);
+        assert!(trace.lines().nth(1).unwrap().starts_with("A:12 "));
+    }
+}
+
+#[cfg(test)]
+mod history_unit {
+    use super::flat_program;
+    use crate::cartridge::{Cartridge, ROM_BANK_SIZE};
+    use crate::cpu::{CpuError, CPU};
+    use crate::history::History;
+
+    #[test]
+    fn keeps_the_last_instructions() {
+        // INC A, eight times
+        let mut cpu = flat_program(0xC000, &[0x3C; 8], 0xD000);
+        cpu.history = History::new(4);
+        for _ in 0..8 {
+            cpu.step().unwrap();
+        }
+        let addresses: Vec<u16> = cpu.history.iter().map(|e| e.decoded.address).collect();
+        assert_eq!(addresses, [0xC004, 0xC005, 0xC006, 0xC007]);
+        // Registers as the instruction saw them, before it ran
+        let accumulators: Vec<u8> = cpu.history.iter().map(|e| e.registers.a).collect();
+        assert_eq!(accumulators, [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn leads_up_to_a_fault() {
+        // LD SP,$0001; JP $C010, which holds an illegal opcode
+        let mut cpu = flat_program(0xC000, &[0x31, 0x01, 0x00, 0xC3, 0x10, 0xC0], 0xD000);
+        cpu.bus.write_byte(0xC010, 0xD3);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(matches!(
+            cpu.step(),
+            Err(CpuError::IllegalOpcode {
+                address: 0xC010,
+                ..
+            })
+        ));
+        // The illegal opcode never executed, the jump that got there is the last entry
+        let last = cpu.history.iter().last().unwrap();
+        assert_eq!(last.decoded.address, 0xC003);
+        assert_eq!(last.sp, 0x0001);
+        assert_eq!(last.decoded.to_string(), "JP $C010");
+    }
+
+    #[test]
+    fn records_the_rom_bank() {
+        let mut rom = vec![0x00; 4 * ROM_BANK_SIZE];
+        rom[0x147] = 0x01; // MBC1
+        let mut cpu = CPU::with_cartridge(Cartridge::from_bytes(rom));
+        cpu.bus.write_byte(0x2000, 0x03);
+        cpu.step().unwrap();
+        cpu.pc = 0x4000;
+        cpu.step().unwrap();
+        let banks: Vec<usize> = cpu.history.iter().map(|e| e.bank).collect();
+        assert_eq!(banks, [0, 3]);
+        assert!(cpu
+            .history
+            .iter()
+            .last()
+            .unwrap()
+            .to_string()
+            .starts_with("03:4000  NOP"));
+    }
+
+    #[test]
+    fn disabled_with_no_capacity() {
+        let mut cpu = flat_program(0xC000, &[0x00, 0x00], 0xD000);
+        cpu.history = History::new(0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.history.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod symbols_unit {
+    use super::{flat_program, CALL_PROGRAM};
+    use crate::cartridge::{Cartridge, ROM_BANK_SIZE};
+    use crate::cpu::CPU;
+    use crate::debugger::Debugger;
+    use crate::disasm::listing_with_symbols;
+    use crate::symbols::{Location, Symbols};
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0100 Main
+00:0105 Main.loop
+00:0110 Func
+02:4000 Banked
+03:4000 OtherBanked
+00:c000 wCounter
+";
+
+    #[test]
+    fn parses_rgbds_files() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.get("Main.loop"), Some(Location::new(0, 0x0105)));
+        assert_eq!(symbols.get("Banked"), Some(Location::new(2, 0x4000)));
+        assert_eq!(symbols.name_at(Location::new(3, 0x4000)), Some("OtherBanked"));
+        assert_eq!(symbols.name_at(Location::new(1, 0x4000)), None);
+
+        let error = Symbols::parse("00:0100 Main\nMain.loop\n").err().unwrap();
+        assert!(error.starts_with("Line 2"), "{}", error);
+    }
+
+    #[test]
+    fn describes_locations() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        assert_eq!(symbols.describe(Location::new(0, 0x0105)).unwrap(), "Main.loop");
+        assert_eq!(symbols.describe(Location::new(0, 0x0107)).unwrap(), "Main.loop+$02");
+        assert_eq!(symbols.describe(Location::new(2, 0x4010)).unwrap(), "Banked+$10");
+        // Nothing in bank 1, and ROM labels don't extend into RAM
+        assert_eq!(symbols.describe(Location::new(1, 0x4010)), None);
+        assert_eq!(symbols.describe(Location::new(0, 0x8000)), None);
+        assert_eq!(symbols.describe(Location::new(0, 0xC001)).unwrap(), "wCounter+$01");
+    }
+
+    fn program() -> (CPU, Debugger) {
+        let cpu = flat_program(0x0100, &CALL_PROGRAM, 0xFFFE);
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(Symbols::parse(SYM).unwrap());
+        (cpu, debugger)
+    }
+
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, command: &str) {
+        debugger.execute(cpu, command).unwrap();
+        for _ in 0..1000 {
+            if debugger.is_paused() {
+                return;
+            }
+            debugger.step(cpu).unwrap();
+        }
+        panic!("{} never paused", command);
+    }
+
+    #[test]
+    fn breaks_on_symbols() {
+        let (mut cpu, mut debugger) = program();
+        assert_eq!(
+            debugger.execute(&mut cpu, "break Func").unwrap(),
+            "Breakpoint at 0110\n"
+        );
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.pc, 0x0110);
+        debugger.execute(&mut cpu, "delete Func").unwrap();
+        debugger.execute(&mut cpu, "b Main.loop").unwrap();
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(cpu.pc, 0x0105);
+        assert!(debugger.execute(&mut cpu, "b Nowhere").is_err());
+    }
+
+    #[test]
+    fn banked_breakpoints() {
+        // JP $4000 into bank 2, which spins there
+        let mut rom = vec![0x00; 4 * ROM_BANK_SIZE];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        for bank in 1..4 {
+            rom[bank * ROM_BANK_SIZE..][..2].copy_from_slice(&[0x18, 0xFE]);
+        }
+        let mut cpu = CPU::with_cartridge(Cartridge::from_bytes(rom));
+        cpu.bus.write_byte(0x2000, 0x02);
+        let mut debugger = Debugger::new();
+        debugger.set_symbols(Symbols::parse(SYM).unwrap());
+
+        debugger.execute(&mut cpu, "b OtherBanked").unwrap();
+        debugger.execute(&mut cpu, "b 02:4000").unwrap();
+        let listed = debugger.execute(&mut cpu, "b").unwrap();
+        assert_eq!(listed.lines().count(), 2, "{}", listed);
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!((cpu.pc, cpu.bus.rom_bank_at(0x4000)), (0x4000, 2));
+
+        // Bank 3 is mapped at the same address, but only the bank 2 breakpoint is gone
+        debugger.execute(&mut cpu, "delete 02:4000").unwrap();
+        run(&mut debugger, &mut cpu, "s 10");
+        assert_eq!(cpu.pc, 0x4000);
+        cpu.bus.write_byte(0x2000, 0x03);
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!((cpu.pc, cpu.bus.rom_bank_at(0x4000)), (0x4000, 3));
+    }
+
+    #[test]
+    fn names_in_listings_and_backtraces() {
+        let (mut cpu, mut debugger) = program();
+        let listing = debugger.execute(&mut cpu, "l 100 3").unwrap();
+        assert_eq!(
+            listing,
+            "Main:\n\
+             > 0100: 3E 05     LD A,$05\n  \
+             0102: CD 10 01  CALL Func\n\
+             Main.loop:\n  \
+             0105: 3C        INC A\n"
+        );
+
+        debugger.execute(&mut cpu, "b 111").unwrap();
+        run(&mut debugger, &mut cpu, "c");
+        assert_eq!(
+            debugger.execute(&mut cpu, "bt").unwrap(),
+            "#0  00:0111 Func+$01\n\
+             #1  00:0102 Main+$02\n"
+        );
+        run(&mut debugger, &mut cpu, "finish");
+        assert_eq!(
+            debugger.execute(&mut cpu, "bt").unwrap(),
+            "#0  00:0105 Main.loop\n"
+        );
+    }
+
+    #[test]
+    fn labels_static_listings() {
+        let symbols = Symbols::parse(SYM).unwrap();
+        // 0100: LD A,$05 / CALL $0110
+        let code = [0x3E, 0x05, 0xCD, 0x10, 0x01];
+        let symbol = |address| symbols.name_at(Location::new(0, address)).map(str::to_string);
+        assert_eq!(
+            listing_with_symbols(&code, 0x0100, 0x0100, 0x0104, symbol),
+            "Main:\n\
+             \tLD A,$05\t\t; $0100\n\
+             \tCALL Func\t\t; $0102\n"
+        );
+    }
+}
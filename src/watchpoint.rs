@@ -0,0 +1,74 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Breaks on accesses to an inclusive range of addresses, optionally only when a given value
+/// is read or written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub on_read: bool,
+    pub on_write: bool,
+    pub value: Option<u8>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, access: Access, value: u8) -> bool {
+        let access_matches = match access {
+            Access::Read => self.on_read,
+            Access::Write => self.on_write,
+        };
+        access_matches
+            && (self.start..=self.end).contains(&address)
+            && self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match (self.on_read, self.on_write) {
+            (true, true) => "access",
+            (true, false) => "read",
+            _ => "write",
+        };
+        write!(f, "{} {:04X}", access, self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " = {:02X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The access that triggered a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub watchpoint: usize,
+    pub address: u16,
+    pub access: Access,
+    pub value: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read => write!(
+                f,
+                "Watchpoint {}: read {:02X} from {:04X}",
+                self.watchpoint, self.value, self.address
+            ),
+            Access::Write => write!(
+                f,
+                "Watchpoint {}: wrote {:02X} to {:04X}",
+                self.watchpoint, self.value, self.address
+            ),
+        }
+    }
+}